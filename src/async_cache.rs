@@ -0,0 +1,271 @@
+use crate::{NonPurgeableBox, PurgePriority, PurgeableBox};
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::{fmt, ops};
+
+/// A map of purgeable values that are regenerated asynchronously when they have been purged;
+/// requires the `async` feature.
+///
+/// Concurrent tasks that ask for the same missing or purged value await a single regeneration:
+/// the first one runs its `regen` future while the others wait for the result. If the
+/// regeneration fails or the task is cancelled, the next waiting task regenerates the value;
+/// if no task is waiting, the key is removed.
+///
+/// Tasks asking for a resident value don't wait for each other: an [AsyncCacheGuard] gives
+/// shared access to the locked value, which is unlocked when the last guard to it is dropped.
+///
+/// # Examples
+///
+/// ```
+/// use purgeable::{AsyncCache, NonPurgeableBox};
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let cache = AsyncCache::<String, [u8]>::new();
+/// let value = cache
+///     .get_or_regenerate("key".to_owned(), async {
+///         // e.g. `tokio::fs::read`
+///         NonPurgeableBox::new_slice(b"value")
+///     })
+///     .await;
+/// assert_eq!(&value[..], b"value");
+/// # });
+/// ```
+pub struct AsyncCache<K, T: ?Sized> {
+    slots: Mutex<HashMap<K, Arc<Slot<T>>>>,
+    priority: PurgePriority,
+}
+
+struct Slot<T: ?Sized> {
+    state: Mutex<State<T>>,
+    /// Held by the task that regenerates the value, so the others wait for it
+    regenerating: tokio::sync::Mutex<()>,
+    // Readers on different threads share `&T`
+    _marker: PhantomData<T>,
+}
+
+enum State<T: ?Sized> {
+    Empty,
+    Unlocked(PurgeableBox<T>),
+    Locked {
+        npb: NonPurgeableBox<T>,
+        readers: usize,
+    },
+}
+
+impl<T: ?Sized> Slot<T> {
+    fn state(&self) -> MutexGuard<'_, State<T>> {
+        // The state is consistent at any panic point
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<T: ?Sized> Default for Slot<T> {
+    fn default() -> Slot<T> {
+        Slot {
+            state: Mutex::new(State::Empty),
+            regenerating: tokio::sync::Mutex::new(()),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Eq + Hash, T: ?Sized> AsyncCache<K, T> {
+    pub fn new() -> AsyncCache<K, T> {
+        AsyncCache::with_priority(PurgePriority::DEFAULT)
+    }
+
+    /// Values are unlocked with the `priority`, see [PurgePriority]
+    pub fn with_priority(priority: PurgePriority) -> AsyncCache<K, T> {
+        AsyncCache {
+            slots: Mutex::new(HashMap::new()),
+            priority,
+        }
+    }
+
+    /// Returns the locked value of the `key`, or awaits `regen` for it if it is missing or
+    /// has been purged and no other task is regenerating it already
+    pub async fn get_or_regenerate(
+        &self,
+        key: K,
+        regen: impl Future<Output = NonPurgeableBox<T>>,
+    ) -> AsyncCacheGuard<T> {
+        let result = self
+            .get_or_try_regenerate(key, async {
+                Ok::<_, std::convert::Infallible>(regen.await)
+            })
+            .await;
+        match result {
+            Ok(guard) => guard,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Like [AsyncCache::get_or_regenerate], but the regeneration can fail. The error is
+    /// returned to the task that has run `regen` only; the next waiting task tries its own
+    pub async fn get_or_try_regenerate<E>(
+        &self,
+        key: K,
+        regen: impl Future<Output = Result<NonPurgeableBox<T>, E>>,
+    ) -> Result<AsyncCacheGuard<T>, E> {
+        let slot = self.slots().entry(key).or_default().clone();
+        if let Some(guard) = self.try_get(&slot) {
+            return Ok(guard);
+        }
+        let slot = EmptySlotGuard { cache: self, slot };
+        let _regenerating = slot.slot.regenerating.lock().await;
+        // The value may have been regenerated while this task has been waiting
+        if let Some(guard) = self.try_get(&slot.slot) {
+            return Ok(guard);
+        }
+        let npb = regen.await?;
+        let mut state = slot.slot.state();
+        *state = State::Locked { npb, readers: 1 };
+        Ok(self.guard(&slot.slot, &state))
+    }
+
+    /// Returns a guard if the value is locked by another task or can be locked
+    fn try_get(&self, slot: &Arc<Slot<T>>) -> Option<AsyncCacheGuard<T>> {
+        let mut state = slot.state();
+        match std::mem::replace(&mut *state, State::Empty) {
+            State::Locked { npb, readers } => {
+                *state = State::Locked {
+                    npb,
+                    readers: readers + 1,
+                };
+            }
+            State::Unlocked(pb) => {
+                *state = State::Locked {
+                    npb: pb.lock().ok()?,
+                    readers: 1,
+                }
+            }
+            State::Empty => return None,
+        }
+        Some(self.guard(slot, &state))
+    }
+
+    fn guard(&self, slot: &Arc<Slot<T>>, state: &State<T>) -> AsyncCacheGuard<T> {
+        let State::Locked { npb, .. } = state else {
+            unreachable!()
+        };
+        AsyncCacheGuard {
+            slot: slot.clone(),
+            // The content doesn't move while the box is locked
+            content: &**npb as *const T,
+            priority: self.priority,
+        }
+    }
+
+    /// Removes the value of the `key`. A task holding a guard to it keeps the value until
+    /// the guard is dropped
+    pub fn remove<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.slots().remove(key);
+    }
+
+    /// The number of keys, including values that have been purged
+    pub fn len(&self) -> usize {
+        self.slots().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Purges the value of the `key` if it is unlocked, see [crate::testing::force_purge]
+    #[cfg(all(test, feature = "testing"))]
+    pub(crate) fn force_purge(&self, key: &K) -> bool {
+        let Some(slot) = self.slots().get(key).cloned() else {
+            return false;
+        };
+        let mut state = slot.state();
+        match &mut *state {
+            State::Unlocked(pb) => crate::testing::force_purge(pb),
+            _ => false,
+        }
+    }
+
+    fn slots(&self) -> MutexGuard<'_, HashMap<K, Arc<Slot<T>>>> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Removes the slot from the cache if it is dropped empty, i.e. the regeneration has failed
+/// or has been cancelled, and no other task is waiting for the slot
+struct EmptySlotGuard<'a, K, T: ?Sized> {
+    cache: &'a AsyncCache<K, T>,
+    slot: Arc<Slot<T>>,
+}
+
+impl<K, T: ?Sized> Drop for EmptySlotGuard<'_, K, T> {
+    fn drop(&mut self) {
+        // Waiting tasks hold clones of the slot, and they are cloned under the `slots` lock;
+        // the other references are the map entry and `slot`. Guards hold clones too, but
+        // only while the slot is locked
+        let mut slots = self.cache.slots.lock().unwrap_or_else(|e| e.into_inner());
+        if Arc::strong_count(&self.slot) == 2 && matches!(*self.slot.state(), State::Empty) {
+            slots.retain(|_, it| !Arc::ptr_eq(it, &self.slot));
+        }
+    }
+}
+
+impl<K: Eq + Hash, T: ?Sized> Default for AsyncCache<K, T> {
+    fn default() -> AsyncCache<K, T> {
+        AsyncCache::new()
+    }
+}
+
+impl<K, T: ?Sized> fmt::Debug for AsyncCache<K, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AsyncCache")
+    }
+}
+
+/// Shared access to a locked value of an [AsyncCache]; the value is unlocked back into the
+/// cache when the last guard to it is dropped
+pub struct AsyncCacheGuard<T: ?Sized> {
+    slot: Arc<Slot<T>>,
+    content: *const T,
+    priority: PurgePriority,
+}
+
+// SAFETY: the guard gives only shared access to `T`, like `&AsyncCache<K, T>` does
+unsafe impl<T: Send + Sync + ?Sized> Send for AsyncCacheGuard<T> {}
+unsafe impl<T: Send + Sync + ?Sized> Sync for AsyncCacheGuard<T> {}
+
+impl<T: ?Sized> ops::Deref for AsyncCacheGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the box stays locked and in place while it has readers
+        unsafe { &*self.content }
+    }
+}
+
+impl<T: ?Sized> Drop for AsyncCacheGuard<T> {
+    fn drop(&mut self) {
+        let mut state = self.slot.state();
+        if let State::Locked { readers, .. } = &mut *state {
+            *readers -= 1;
+            if *readers == 0 {
+                if let State::Locked { npb, .. } = std::mem::replace(&mut *state, State::Empty) {
+                    *state =
+                        State::Unlocked(NonPurgeableBox::unlock_with_priority(npb, self.priority));
+                }
+            }
+        }
+    }
+}
+
+impl<T: fmt::Debug + ?Sized> fmt::Debug for AsyncCacheGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
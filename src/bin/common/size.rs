@@ -0,0 +1,16 @@
+//! Size arguments shared by the binaries
+
+/// Parses sizes like `4096`, `64k`, `16M` or `1g`
+pub fn parse_size(size: &str) -> Result<usize, String> {
+    let (number, multiplier) = match size.as_bytes().last() {
+        Some(b'k' | b'K') => (&size[..size.len() - 1], 1 << 10),
+        Some(b'm' | b'M') => (&size[..size.len() - 1], 1 << 20),
+        Some(b'g' | b'G') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size `{}`", size))
+}
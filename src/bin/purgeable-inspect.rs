@@ -0,0 +1,410 @@
+//! Lists purgeable regions of a running process.
+//!
+//! Usage: `purgeable-inspect <pid>`. Regions are found in `/proc/<pid>/smaps` (or `maps` if
+//! `smaps` is not readable) by their names: `[anon:purgeable:<label>]` for `MADV_FREE` memory,
+//! `/dev/ashmem/purgeable:<label>` for `ashmem` and `/memfd:purgeable:<label>` for `memfd`.
+//!
+//! `LAZY_KB` is the size of lazily freed pages (`LazyFree` in `smaps`), i.e. of unlocked
+//! `MADV_FREE` regions that haven't been reclaimed yet. It gives the `STATE` of `MADV_FREE`
+//! regions: unlocking frees every resident page lazily and locking dirties every page again,
+//! so a region is `unlocked` if all its resident pages are lazily freed, `locked` if none is,
+//! and `mixed` otherwise. A region without resident pages may be purged or never written.
+//! Whether an `ashmem` or `memfd` region is pinned can't be read from outside the process.
+//!
+//! The kernel merges adjacent mappings with the same name and flags into one region, so
+//! a listed region may hold several boxes with the same label, in different states, and the
+//! count of regions may be lower than the count of boxes.
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn main() -> std::process::ExitCode {
+    linux::main()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn main() -> std::process::ExitCode {
+    eprintln!("purgeable-inspect is supported only on Linux and Android");
+    std::process::ExitCode::FAILURE
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux {
+    use std::fs;
+    use std::process::ExitCode;
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    enum Kind {
+        Anon,
+        Ashmem,
+        Memfd,
+    }
+
+    impl Kind {
+        fn name(self) -> &'static str {
+            match self {
+                Kind::Anon => "anon",
+                Kind::Ashmem => "ashmem",
+                Kind::Memfd => "memfd",
+            }
+        }
+    }
+
+    #[derive(PartialEq, Eq, Debug)]
+    struct Region {
+        start: u64,
+        end: u64,
+        kind: Kind,
+        label: Option<String>,
+        /// Fields of `smaps` in KiB; `None` if only `maps` has been read
+        rss: Option<u64>,
+        lazy_free: Option<u64>,
+    }
+
+    impl Region {
+        /// The pin state if it can be told, see the module documentation
+        fn state(&self) -> Option<&'static str> {
+            if self.kind != Kind::Anon {
+                return None;
+            }
+            match (self.rss?, self.lazy_free?) {
+                (0, _) => None,
+                (_, 0) => Some("locked"),
+                (rss, lazy_free) if lazy_free >= rss => Some("unlocked"),
+                _ => Some("mixed"),
+            }
+        }
+    }
+
+    pub fn main() -> ExitCode {
+        let mut args = std::env::args().skip(1);
+        let pid = match (args.next(), args.next()) {
+            (Some(pid), None) if pid.parse::<u32>().is_ok() || pid == "self" => pid,
+            _ => {
+                eprintln!("Usage: purgeable-inspect <pid>");
+                return ExitCode::from(2);
+            }
+        };
+
+        let (content, has_smaps) = match fs::read_to_string(format!("/proc/{}/smaps", pid)) {
+            Ok(smaps) => (smaps, true),
+            Err(_) => match fs::read_to_string(format!("/proc/{}/maps", pid)) {
+                Ok(maps) => (maps, false),
+                Err(e) => {
+                    eprintln!("Failed to read the memory map of {}: {}", pid, e);
+                    return ExitCode::FAILURE;
+                }
+            },
+        };
+
+        let regions = parse(&content, has_smaps);
+        print(&regions);
+        ExitCode::SUCCESS
+    }
+
+    fn parse(content: &str, has_smaps: bool) -> Vec<Region> {
+        let mut regions = Vec::new();
+        // Whether `smaps` fields below the current header belong to a purgeable region
+        let mut current = false;
+        for line in content.lines() {
+            if let Some((start, end, path)) = parse_header(line) {
+                current = false;
+                if let Some((kind, label)) = parse_name(path) {
+                    regions.push(Region {
+                        start,
+                        end,
+                        kind,
+                        label,
+                        rss: has_smaps.then_some(0),
+                        lazy_free: has_smaps.then_some(0),
+                    });
+                    current = true;
+                }
+            } else if current {
+                let region = regions.last_mut().unwrap();
+                let Some((key, value)) = line.split_once(':') else {
+                    continue;
+                };
+                let kib = value
+                    .trim()
+                    .strip_suffix("kB")
+                    .and_then(|it| it.trim().parse::<u64>().ok());
+                match key {
+                    "Rss" => region.rss = kib,
+                    "LazyFree" => region.lazy_free = kib,
+                    _ => {}
+                }
+            }
+        }
+        regions
+    }
+
+    /// Parses `start-end perms offset dev inode [path]`
+    fn parse_header(line: &str) -> Option<(u64, u64, &str)> {
+        let (range, rest) = line.split_once(' ')?;
+        let (start, end) = range.split_once('-')?;
+        let start = u64::from_str_radix(start, 16).ok()?;
+        let end = u64::from_str_radix(end, 16).ok()?;
+        // Skip perms, offset, dev and inode; the path may contain spaces
+        let mut rest = rest;
+        for _ in 0..4 {
+            rest = rest.trim_start().split_once(' ').map_or("", |it| it.1);
+        }
+        Some((start, end, rest.trim()))
+    }
+
+    /// Recognizes names given by the crate: `purgeable` or `purgeable:<label>`
+    fn parse_name(path: &str) -> Option<(Kind, Option<String>)> {
+        let path = path.strip_suffix(" (deleted)").unwrap_or(path);
+        let (kind, name) = if let Some(name) = path.strip_prefix("[anon:") {
+            (Kind::Anon, name.strip_suffix(']')?)
+        } else if let Some(name) = path.strip_prefix("/dev/ashmem/") {
+            (Kind::Ashmem, name)
+        } else if let Some(name) = path.strip_prefix("/memfd:") {
+            (Kind::Memfd, name)
+        } else {
+            return None;
+        };
+        match name.strip_prefix("purgeable")? {
+            "" => Some((kind, None)),
+            label => Some((kind, Some(label.strip_prefix(':')?.to_owned()))),
+        }
+    }
+
+    fn print(regions: &[Region]) {
+        let kib = |value: Option<u64>| value.map_or("-".to_owned(), |it| it.to_string());
+        println!(
+            "{:<29} {:>10} {:>10} {:>10} {:<6} {:<8} LABEL",
+            "ADDRESS", "SIZE_KB", "RSS_KB", "LAZY_KB", "KIND", "STATE"
+        );
+        for region in regions {
+            println!(
+                "{:012x}-{:012x}     {:>10} {:>10} {:>10} {:<6} {:<8} {}",
+                region.start,
+                region.end,
+                (region.end - region.start) / 1024,
+                kib(region.rss),
+                kib(region.lazy_free),
+                region.kind.name(),
+                region.state().unwrap_or("-"),
+                region.label.as_deref().unwrap_or("-")
+            );
+        }
+
+        let size: u64 = regions.iter().map(|it| it.end - it.start).sum::<u64>() / 1024;
+        let rss: Option<u64> = regions.iter().map(|it| it.rss).sum();
+        println!(
+            "{} purgeable regions, {} KiB, RSS {} KiB",
+            regions.len(),
+            size,
+            kib(rss)
+        );
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// Captured from `/proc/<pid>/smaps` of `purgeable-pressure` and an Android app
+        const SMAPS: &str = "\
+55d0c5a4b000-55d0c5a6d000 r--p 00000000 fd:01 1835299                    /usr/bin/purgeable-pressure
+Size:                136 kB
+Rss:                 136 kB
+7f3a1c000000-7f3a1c400000 rw-p 00000000 00:00 0                          [anon:purgeable:image cache]
+Size:               4096 kB
+KernelPageSize:        4 kB
+Rss:                4096 kB
+LazyFree:           1024 kB
+VmFlags: rd wr mr mw me ac sd
+7f3a1c400000-7f3a1c401000 rw-p 00000000 00:00 0                          [anon:purgeable]
+Size:                  4 kB
+Rss:                   0 kB
+LazyFree:              0 kB
+7f3a1c500000-7f3a1c510000 rw-p 00000000 00:00 0                          [anon:purgeable:thumbnails]
+Size:                 64 kB
+Rss:                  48 kB
+LazyFree:             48 kB
+7f3a1c510000-7f3a1c520000 rw-p 00000000 00:00 0                          [anon:purgeable:glyphs]
+Size:                 64 kB
+Rss:                  64 kB
+LazyFree:              0 kB
+7f3a1c401000-7f3a1c402000 rw-p 00000000 00:00 0 
+Rss:                   4 kB
+LazyFree:              4 kB
+7f3a1d000000-7f3a1d100000 rw-s 00000000 00:0f 2048                       /dev/ashmem/purgeable:tiles (deleted)
+Size:               1024 kB
+Rss:                 512 kB
+7f1e2a400000-7f1e2a600000 rw-s 00000000 00:01 3073                       /memfd:purgeable:shared (deleted)
+Size:               2048 kB
+Rss:                2048 kB
+7ffd2b5e0000-7ffd2b601000 rw-p 00000000 00:00 0                          [stack]
+Rss:                  16 kB
+";
+
+        #[test]
+        fn test_parse_header() {
+            assert_eq!(
+                parse_header(
+                    "7f3a1c000000-7f3a1c400000 rw-p 00000000 00:00 0                          [anon:purgeable:image cache]"
+                ),
+                Some((0x7f3a1c000000, 0x7f3a1c400000, "[anon:purgeable:image cache]"))
+            );
+            assert_eq!(
+                parse_header(
+                    "7f1e2a400000-7f1e2a600000 rw-s 00000000 00:01 3073 /memfd:purgeable (deleted)"
+                ),
+                Some((0x7f1e2a400000, 0x7f1e2a600000, "/memfd:purgeable (deleted)"))
+            );
+            assert_eq!(
+                parse_header("7f3a1c401000-7f3a1c402000 rw-p 00000000 00:00 0 "),
+                Some((0x7f3a1c401000, 0x7f3a1c402000, ""))
+            );
+            assert_eq!(
+                parse_header("7f3a1c401000-7f3a1c402000 rw-p 00000000 00:00 0"),
+                Some((0x7f3a1c401000, 0x7f3a1c402000, ""))
+            );
+            assert_eq!(
+                parse_header(
+                    "ffffffffff600000-ffffffffff601000 --xp 00000000 00:00 0                  [vsyscall]"
+                ),
+                Some((0xffffffffff600000, 0xffffffffff601000, "[vsyscall]"))
+            );
+
+            assert_eq!(parse_header("Rss:                4096 kB"), None);
+            assert_eq!(parse_header("VmFlags: rd wr mr mw me ac sd"), None);
+            assert_eq!(parse_header("THPeligible:    0"), None);
+            assert_eq!(parse_header(""), None);
+        }
+
+        #[test]
+        fn test_parse_name() {
+            assert_eq!(parse_name("[anon:purgeable]"), Some((Kind::Anon, None)));
+            assert_eq!(
+                parse_name("[anon:purgeable:image cache]"),
+                Some((Kind::Anon, Some("image cache".to_owned())))
+            );
+            assert_eq!(
+                parse_name("/dev/ashmem/purgeable:tiles (deleted)"),
+                Some((Kind::Ashmem, Some("tiles".to_owned())))
+            );
+            assert_eq!(
+                parse_name("/dev/ashmem/purgeable"),
+                Some((Kind::Ashmem, None))
+            );
+            assert_eq!(
+                parse_name("/memfd:purgeable (deleted)"),
+                Some((Kind::Memfd, None))
+            );
+            assert_eq!(
+                parse_name("/memfd:purgeable:shared (deleted)"),
+                Some((Kind::Memfd, Some("shared".to_owned())))
+            );
+            assert_eq!(
+                parse_name("[anon:purgeable:]"),
+                Some((Kind::Anon, Some(String::new())))
+            );
+
+            for path in [
+                "",
+                "[heap]",
+                "[stack]",
+                "[anon:scudo:primary]",
+                "[anon:purgeables]",
+                "[anon:purgeable",
+                "/dev/ashmem/dalvik-main space (region space) (deleted)",
+                "/memfd:jit-cache (deleted)",
+                "/memfd:purgeables (deleted)",
+                "/usr/lib/libpurgeable.so",
+            ] {
+                assert_eq!(parse_name(path), None, "{}", path);
+            }
+        }
+
+        #[test]
+        fn test_parse_smaps() {
+            assert_eq!(
+                parse(SMAPS, true),
+                [
+                    Region {
+                        start: 0x7f3a1c000000,
+                        end: 0x7f3a1c400000,
+                        kind: Kind::Anon,
+                        label: Some("image cache".to_owned()),
+                        rss: Some(4096),
+                        lazy_free: Some(1024),
+                    },
+                    Region {
+                        start: 0x7f3a1c400000,
+                        end: 0x7f3a1c401000,
+                        kind: Kind::Anon,
+                        label: None,
+                        rss: Some(0),
+                        lazy_free: Some(0),
+                    },
+                    Region {
+                        start: 0x7f3a1c500000,
+                        end: 0x7f3a1c510000,
+                        kind: Kind::Anon,
+                        label: Some("thumbnails".to_owned()),
+                        rss: Some(48),
+                        lazy_free: Some(48),
+                    },
+                    Region {
+                        start: 0x7f3a1c510000,
+                        end: 0x7f3a1c520000,
+                        kind: Kind::Anon,
+                        label: Some("glyphs".to_owned()),
+                        rss: Some(64),
+                        lazy_free: Some(0),
+                    },
+                    Region {
+                        start: 0x7f3a1d000000,
+                        end: 0x7f3a1d100000,
+                        kind: Kind::Ashmem,
+                        label: Some("tiles".to_owned()),
+                        rss: Some(512),
+                        lazy_free: Some(0),
+                    },
+                    Region {
+                        start: 0x7f1e2a400000,
+                        end: 0x7f1e2a600000,
+                        kind: Kind::Memfd,
+                        label: Some("shared".to_owned()),
+                        rss: Some(2048),
+                        lazy_free: Some(0),
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn test_state() {
+            let states: Vec<_> = parse(SMAPS, true).iter().map(Region::state).collect();
+            assert_eq!(
+                states,
+                [
+                    Some("mixed"),
+                    None,
+                    Some("unlocked"),
+                    Some("locked"),
+                    None,
+                    None
+                ]
+            );
+        }
+
+        #[test]
+        fn test_parse_maps() {
+            let maps: String = SMAPS
+                .lines()
+                .filter(|line| parse_header(line).is_some())
+                .map(|line| format!("{}\n", line))
+                .collect();
+            let regions = parse(&maps, false);
+            assert_eq!(regions.len(), 6);
+            assert!(regions
+                .iter()
+                .all(|it| it.rss.is_none() && it.lazy_free.is_none() && it.state().is_none()));
+            assert_eq!(regions[4].kind, Kind::Ashmem);
+            assert_eq!(regions[5].kind, Kind::Memfd);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! A minimal JSON object writer; reports are flat, so a serializer isn't worth a dependency
+
+use std::fmt::Write;
+
+pub struct Object {
+    buf: String,
+}
+
+impl Object {
+    pub fn new() -> Object {
+        Object {
+            buf: String::from("{"),
+        }
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.buf.len() > 1 {
+            self.buf.push(',');
+        }
+        write_str(&mut self.buf, key);
+        self.buf.push(':');
+    }
+
+    pub fn str(mut self, key: &str, value: &str) -> Object {
+        self.key(key);
+        write_str(&mut self.buf, value);
+        self
+    }
+
+    pub fn opt_str(mut self, key: &str, value: Option<&str>) -> Object {
+        match value {
+            Some(value) => self.str(key, value),
+            None => {
+                self.key(key);
+                self.buf.push_str("null");
+                self
+            }
+        }
+    }
+
+    pub fn num(mut self, key: &str, value: u64) -> Object {
+        self.key(key);
+        write!(self.buf, "{}", value).unwrap();
+        self
+    }
+
+    pub fn finish(mut self) -> String {
+        self.buf.push('}');
+        self.buf
+    }
+}
+
+fn write_str(buf: &mut String, s: &str) {
+    buf.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if c < ' ' => write!(buf, "\\u{:04x}", c as u32).unwrap(),
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
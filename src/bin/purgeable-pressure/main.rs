@@ -0,0 +1,315 @@
+//! Runs a memory-pressure scenario script and reports every step as a JSON line.
+//!
+//! Usage: `purgeable-pressure [SCRIPT]`; the script is read from `stdin` if it is omitted or
+//! `-`. Commands, one per line (`#` starts a comment):
+//!
+//! ```text
+//! purgeable <group> <size>   # allocate a locked purgeable box, e.g. `purgeable cache 64M`
+//! alloc <group> <size>       # allocate plain memory
+//! unlock <group>             # unlock all locked boxes of the group
+//! lock <group>               # lock all unlocked boxes of the group
+//! regenerate <group>         # lock all boxes of the group, reallocating purged ones
+//! free <group>               # drop all boxes of the group
+//! hold <duration>            # keep plain memory resident for `100ms`, `2s`, ...
+//! sleep <duration>
+//! repeat <n> <command>
+//! repeat <n>                 # repeats the commands up to the matching `end`
+//! end
+//! ```
+//!
+//! Every step is reported as `{"event":"step",...}` with its duration in microseconds, the
+//! number of boxes found purged and the number of allocations that failed; the last line is
+//! `{"event":"summary",...}`.
+
+mod json;
+mod script;
+#[path = "../common/size.rs"]
+mod size;
+#[cfg(test)]
+mod tests;
+
+use json::Object;
+use purgeable::{Backend, NonPurgeableBox, PurgeableBox};
+use script::{Command, Step};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use std::{env, fs, io, thread};
+
+/// The byte purgeable boxes are filled with, so their pages are actually allocated
+const FILL_BYTE: u8 = 0xa5;
+
+enum Slot {
+    Locked(NonPurgeableBox<[u8]>),
+    Unlocked(PurgeableBox<[u8]>),
+    Purged(usize),
+    Plain(Box<[u8]>),
+}
+
+impl Slot {
+    fn size(&self) -> usize {
+        match self {
+            Slot::Locked(b) => b.len(),
+            Slot::Unlocked(b) => b.size(),
+            Slot::Purged(size) => *size,
+            Slot::Plain(b) => b.len(),
+        }
+    }
+}
+
+/// What a step did
+#[derive(Default)]
+struct Outcome {
+    /// The number of boxes the step has been applied to
+    boxes: usize,
+    bytes: usize,
+    /// The number of boxes found purged
+    purged: usize,
+    /// The number of allocations that failed; boxes that couldn't be regenerated stay purged
+    failed: usize,
+}
+
+impl Outcome {
+    fn failed() -> Outcome {
+        Outcome {
+            failed: 1,
+            ..Outcome::default()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Runner {
+    groups: HashMap<String, Vec<Slot>>,
+    backend: Option<Backend>,
+    total_purged: usize,
+    total_failed: usize,
+}
+
+impl Runner {
+    fn run(&mut self, step: &Step) -> Outcome {
+        let outcome = self.apply(&step.command);
+        self.total_purged += outcome.purged;
+        self.total_failed += outcome.failed;
+        outcome
+    }
+
+    fn apply(&mut self, command: &Command) -> Outcome {
+        match command {
+            Command::Purgeable { group, size } => {
+                match NonPurgeableBox::try_new_filled_slice(FILL_BYTE, *size) {
+                    Ok(b) => {
+                        self.backend.get_or_insert(NonPurgeableBox::backend(&b));
+                        self.add(group, Slot::Locked(b))
+                    }
+                    Err(_) => Outcome::failed(),
+                }
+            }
+            Command::Alloc { group, size } => match alloc_plain(*size) {
+                Some(b) => self.add(group, Slot::Plain(b)),
+                None => Outcome::failed(),
+            },
+            Command::Unlock { group } => self.update(group, |slot, _| match slot {
+                Slot::Locked(b) => Slot::Unlocked(NonPurgeableBox::unlock(b)),
+                slot => slot,
+            }),
+            Command::Lock { group } => self.update(group, |slot, outcome| match slot {
+                Slot::Unlocked(b) => {
+                    let size = b.size();
+                    b.lock().map(Slot::Locked).unwrap_or_else(|_| {
+                        outcome.purged += 1;
+                        Slot::Purged(size)
+                    })
+                }
+                slot => slot,
+            }),
+            Command::Regenerate { group } => self.update(group, |slot, outcome| match slot {
+                Slot::Unlocked(b) => {
+                    let size = b.size();
+                    b.lock().map(Slot::Locked).unwrap_or_else(|_| {
+                        outcome.purged += 1;
+                        regenerate(size, outcome)
+                    })
+                }
+                Slot::Purged(size) => regenerate(size, outcome),
+                slot => slot,
+            }),
+            Command::Free { group } => {
+                let slots = self.groups.remove(group).unwrap_or_default();
+                Outcome {
+                    boxes: slots.len(),
+                    bytes: slots.iter().map(Slot::size).sum(),
+                    ..Outcome::default()
+                }
+            }
+            Command::Hold(duration) => {
+                self.hold(*duration);
+                Outcome::default()
+            }
+            Command::Sleep(duration) => {
+                thread::sleep(*duration);
+                Outcome::default()
+            }
+        }
+    }
+
+    fn add(&mut self, group: &str, slot: Slot) -> Outcome {
+        let bytes = slot.size();
+        self.groups.entry(group.to_owned()).or_default().push(slot);
+        Outcome {
+            boxes: 1,
+            bytes,
+            ..Outcome::default()
+        }
+    }
+
+    /// `f` returns the new slot and counts purges and failures in the outcome
+    fn update(&mut self, group: &str, mut f: impl FnMut(Slot, &mut Outcome) -> Slot) -> Outcome {
+        let mut outcome = Outcome::default();
+        let slots = self.groups.remove(group).unwrap_or_default();
+        let slots = slots
+            .into_iter()
+            .map(|slot| {
+                let slot = f(slot, &mut outcome);
+                outcome.boxes += 1;
+                outcome.bytes += slot.size();
+                slot
+            })
+            .collect();
+        self.groups.insert(group.to_owned(), slots);
+        outcome
+    }
+
+    fn hold(&mut self, duration: Duration) {
+        let page_size = page_size::get();
+        let deadline = Instant::now() + duration;
+        loop {
+            for slot in self.groups.values_mut().flatten() {
+                if let Slot::Plain(b) = slot {
+                    for page in b.chunks_mut(page_size) {
+                        // A volatile write, so the touch isn't optimized out
+                        unsafe { std::ptr::write_volatile(&mut page[0], 1) };
+                    }
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(10)));
+        }
+    }
+
+    fn resident_bytes(&self) -> Option<usize> {
+        #[cfg(unix)]
+        {
+            self.groups
+                .values()
+                .flatten()
+                .map(|slot| match slot {
+                    Slot::Locked(b) => NonPurgeableBox::resident_bytes(b),
+                    Slot::Unlocked(b) => b.resident_bytes(),
+                    _ => Ok(0),
+                })
+                .sum::<std::io::Result<usize>>()
+                .ok()
+        }
+        #[cfg(not(unix))]
+        None
+    }
+}
+
+/// The slot stays purged if the allocation fails
+fn regenerate(size: usize, outcome: &mut Outcome) -> Slot {
+    match NonPurgeableBox::try_new_filled_slice(FILL_BYTE, size) {
+        Ok(b) => Slot::Locked(b),
+        Err(_) => {
+            outcome.failed += 1;
+            Slot::Purged(size)
+        }
+    }
+}
+
+/// Returns `None` instead of aborting if the memory can't be allocated
+fn alloc_plain(size: usize) -> Option<Box<[u8]>> {
+    let mut plain = Vec::new();
+    plain.try_reserve_exact(size).ok()?;
+    plain.resize(size, 1u8);
+    Some(plain.into_boxed_slice())
+}
+
+fn read_script(path: Option<&str>) -> io::Result<String> {
+    match path {
+        None | Some("-") => {
+            let mut script = String::new();
+            io::stdin().read_to_string(&mut script)?;
+            Ok(script)
+        }
+        Some(path) => fs::read_to_string(path),
+    }
+}
+
+fn main() -> ExitCode {
+    let path = env::args().nth(1);
+    let script = match read_script(path.as_deref()) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("Failed to read the script: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let script = match script::parse(&script) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("Invalid script: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut runner = Runner::default();
+    let mut stdout = io::stdout().lock();
+    let started = Instant::now();
+    let mut steps = 0;
+    for (i, step) in script.steps().enumerate() {
+        steps += 1;
+        let step_started = Instant::now();
+        let outcome = runner.run(step);
+        let elapsed = step_started.elapsed();
+
+        let stats = purgeable::stats();
+        let mut report = Object::new()
+            .str("event", "step")
+            .num("step", i as u64)
+            .num("line", step.line as u64)
+            .str("command", &step.text)
+            .num("elapsed_us", elapsed.as_micros() as u64)
+            .num("boxes", outcome.boxes as u64)
+            .num("bytes", outcome.bytes as u64)
+            .num("purged", outcome.purged as u64)
+            .num("failed", outcome.failed as u64)
+            .num("locked_bytes", stats.locked.bytes as u64)
+            .num("unlocked_bytes", stats.unlocked.bytes as u64);
+        if let Some(resident) = runner.resident_bytes() {
+            report = report.num("resident_bytes", resident as u64);
+        }
+        if writeln!(stdout, "{}", report.finish()).is_err() {
+            // The reader has gone away
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let stats = purgeable::stats();
+    let summary = Object::new()
+        .str("event", "summary")
+        .opt_str("backend", runner.backend.map(|it| it.name()))
+        .num("steps", steps)
+        .num("elapsed_us", started.elapsed().as_micros() as u64)
+        .num("purged", runner.total_purged as u64)
+        .num("failed", runner.total_failed as u64)
+        .num("lock_attempts", stats.locks.attempts)
+        .num("lock_failures", stats.locks.failures)
+        .finish();
+    let _ = writeln!(stdout, "{}", summary);
+    ExitCode::SUCCESS
+}
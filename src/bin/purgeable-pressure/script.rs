@@ -0,0 +1,215 @@
+//! The scenario script parser
+
+use crate::size::parse_size;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Allocates a locked purgeable box and adds it to the group
+    Purgeable {
+        group: String,
+        size: usize,
+    },
+    /// Allocates plain memory and adds it to the group
+    Alloc {
+        group: String,
+        size: usize,
+    },
+    Unlock {
+        group: String,
+    },
+    /// Locks unlocked boxes of the group; purged boxes stay purged
+    Lock {
+        group: String,
+    },
+    /// Locks unlocked boxes of the group and reallocates purged ones
+    Regenerate {
+        group: String,
+    },
+    /// Drops all boxes of the group
+    Free {
+        group: String,
+    },
+    /// Keeps plain memory resident by touching it for the duration
+    Hold(Duration),
+    Sleep(Duration),
+}
+
+/// A command with the script line it comes from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Step {
+    pub line: usize,
+    pub text: String,
+    pub command: Command,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseError {
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A parsed script; `repeat` blocks are kept as parsed and iterated by [Script::steps]
+#[derive(Debug)]
+pub struct Script {
+    items: Vec<Item>,
+}
+
+#[derive(Debug)]
+enum Item {
+    Step(Step),
+    Repeat { count: usize, body: Vec<Item> },
+}
+
+impl Script {
+    /// The steps in execution order; `repeat` blocks are walked `count` times, not expanded
+    pub fn steps(&self) -> Steps<'_> {
+        Steps {
+            stack: vec![(&self.items, 0, 1)],
+        }
+    }
+}
+
+/// See [Script::steps]
+pub struct Steps<'a> {
+    /// The blocks being walked, with the index of their next item and the number of passes
+    /// left including the current one
+    stack: Vec<(&'a [Item], usize, usize)>,
+}
+
+impl<'a> Iterator for Steps<'a> {
+    type Item = &'a Step;
+
+    fn next(&mut self) -> Option<&'a Step> {
+        loop {
+            let (items, index, passes) = self.stack.last_mut()?;
+            let items: &'a [Item] = items;
+            let Some(item) = items.get(*index) else {
+                *passes -= 1;
+                if *passes == 0 {
+                    self.stack.pop();
+                } else {
+                    *index = 0;
+                }
+                continue;
+            };
+            *index += 1;
+            match item {
+                Item::Step(step) => return Some(step),
+                Item::Repeat { count, body } if *count > 0 && !body.is_empty() => {
+                    self.stack.push((body, 0, *count));
+                }
+                Item::Repeat { .. } => {}
+            }
+        }
+    }
+}
+
+pub fn parse(script: &str) -> Result<Script, ParseError> {
+    let mut lines = script
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, line)| !line.is_empty());
+    let items = parse_block(&mut lines, None)?;
+    Ok(Script { items })
+}
+
+fn parse_block<'a>(
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    repeat_line: Option<usize>,
+) -> Result<Vec<Item>, ParseError> {
+    let mut items = Vec::new();
+    while let Some((line, text)) = lines.next() {
+        let error = |message: String| ParseError { line, message };
+        let (keyword, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let args = args.trim();
+        match keyword {
+            "end" if repeat_line.is_some() => return Ok(items),
+            "end" => return Err(error("`end` without `repeat`".to_owned())),
+            "repeat" => {
+                let (count, command) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                let count = count
+                    .parse::<usize>()
+                    .map_err(|_| error(format!("invalid repeat count `{}`", count)))?;
+                let body = if command.trim().is_empty() {
+                    parse_block(lines, Some(line))?
+                } else {
+                    let command = command.trim();
+                    vec![Item::Step(Step {
+                        line,
+                        text: command.to_owned(),
+                        command: parse_command(command).map_err(error)?,
+                    })]
+                };
+                items.push(Item::Repeat { count, body });
+            }
+            _ => items.push(Item::Step(Step {
+                line,
+                text: text.to_owned(),
+                command: parse_command(text).map_err(error)?,
+            })),
+        }
+    }
+    match repeat_line {
+        Some(line) => Err(ParseError {
+            line,
+            message: "`repeat` without `end`".to_owned(),
+        }),
+        None => Ok(items),
+    }
+}
+
+fn parse_command(text: &str) -> Result<Command, String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let command = match words.as_slice() {
+        ["purgeable" | "p", group, size] => Command::Purgeable {
+            group: group.to_string(),
+            size: parse_size(size)?,
+        },
+        ["alloc" | "a", group, size] => Command::Alloc {
+            group: group.to_string(),
+            size: parse_size(size)?,
+        },
+        ["unlock", group] => Command::Unlock {
+            group: group.to_string(),
+        },
+        ["lock", group] => Command::Lock {
+            group: group.to_string(),
+        },
+        ["regenerate", group] => Command::Regenerate {
+            group: group.to_string(),
+        },
+        ["free", group] => Command::Free {
+            group: group.to_string(),
+        },
+        ["hold", duration] => Command::Hold(parse_duration(duration)?),
+        ["sleep", duration] => Command::Sleep(parse_duration(duration)?),
+        _ => return Err(format!("unknown command `{}`", text)),
+    };
+    Ok(command)
+}
+
+/// Parses durations like `500us`, `100ms` or `2s`
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let error = || format!("invalid duration `{}`", duration);
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(error)?;
+    let number = duration[..split].parse::<u64>().map_err(|_| error())?;
+    match &duration[split..] {
+        "us" => Ok(Duration::from_micros(number)),
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        _ => Err(error()),
+    }
+}
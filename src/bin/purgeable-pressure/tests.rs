@@ -0,0 +1,188 @@
+use crate::json::Object;
+use crate::script::{self, parse_duration, Command};
+use crate::size::parse_size;
+use std::time::Duration;
+
+fn commands(script: &str) -> Vec<(usize, Command)> {
+    let script = script::parse(script).unwrap();
+    script
+        .steps()
+        .map(|step| (step.line, step.command.clone()))
+        .collect()
+}
+
+fn parse_error(script: &str) -> String {
+    script::parse(script).unwrap_err().to_string()
+}
+
+#[test]
+fn test_parse() {
+    let script = "
+        # a comment
+        purgeable cache 64M
+        p small 4k   # aliases
+        a ballast 1g
+        unlock cache
+        lock cache
+        regenerate cache
+        free cache
+        hold 100ms
+        sleep 2s
+    ";
+    let group = || "cache".to_owned();
+    assert_eq!(
+        commands(script),
+        [
+            (
+                3,
+                Command::Purgeable {
+                    group: group(),
+                    size: 64 << 20
+                }
+            ),
+            (
+                4,
+                Command::Purgeable {
+                    group: "small".to_owned(),
+                    size: 4 << 10
+                }
+            ),
+            (
+                5,
+                Command::Alloc {
+                    group: "ballast".to_owned(),
+                    size: 1 << 30
+                }
+            ),
+            (6, Command::Unlock { group: group() }),
+            (7, Command::Lock { group: group() }),
+            (8, Command::Regenerate { group: group() }),
+            (9, Command::Free { group: group() }),
+            (10, Command::Hold(Duration::from_millis(100))),
+            (11, Command::Sleep(Duration::from_secs(2))),
+        ]
+    );
+
+    let step = script::parse("p cache 1k # comment").unwrap();
+    assert_eq!(step.steps().next().unwrap().text, "p cache 1k");
+    assert_eq!(commands(""), []);
+    assert_eq!(commands("\n# only comments\n"), []);
+}
+
+#[test]
+fn test_parse_repeat() {
+    let unlock = || Command::Unlock {
+        group: "a".to_owned(),
+    };
+    let lock = || Command::Lock {
+        group: "a".to_owned(),
+    };
+    assert_eq!(commands("repeat 3 unlock a"), vec![(1, unlock()); 3]);
+    assert_eq!(commands("repeat 0 unlock a\nlock a"), [(2, lock())]);
+    assert_eq!(
+        commands("repeat 2\n unlock a\n repeat 2\n  lock a\n end\nend\nunlock a"),
+        [
+            (2, unlock()),
+            (4, lock()),
+            (4, lock()),
+            (2, unlock()),
+            (4, lock()),
+            (4, lock()),
+            (7, unlock()),
+        ]
+    );
+    assert_eq!(commands("repeat 5\nend\nlock a"), [(3, lock())]);
+
+    // Repeats are walked, not expanded
+    let script = script::parse("repeat 1000000000000\n repeat 1000000000000 lock a\nend").unwrap();
+    assert_eq!(script.steps().take(3).count(), 3);
+}
+
+#[test]
+fn test_parse_errors() {
+    assert_eq!(
+        parse_error("lock a\njump a"),
+        "line 2: unknown command `jump a`"
+    );
+    assert_eq!(parse_error("lock"), "line 1: unknown command `lock`");
+    assert_eq!(parse_error("p cache 1x"), "line 1: invalid size `1x`");
+    assert_eq!(parse_error("sleep 1h"), "line 1: invalid duration `1h`");
+    assert_eq!(
+        parse_error("repeat x lock a"),
+        "line 1: invalid repeat count `x`"
+    );
+    assert_eq!(
+        parse_error("repeat 2 jump"),
+        "line 1: unknown command `jump`"
+    );
+    assert_eq!(parse_error("\nend"), "line 2: `end` without `repeat`");
+    assert_eq!(
+        parse_error("repeat 2\n repeat 2\n  lock a\nend"),
+        "line 1: `repeat` without `end`"
+    );
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("0"), Ok(0));
+    assert_eq!(parse_size("4096"), Ok(4096));
+    assert_eq!(parse_size("64k"), Ok(64 << 10));
+    assert_eq!(parse_size("64K"), Ok(64 << 10));
+    assert_eq!(parse_size("16m"), Ok(16 << 20));
+    assert_eq!(parse_size("16M"), Ok(16 << 20));
+    assert_eq!(parse_size("1g"), Ok(1 << 30));
+    assert_eq!(parse_size("2G"), Ok(2 << 30));
+
+    for size in ["", "k", "-1", "1.5M", "1t", "1 k", "1kb"] {
+        assert_eq!(parse_size(size), Err(format!("invalid size `{}`", size)));
+    }
+    let overflow = format!("{}g", usize::MAX);
+    assert!(parse_size(&overflow).is_err());
+    assert!(parse_size(&format!("{}0", usize::MAX)).is_err());
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("500us"), Ok(Duration::from_micros(500)));
+    assert_eq!(parse_duration("100ms"), Ok(Duration::from_millis(100)));
+    assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+    assert_eq!(parse_duration("0s"), Ok(Duration::ZERO));
+
+    for duration in ["", "100", "ms", "1m", "1.5s", "-1s", "1 s", "1S"] {
+        assert_eq!(
+            parse_duration(duration),
+            Err(format!("invalid duration `{}`", duration))
+        );
+    }
+    assert!(parse_duration(&format!("{}0s", u64::MAX)).is_err());
+}
+
+#[test]
+fn test_json_object() {
+    assert_eq!(Object::new().finish(), "{}");
+    assert_eq!(
+        Object::new()
+            .str("event", "step")
+            .num("step", 0)
+            .num("max", u64::MAX)
+            .opt_str("backend", Some("sim"))
+            .opt_str("label", None)
+            .finish(),
+        r#"{"event":"step","step":0,"max":18446744073709551615,"backend":"sim","label":null}"#
+    );
+}
+
+#[test]
+fn test_json_escaping() {
+    let escaped = |s: &str| Object::new().str("s", s).finish();
+    assert_eq!(escaped(""), r#"{"s":""}"#);
+    assert_eq!(escaped(r#"say "hi""#), r#"{"s":"say \"hi\""}"#);
+    assert_eq!(escaped(r"C:\tmp"), r#"{"s":"C:\\tmp"}"#);
+    assert_eq!(escaped("a\nb\rc\td"), r#"{"s":"a\nb\rc\td"}"#);
+    assert_eq!(escaped("\0\u{1b}\u{1f}"), r#"{"s":"\u0000\u001b\u001f"}"#);
+    assert_eq!(escaped("\u{7f}é€🦀"), "{\"s\":\"\u{7f}é€🦀\"}");
+    assert_eq!(
+        Object::new().str("a \"key\"", "v").finish(),
+        r#"{"a \"key\"":"v"}"#
+    );
+}
@@ -0,0 +1,437 @@
+//! Replays a trace written by `purgeable::recorder` against a simulated backend.
+//!
+//! Usage: `purgeable-replay <trace> [--limit <size>] [--policy <policy>]`
+//!
+//! The simulated system keeps at most `--limit` bytes (e.g. `512M`; unlimited by default) of
+//! locked and unlocked boxes resident. When the limit is exceeded, unlocked boxes are purged
+//! in the `--policy` order:
+//! - `fifo` (default): the least recently unlocked first;
+//! - `lifo`: the most recently unlocked first;
+//! - `largest`: the largest first;
+//! - `priority`: in the [PurgePriority::purge_order], as the simulated backend does.
+//!
+//! Locks that fail in the simulation are compared to the recorded outcomes. A box that the
+//! simulation purged but the application locked successfully is considered regenerated. A
+//! failed lock consumes the box, so a box whose recorded lock failed stays non-resident until
+//! it is dropped; the application regenerates the content into a new box.
+//!
+//! The replay models only the sizes and states of the boxes instead of running the crate's
+//! simulated backend (`--cfg purgeable_sim`): that backend replaces the OS backends for the
+//! whole build, allocates the memory of every box and purges only in the [PurgePriority]
+//! order. The `priority` policy shares that order through [PurgePriority::purge_order].
+
+#[path = "common/size.rs"]
+mod size;
+
+use purgeable::recorder::{TraceEventKind, TraceReader};
+use purgeable::PurgePriority;
+use size::parse_size;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::BufReader;
+use std::process::ExitCode;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Policy {
+    Fifo,
+    Lifo,
+    Largest,
+    Priority,
+}
+
+impl Policy {
+    fn parse(name: &str) -> Option<Policy> {
+        match name {
+            "fifo" => Some(Policy::Fifo),
+            "lifo" => Some(Policy::Lifo),
+            "largest" => Some(Policy::Largest),
+            "priority" => Some(Policy::Priority),
+            _ => None,
+        }
+    }
+}
+
+enum State {
+    Locked,
+    Unlocked { priority: PurgePriority, seq: u64 },
+    Purged,
+}
+
+struct SimBox {
+    size: usize,
+    state: State,
+}
+
+/// An unlocked box, ordered for purging by the policy: the least candidate is purged first
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Candidate {
+    policy: Policy,
+    box_id: u64,
+    size: usize,
+    priority: PurgePriority,
+    seq: u64,
+}
+
+impl Candidate {
+    fn of(policy: Policy, box_id: u64, b: &SimBox) -> Option<Candidate> {
+        match b.state {
+            State::Unlocked { priority, seq } => Some(Candidate {
+                policy,
+                box_id,
+                size: b.size,
+                priority,
+                seq,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        let order = match self.policy {
+            Policy::Fifo => self.seq.cmp(&other.seq),
+            Policy::Lifo => other.seq.cmp(&self.seq),
+            Policy::Largest => other.size.cmp(&self.size),
+            Policy::Priority => self
+                .priority
+                .purge_order(self.seq)
+                .cmp(&other.priority.purge_order(other.seq)),
+        };
+        // Ties are broken by the id, so replays are deterministic
+        order.then(self.box_id.cmp(&other.box_id))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    events: u64,
+    /// Events of boxes allocated before the recording has started
+    unknown_events: u64,
+    allocations: u64,
+    locks: u64,
+    recorded_failures: u64,
+    simulated_failures: u64,
+    /// Locks that failed both in the recording and in the simulation
+    matching_failures: u64,
+    purges: u64,
+    purged_bytes: u64,
+    peak_resident: usize,
+    /// The peak of resident bytes above the limit when nothing could be purged
+    peak_overcommit: usize,
+}
+
+struct Simulation {
+    limit: usize,
+    policy: Policy,
+    boxes: HashMap<u64, SimBox>,
+    /// The unlocked boxes in the purge order
+    candidates: BTreeSet<Candidate>,
+    resident: usize,
+    next_seq: u64,
+    report: Report,
+}
+
+impl Simulation {
+    fn new(limit: usize, policy: Policy) -> Simulation {
+        Simulation {
+            limit,
+            policy,
+            boxes: HashMap::new(),
+            candidates: BTreeSet::new(),
+            resident: 0,
+            next_seq: 0,
+            report: Report::default(),
+        }
+    }
+
+    fn apply(&mut self, box_id: u64, kind: TraceEventKind) {
+        self.report.events += 1;
+        if let TraceEventKind::Allocated { size, .. } = kind {
+            self.report.allocations += 1;
+            self.boxes.insert(
+                box_id,
+                SimBox {
+                    size,
+                    state: State::Locked,
+                },
+            );
+            self.resident += size;
+            self.enforce_limit();
+            return;
+        }
+
+        let Some(b) = self.boxes.get_mut(&box_id) else {
+            self.report.unknown_events += 1;
+            return;
+        };
+        if let Some(candidate) = Candidate::of(self.policy, box_id, b) {
+            self.candidates.remove(&candidate);
+        }
+        match kind {
+            TraceEventKind::Unlocked { priority } => {
+                b.state = State::Unlocked {
+                    priority,
+                    seq: self.next_seq,
+                };
+                self.next_seq += 1;
+                self.candidates
+                    .extend(Candidate::of(self.policy, box_id, b));
+            }
+            TraceEventKind::Locked { success } => {
+                self.report.locks += 1;
+                let purged = matches!(b.state, State::Purged);
+                self.report.simulated_failures += purged as u64;
+                if success {
+                    if purged {
+                        // The application goes on using the box, so it has been regenerated
+                        self.resident += b.size;
+                    }
+                    b.state = State::Locked;
+                    self.enforce_limit();
+                } else {
+                    // The lock consumed the box, it is dropped next
+                    self.report.recorded_failures += 1;
+                    self.report.matching_failures += purged as u64;
+                    if !purged {
+                        self.resident -= b.size;
+                        b.state = State::Purged;
+                    }
+                }
+            }
+            TraceEventKind::Dropped => {
+                if !matches!(b.state, State::Purged) {
+                    self.resident -= b.size;
+                }
+                self.boxes.remove(&box_id);
+            }
+            _ => {}
+        }
+    }
+
+    fn enforce_limit(&mut self) {
+        while self.resident > self.limit {
+            let Some(victim) = self.candidates.pop_first() else {
+                let overcommit = self.resident - self.limit;
+                self.report.peak_overcommit = self.report.peak_overcommit.max(overcommit);
+                break;
+            };
+            self.boxes.get_mut(&victim.box_id).unwrap().state = State::Purged;
+            self.resident -= victim.size;
+            self.report.purges += 1;
+            self.report.purged_bytes += victim.size as u64;
+        }
+        self.report.peak_resident = self.report.peak_resident.max(self.resident);
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!(
+        "Usage: purgeable-replay <trace> [--limit <size>] [--policy fifo|lifo|largest|priority]"
+    );
+    ExitCode::from(2)
+}
+
+fn main() -> ExitCode {
+    let mut path = None;
+    let mut limit = usize::MAX;
+    let mut policy = Policy::Fifo;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--limit" => match args.next().as_deref().map(parse_size) {
+                Some(Ok(it)) => limit = it,
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return usage();
+                }
+                None => return usage(),
+            },
+            "--policy" => match args.next().as_deref().and_then(Policy::parse) {
+                Some(it) => policy = it,
+                None => return usage(),
+            },
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return usage(),
+        }
+    }
+    let Some(path) = path else {
+        return usage();
+    };
+
+    let reader = match File::open(&path).and_then(|it| TraceReader::new(BufReader::new(it))) {
+        Ok(reader) => reader,
+        Err(e) => {
+            eprintln!("Failed to open the trace {}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut simulation = Simulation::new(limit, policy);
+    let mut duration = None;
+    for event in reader {
+        match event {
+            Ok(event) => {
+                duration = Some(event.time);
+                simulation.apply(event.box_id, event.kind);
+            }
+            Err(e) => {
+                eprintln!("The trace is truncated or corrupted: {}", e);
+                break;
+            }
+        }
+    }
+
+    let report = &simulation.report;
+    println!("policy: {:?}", policy);
+    match limit {
+        usize::MAX => println!("limit: none"),
+        limit => println!("limit: {}", limit),
+    }
+    println!("duration_us: {}", duration.unwrap_or_default().as_micros());
+    println!("events: {}", report.events);
+    println!("unknown_events: {}", report.unknown_events);
+    println!("allocations: {}", report.allocations);
+    println!("locks: {}", report.locks);
+    println!("recorded_lock_failures: {}", report.recorded_failures);
+    println!("simulated_lock_failures: {}", report.simulated_failures);
+    println!("matching_lock_failures: {}", report.matching_failures);
+    println!("purges: {}", report.purges);
+    println!("purged_bytes: {}", report.purged_bytes);
+    println!("peak_resident_bytes: {}", report.peak_resident);
+    println!("peak_overcommit_bytes: {}", report.peak_overcommit);
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use purgeable::Backend;
+
+    fn allocate(simulation: &mut Simulation, box_id: u64, size: usize) {
+        let kind = TraceEventKind::Allocated {
+            size,
+            backend: Backend::Sim,
+        };
+        simulation.apply(box_id, kind);
+    }
+
+    fn unlock(simulation: &mut Simulation, box_id: u64, priority: PurgePriority) {
+        simulation.apply(box_id, TraceEventKind::Unlocked { priority });
+    }
+
+    /// Allocates and unlocks the `unlocked` boxes with ids `0..` in order, then allocates a box
+    /// that exceeds the limit by `overflow` bytes. Returns the ids of the purged boxes
+    fn purged(policy: Policy, unlocked: &[(usize, PurgePriority)], overflow: usize) -> Vec<u64> {
+        let total: usize = unlocked.iter().map(|it| it.0).sum();
+        let mut simulation = Simulation::new(total, policy);
+        for (id, &(size, priority)) in (0..).zip(unlocked) {
+            allocate(&mut simulation, id, size);
+            unlock(&mut simulation, id, priority);
+        }
+        assert_eq!(simulation.report.purges, 0);
+        allocate(&mut simulation, 100, overflow);
+
+        let mut purged: Vec<u64> = simulation
+            .boxes
+            .iter()
+            .filter(|(_, b)| matches!(b.state, State::Purged))
+            .map(|(&id, _)| id)
+            .collect();
+        purged.sort_unstable();
+        assert_eq!(simulation.report.purges, purged.len() as u64);
+        assert!(simulation.resident <= simulation.limit);
+        purged
+    }
+
+    #[test]
+    fn test_policies() {
+        let fifo = PurgePriority::DEFAULT;
+        let boxes = [(100, fifo), (300, fifo), (200, fifo)];
+        assert_eq!(purged(Policy::Fifo, &boxes, 1), [0]);
+        assert_eq!(purged(Policy::Fifo, &boxes, 101), [0, 1]);
+        assert_eq!(purged(Policy::Lifo, &boxes, 1), [2]);
+        assert_eq!(purged(Policy::Lifo, &boxes, 201), [1, 2]);
+        assert_eq!(purged(Policy::Largest, &boxes, 1), [1]);
+        assert_eq!(purged(Policy::Largest, &boxes, 301), [1, 2]);
+        assert_eq!(purged(Policy::Priority, &boxes, 1), [0]);
+    }
+
+    #[test]
+    fn test_priority_policy() {
+        let low = PurgePriority::new(1);
+        let high = PurgePriority::new(2);
+        let boxes = [
+            (1, high),
+            (1, low.lifo()),
+            (1, low),
+            (1, low),
+            (1, low.lifo()),
+        ];
+        // Lower levels first; FIFO boxes in the unlock order, then LIFO boxes reversed
+        let order = [2, 3, 4, 1, 0];
+        for count in 1..=order.len() {
+            let mut expected = order[..count].to_vec();
+            expected.sort_unstable();
+            assert_eq!(purged(Policy::Priority, &boxes, count), expected);
+        }
+        // The other policies ignore the priority
+        assert_eq!(purged(Policy::Fifo, &boxes, 1), [0]);
+    }
+
+    #[test]
+    fn test_replay_report() {
+        let mut simulation = Simulation::new(100, Policy::Fifo);
+        allocate(&mut simulation, 0, 60);
+        unlock(&mut simulation, 0, PurgePriority::DEFAULT);
+        allocate(&mut simulation, 1, 60);
+        assert!(matches!(simulation.boxes[&0].state, State::Purged));
+
+        // The recording says the lock failed too, so nothing is regenerated into the box
+        simulation.apply(0, TraceEventKind::Locked { success: false });
+        assert!(matches!(simulation.boxes[&0].state, State::Purged));
+        assert_eq!(simulation.resident, 60);
+        simulation.apply(0, TraceEventKind::Dropped);
+        assert_eq!(simulation.report.peak_overcommit, 0);
+
+        // The recorded lock succeeded, so the application has regenerated the box, and
+        // nothing unlocked can be purged to make room for it
+        unlock(&mut simulation, 1, PurgePriority::DEFAULT);
+        allocate(&mut simulation, 2, 60);
+        assert!(matches!(simulation.boxes[&1].state, State::Purged));
+        simulation.apply(1, TraceEventKind::Locked { success: true });
+        assert_eq!(simulation.resident, 120);
+        assert_eq!(simulation.report.peak_overcommit, 20);
+        simulation.apply(1, TraceEventKind::Dropped);
+        simulation.apply(2, TraceEventKind::Dropped);
+
+        // A failed lock of a box resident in the simulation releases it
+        allocate(&mut simulation, 3, 10);
+        unlock(&mut simulation, 3, PurgePriority::DEFAULT);
+        simulation.apply(3, TraceEventKind::Locked { success: false });
+        assert_eq!(simulation.resident, 0);
+        simulation.apply(3, TraceEventKind::Dropped);
+        simulation.apply(7, TraceEventKind::Dropped);
+
+        let report = &simulation.report;
+        assert_eq!(report.events, 15);
+        assert_eq!(report.unknown_events, 1);
+        assert_eq!(report.allocations, 4);
+        assert_eq!(report.locks, 3);
+        assert_eq!(report.recorded_failures, 2);
+        assert_eq!(report.simulated_failures, 2);
+        assert_eq!(report.matching_failures, 1);
+        assert_eq!(report.purges, 2);
+        assert_eq!(report.purged_bytes, 120);
+        assert_eq!(report.peak_resident, 120);
+        assert_eq!(simulation.resident, 0);
+        assert!(simulation.boxes.is_empty());
+        assert!(simulation.candidates.is_empty());
+    }
+}
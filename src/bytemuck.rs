@@ -0,0 +1,73 @@
+//! Casts between boxes of [bytemuck] plain data types without copying; requires the
+//! `bytemuck` feature.
+//!
+//! A locked box derefs to a slice, so it can be viewed as another type in place with
+//! [bytemuck::try_cast_slice] and [bytemuck::try_cast_slice_mut], which check the alignment
+//! and the length. Purgeable memory is page-aligned, so only views at offsets can be misaligned.
+//!
+//! # Examples
+//!
+//! ```
+//! use purgeable::NonPurgeableBox;
+//!
+//! let bytes = NonPurgeableBox::new_slice(&[1u8, 0, 0, 0, 2, 0, 0, 0]);
+//! let view: &[u32] = bytemuck::try_cast_slice(&bytes).unwrap();
+//! assert_eq!(view.len(), 2);
+//!
+//! let words: NonPurgeableBox<[u32]> = purgeable::bytemuck::cast_slice_box(bytes);
+//! assert_eq!(words[1], u32::from_le(2));
+//! let bytes: NonPurgeableBox<[u8]> = purgeable::bytemuck::cast_slice_box(words);
+//! assert_eq!(bytes.len(), 8);
+//! ```
+//!
+//! The target type must be [Pod]: the box can be unlocked again after a cast, and an
+//! [integrity verified](NonPurgeableBox::set_integrity_verified) box reads all of its bytes
+//! then, so they must not become uninitialized padding
+//!
+//! ```compile_fail
+//! use purgeable::NonPurgeableBox;
+//!
+//! #[derive(Clone, Copy)]
+//! #[repr(C)]
+//! struct Padded(u8, u16);
+//! unsafe impl bytemuck::Zeroable for Padded {}
+//! unsafe impl bytemuck::AnyBitPattern for Padded {}
+//!
+//! let bytes = NonPurgeableBox::new_slice(&[0u8; 8]);
+//! let _: NonPurgeableBox<[Padded]> = purgeable::bytemuck::cast_slice_box(bytes);
+//! ```
+
+use crate::NonPurgeableBox;
+use ::bytemuck::{NoUninit, Pod, PodCastError};
+use std::mem;
+
+/// Reinterprets a box of `A` as a box of `B` in place.
+///
+/// Fails if the box is not aligned for `B` or its size in bytes is not a multiple of the size
+/// of `B`; the box is returned back then. Casting to bytes never fails
+#[allow(clippy::result_large_err, clippy::type_complexity)]
+pub fn try_cast_slice_box<A: NoUninit, B: Pod>(
+    npb: NonPurgeableBox<[A]>,
+) -> Result<NonPurgeableBox<[B]>, (PodCastError, NonPurgeableBox<[A]>)> {
+    let size = mem::size_of_val::<[A]>(&npb);
+    if size != 0 && !(npb.as_ptr() as usize).is_multiple_of(mem::align_of::<B>()) {
+        return Err((PodCastError::TargetAlignmentGreaterAndInputNotAligned, npb));
+    }
+    match mem::size_of::<B>() {
+        0 if size != 0 => Err((PodCastError::SizeMismatch, npb)),
+        b_size if !size.is_multiple_of(b_size) => {
+            Err((PodCastError::OutputSliceWouldHaveSlop, npb))
+        }
+        // SAFETY: the alignment and the size have been checked; `A` has no uninitialized bytes
+        //  and any bytes are a valid `B`
+        _ => Ok(unsafe { npb.cast_slice() }),
+    }
+}
+
+/// Like [try_cast_slice_box], but panics if the cast fails
+pub fn cast_slice_box<A: NoUninit, B: Pod>(npb: NonPurgeableBox<[A]>) -> NonPurgeableBox<[B]> {
+    match try_cast_slice_box(npb) {
+        Ok(npb) => npb,
+        Err((e, _)) => panic!("cast_slice_box failed: {:?}", e),
+    }
+}
@@ -0,0 +1,43 @@
+use std::mem::size_of;
+
+/// Content without padding or uninitialized bytes, so the checksum can read all of it:
+/// byte slices and arrays and `str`. The trait is sealed
+pub trait ByteContent: sealed::Sealed {}
+
+impl ByteContent for [u8] {}
+impl<const N: usize> ByteContent for [u8; N] {}
+impl ByteContent for str {}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for [u8] {}
+    impl<const N: usize> Sealed for [u8; N] {}
+    impl Sealed for str {}
+}
+
+const SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+const MULTIPLIER: u64 = 0xff51_afd7_ed55_8ccd;
+
+/// A fast non-cryptographic 64-bit checksum used to detect corrupted (e.g. partially purged)
+/// content.
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads of `len` bytes and aligned to `u64`.
+pub(crate) unsafe fn checksum(ptr: *const u8, len: usize) -> u64 {
+    let words = len / size_of::<u64>();
+    let mut hash = SEED ^ len as u64;
+    for i in 0..words {
+        let word = (ptr as *const u64).add(i).read();
+        hash = (hash ^ word).wrapping_mul(MULTIPLIER).rotate_left(29);
+    }
+    for i in words * size_of::<u64>()..len {
+        hash = (hash ^ ptr.add(i).read() as u64).wrapping_mul(MULTIPLIER);
+    }
+    // Final avalanche from MurmurHash3
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(MULTIPLIER);
+    hash ^= hash >> 33;
+    hash
+}
@@ -1,26 +1,57 @@
 use std::alloc::Layout;
 use std::error::Error;
-use std::fmt;
+use std::sync::Arc;
+use std::{fmt, io};
 
 #[non_exhaustive]
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, Debug)]
 pub struct PurgeableAllocError {
     pub(crate) layout: Layout,
+    /// Why the memory has been released after a successful allocation; `Arc` keeps the error
+    /// `Clone`
+    cause: Option<Arc<io::Error>>,
 }
 
 impl PurgeableAllocError {
     pub(crate) fn new(layout: Layout) -> PurgeableAllocError {
-        PurgeableAllocError { layout }
+        PurgeableAllocError {
+            layout,
+            cause: None,
+        }
+    }
+
+    /// Available as [Error::source]
+    #[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+    pub(crate) fn with_cause(layout: Layout, cause: io::Error) -> PurgeableAllocError {
+        PurgeableAllocError {
+            layout,
+            cause: Some(Arc::new(cause)),
+        }
     }
 }
 
+/// Causes are compared by their kind and OS error code, since [io::Error] is not `PartialEq`
+impl PartialEq for PurgeableAllocError {
+    fn eq(&self, other: &PurgeableAllocError) -> bool {
+        let cause =
+            |e: &PurgeableAllocError| e.cause.as_ref().map(|it| (it.kind(), it.raw_os_error()));
+        self.layout == other.layout && cause(self) == cause(other)
+    }
+}
+
+impl Eq for PurgeableAllocError {}
+
 impl fmt::Display for PurgeableAllocError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("purgeable memory allocation failed")
     }
 }
 
-impl Error for PurgeableAllocError {}
+impl Error for PurgeableAllocError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.cause.as_deref().map(|it| it as _)
+    }
+}
 
 #[non_exhaustive]
 #[derive(Clone, PartialEq, Eq, Debug)]
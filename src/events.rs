@@ -0,0 +1,291 @@
+use crate::{os, trace, Backend};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::{io, mem};
+
+/// Describes a purge of a box detected by the library
+#[non_exhaustive]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PurgeEvent {
+    /// See [NonPurgeableBox::set_tag](crate::NonPurgeableBox::set_tag)
+    pub tag: u64,
+    pub size: usize,
+    pub backend: Backend,
+    pub detection: PurgeDetection,
+}
+
+/// How the purge has been detected
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PurgeDetection {
+    /// [PurgeableBox::lock](crate::PurgeableBox::lock) failed
+    Lock,
+    /// The box state has been queried without locking it, e.g. by `PurgeableBox::is_purged`
+    StatusQuery,
+    /// The box has been locked, but its content didn't match the checksum recorded on unlock.
+    /// See [NonPurgeableBox::set_integrity_verified](crate::NonPurgeableBox::set_integrity_verified)
+    Checksum,
+    /// The simulator enabled with `--cfg purgeable_sim` has purged the box
+    Reclaim,
+    /// A [PurgePoller] has found the box purged
+    Poll,
+}
+
+/// Registers a `callback` that is called every time the library detects a purge of a box.
+///
+/// Each purge is reported once: when it is found by a [PurgePoller] or by the simulator, the
+/// failed lock doesn't report it again.
+///
+/// The callback is called synchronously on the thread that detected the purge, so it should
+/// be cheap and must not block. The callback is unregistered when the returned [Subscription]
+/// is dropped.
+///
+/// # Examples
+///
+/// ```
+/// let subscription = purgeable::on_purge(|event| {
+///     println!("Purged {} bytes of box #{}", event.size, event.tag);
+/// });
+/// // ...
+/// drop(subscription);
+/// ```
+#[must_use = "the callback is unregistered when the subscription is dropped"]
+pub fn on_purge(callback: impl Fn(&PurgeEvent) + Send + Sync + 'static) -> Subscription {
+    let id = subscribe(Subscriber::Callback(Box::new(callback)));
+    Subscription { id }
+}
+
+/// Returns a channel that receives an event every time the library detects a purge of a box.
+///
+/// The channel is unregistered on the first event after the [Receiver] is dropped.
+pub fn purge_events() -> Receiver<PurgeEvent> {
+    let (sender, receiver) = mpsc::channel();
+    subscribe(Subscriber::Channel(Mutex::new(sender)));
+    receiver
+}
+
+/// Unregisters the [on_purge] callback on drop
+#[derive(Debug)]
+pub struct Subscription {
+    id: u64,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        unsubscribe(self.id);
+    }
+}
+
+enum Subscriber {
+    Callback(Box<dyn Fn(&PurgeEvent) + Send + Sync>),
+    Channel(Mutex<Sender<PurgeEvent>>),
+}
+
+impl Subscriber {
+    /// Returns `false` if the subscriber is disconnected
+    fn notify(&self, event: &PurgeEvent) -> bool {
+        match self {
+            Subscriber::Callback(callback) => {
+                callback(event);
+                true
+            }
+            Subscriber::Channel(sender) => match sender.lock() {
+                Ok(sender) => sender.send(event.clone()).is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+static SUBSCRIBERS: RwLock<Vec<(u64, Arc<Subscriber>)>> = RwLock::new(Vec::new());
+/// Allows skipping the `SUBSCRIBERS` lock when nobody is subscribed
+static SUBSCRIBERS_COUNT: AtomicUsize = AtomicUsize::new(0);
+static NEXT_SUBSCRIBER_ID: AtomicU64 = AtomicU64::new(0);
+
+fn subscribe(subscriber: Subscriber) -> u64 {
+    let id = NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed);
+    let mut subscribers = SUBSCRIBERS.write().unwrap_or_else(|e| e.into_inner());
+    subscribers.push((id, Arc::new(subscriber)));
+    SUBSCRIBERS_COUNT.store(subscribers.len(), Ordering::Release);
+    id
+}
+
+fn unsubscribe(id: u64) {
+    let mut subscribers = SUBSCRIBERS.write().unwrap_or_else(|e| e.into_inner());
+    subscribers.retain(|(it, _)| *it != id);
+    SUBSCRIBERS_COUNT.store(subscribers.len(), Ordering::Release);
+}
+
+pub(crate) fn emit(tag: u64, size: usize, backend: Backend, detection: PurgeDetection) {
+    if SUBSCRIBERS_COUNT.load(Ordering::Acquire) == 0 {
+        return;
+    }
+
+    // Don't hold the lock while calling subscribers, so they can (un)subscribe
+    let subscribers: Vec<_> = SUBSCRIBERS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+
+    let event = PurgeEvent {
+        tag,
+        size,
+        backend,
+        detection,
+    };
+    for (id, subscriber) in subscribers {
+        if !subscriber.notify(&event) {
+            unsubscribe(id);
+        }
+    }
+}
+
+/// Checks unlocked boxes for purges every `interval` on a background thread, so the purges
+/// are reported before the boxes are locked again. Polling stops when the returned
+/// [PurgePoller] is dropped.
+///
+/// Only boxes unlocked while a poller is running are checked, and only on backends that can
+/// check the state without locking the box: [Backend::Mach], [Backend::MadvFree] and
+/// [Backend::Sim].
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// let _poller = purgeable::poll_purges(Duration::from_secs(1))?;
+/// let subscription = purgeable::on_purge(|event| {
+///     println!("Purged {} bytes of box #{}", event.size, event.tag);
+/// });
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn poll_purges(interval: Duration) -> io::Result<PurgePoller> {
+    let (stop, stopped) = mpsc::channel::<()>();
+    POLLERS_COUNT.fetch_add(1, Ordering::Relaxed);
+    let thread = thread::Builder::new()
+        .name("purgeable-poller".to_owned())
+        .spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                poll();
+            }
+        })
+        .inspect_err(|_| {
+            POLLERS_COUNT.fetch_sub(1, Ordering::Relaxed);
+        })?;
+    Ok(PurgePoller {
+        stop: Some(stop),
+        thread: Some(thread),
+    })
+}
+
+/// Stops polling on drop, see [poll_purges]
+#[must_use = "polling stops when the poller is dropped"]
+#[derive(Debug)]
+pub struct PurgePoller {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for PurgePoller {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        POLLERS_COUNT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// An unlocked box checked by pollers
+struct Watched {
+    probe: os::PurgeProbe,
+    tag: u64,
+    size: usize,
+    backend: Backend,
+    label: Option<Box<str>>,
+    reported: bool,
+}
+
+static WATCHED: Mutex<BTreeMap<u64, Watched>> = Mutex::new(BTreeMap::new());
+static POLLERS_COUNT: AtomicUsize = AtomicUsize::new(0);
+static NEXT_WATCH_ID: AtomicU64 = AtomicU64::new(0);
+
+fn watched() -> MutexGuard<'static, BTreeMap<u64, Watched>> {
+    WATCHED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn poll() {
+    let mut purged = Vec::new();
+    for watched in watched().values_mut() {
+        // SAFETY: boxes stop being watched before they are locked or dropped, which
+        // waits for the lock held here
+        if !watched.reported && unsafe { watched.probe.is_purged() } {
+            watched.reported = true;
+            purged.push((
+                watched.tag,
+                watched.size,
+                watched.backend,
+                watched.label.clone(),
+            ));
+        }
+    }
+    // Subscribers are called without holding the lock, so they can lock boxes
+    for (tag, size, backend, label) in purged {
+        trace::purge_detected(size, backend, label.as_deref(), PurgeDetection::Poll);
+        emit(tag, size, backend, PurgeDetection::Poll);
+    }
+}
+
+/// Makes pollers check an unlocked box until it is dropped
+pub(crate) struct Watch {
+    id: u64,
+}
+
+impl Watch {
+    /// Returns `None` if no poller is running or the backend can't check the box
+    pub(crate) fn new(
+        probe: Option<os::PurgeProbe>,
+        tag: u64,
+        size: usize,
+        backend: Backend,
+        label: Option<&str>,
+    ) -> Option<Watch> {
+        if POLLERS_COUNT.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let entry = Watched {
+            probe: probe?,
+            tag,
+            size,
+            backend,
+            label: label.map(Box::from),
+            reported: false,
+        };
+        let id = NEXT_WATCH_ID.fetch_add(1, Ordering::Relaxed);
+        watched().insert(id, entry);
+        Some(Watch { id })
+    }
+
+    /// Whether a poller has reported the purge of the box
+    #[cfg(any(target_os = "macos", target_os = "ios", purgeable_sim))]
+    pub(crate) fn is_reported(&self) -> bool {
+        watched().get(&self.id).is_some_and(|it| it.reported)
+    }
+
+    /// Stops watching the box. Returns `true` if a poller has reported its purge
+    pub(crate) fn unwatch(self) -> bool {
+        let watched = watched().remove(&self.id);
+        mem::forget(self);
+        watched.is_some_and(|it| it.reported)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        watched().remove(&self.id);
+    }
+}
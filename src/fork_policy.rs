@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// What a child process created with `fork()` gets of a purgeable box; Linux and Android only.
+///
+/// The policy of new boxes is set globally with [set_fork_policy]; the policy of an existing
+/// box is changed with [NonPurgeableBox::set_fork_policy](crate::NonPurgeableBox::set_fork_policy).
+///
+/// `ashmem` and `memfd` regions are always mapped as shared memory, so the parent and the child
+/// can't get separate copies. New boxes are backed by `MADV_FREE` memory under
+/// [ForkPolicy::WipeOnFork] and [ForkPolicy::CopyOnFork] instead, and regions shared by other
+/// processes can't be mapped.
+///
+/// # Examples
+///
+/// ```
+/// use purgeable::ForkPolicy;
+///
+/// // Helper processes spawned with `fork` must not touch or pin the regions of the parent
+/// purgeable::set_fork_policy(ForkPolicy::DontFork);
+/// ```
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum ForkPolicy {
+    /// The OS default: `MADV_FREE` memory is copied on write, while `ashmem` and `memfd`
+    /// regions are shared with the child including their pin state
+    #[default]
+    Inherit,
+    /// The mapping is not inherited (`MADV_DONTFORK`); accessing the box in the child crashes
+    DontFork,
+    /// The child gets zeroed memory (`MADV_WIPEONFORK`, Linux 4.14+), so its locks of
+    /// unlocked boxes fail as if they were purged.
+    ///
+    /// Boxes that are locked at the time of the fork are zeroed in the child as well, without
+    /// any error: a [NonPurgeableBox](crate::NonPurgeableBox) held across `fork()` reads zeros
+    /// in the child
+    WipeOnFork,
+    /// The child gets a copy of the memory that is copied on write
+    CopyOnFork,
+}
+
+impl ForkPolicy {
+    const ALL: [ForkPolicy; 4] = [
+        ForkPolicy::Inherit,
+        ForkPolicy::DontFork,
+        ForkPolicy::WipeOnFork,
+        ForkPolicy::CopyOnFork,
+    ];
+
+    /// `false` if the policy requires a private mapping
+    pub(crate) fn allows_shared_mapping(self) -> bool {
+        matches!(self, ForkPolicy::Inherit | ForkPolicy::DontFork)
+    }
+}
+
+static FORK_POLICY: AtomicU8 = AtomicU8::new(0);
+
+/// Sets the [ForkPolicy] of boxes allocated afterwards. Existing boxes keep their policy
+pub fn set_fork_policy(policy: ForkPolicy) {
+    let index = ForkPolicy::ALL.iter().position(|&it| it == policy).unwrap();
+    FORK_POLICY.store(index as u8, Ordering::Relaxed);
+}
+
+/// The [ForkPolicy] of new boxes, [ForkPolicy::Inherit] by default
+pub fn fork_policy() -> ForkPolicy {
+    ForkPolicy::ALL[FORK_POLICY.load(Ordering::Relaxed) as usize]
+}
@@ -0,0 +1,173 @@
+//! [std::io] adapters over purgeable byte buffers
+
+use crate::NonPurgeableBox;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::mem::MaybeUninit;
+
+/// Reads exactly `len` bytes from the `reader` into a new purgeable buffer.
+///
+/// Returns [io::ErrorKind::UnexpectedEof] if the reader ends earlier
+pub fn read_exact_to_box(mut reader: impl Read, len: usize) -> io::Result<NonPurgeableBox<[u8]>> {
+    let mut npb = new_zeroed(&[], len)?;
+    reader.read_exact(&mut npb)?;
+    Ok(npb)
+}
+
+/// Reads the `reader` to the end into a new purgeable buffer.
+///
+/// The data is read directly into purgeable memory that grows like a `Vec`; it is copied once
+/// more at the end unless its length happens to fill the last allocation. Use
+/// [read_exact_to_box] if the length is known, e.g. from the file metadata.
+///
+/// # Examples
+///
+/// ```
+/// let npb = purgeable::io::read_to_box(&b"file content"[..])?;
+/// assert_eq!(&*npb, b"file content");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn read_to_box(mut reader: impl Read) -> io::Result<NonPurgeableBox<[u8]>> {
+    let page_size = page_size::get();
+    let mut npb = new_zeroed(&[], page_size)?;
+    let mut len = 0;
+    loop {
+        if len == npb.len() {
+            npb = new_zeroed(&npb, 2 * npb.len())?;
+        }
+        match reader.read(&mut npb[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if len == npb.len() {
+        Ok(npb)
+    } else {
+        new_zeroed(&npb[..len], len)
+    }
+}
+
+/// A new buffer of `len` bytes that starts with `prefix` and is zeroed after it
+fn new_zeroed(prefix: &[u8], len: usize) -> io::Result<NonPurgeableBox<[u8]>> {
+    let mut npb = NonPurgeableBox::try_new_uninit_slice(len)
+        .map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))?;
+    let (head, tail) = npb.split_at_mut(prefix.len());
+    // SAFETY: &[u8] and &[MaybeUninit<u8>] have the same layout
+    let uninit_prefix: &[MaybeUninit<u8>] = unsafe { std::mem::transmute(prefix) };
+    head.copy_from_slice(uninit_prefix);
+    tail.fill(MaybeUninit::new(0));
+    // SAFETY: both parts have been initialized
+    Ok(unsafe { npb.assume_init() })
+}
+
+/// Like [io::Cursor], but over a purgeable buffer.
+///
+/// Writes never grow the buffer: they stop at its end like writes to `io::Cursor<&mut [u8]>`.
+///
+/// # Examples
+///
+/// ```
+/// use purgeable::io::Cursor;
+/// use purgeable::NonPurgeableBox;
+/// use std::io::{Read, Seek, SeekFrom, Write};
+///
+/// let mut cursor = Cursor::new(NonPurgeableBox::new_filled_slice(0u8, 8));
+/// cursor.write_all(b"abcd")?;
+/// cursor.seek(SeekFrom::Start(2))?;
+/// let mut buf = [0; 2];
+/// cursor.read_exact(&mut buf)?;
+/// assert_eq!(&buf, b"cd");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Cursor {
+    inner: NonPurgeableBox<[u8]>,
+    pos: u64,
+}
+
+impl Cursor {
+    pub fn new(inner: NonPurgeableBox<[u8]>) -> Cursor {
+        Cursor { inner, pos: 0 }
+    }
+
+    pub fn into_inner(self) -> NonPurgeableBox<[u8]> {
+        self.inner
+    }
+
+    pub fn get_ref(&self) -> &NonPurgeableBox<[u8]> {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut NonPurgeableBox<[u8]> {
+        &mut self.inner
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn set_position(&mut self, pos: u64) {
+        self.pos = pos;
+    }
+
+    /// The position clamped to the buffer length
+    fn offset(&self) -> usize {
+        self.pos.min(self.inner.len() as u64) as usize
+    }
+}
+
+impl Read for Cursor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for Cursor {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let offset = self.offset();
+        Ok(&self.inner[offset..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
+impl Write for Cursor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let offset = self.offset();
+        let n = (&mut self.inner[offset..]).write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Cursor {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.inner.len() as u64, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        match base.checked_add_signed(offset) {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
@@ -1,14 +1,71 @@
 mod os;
 
+#[cfg(feature = "async")]
+mod async_cache;
+#[cfg(feature = "bytemuck")]
+pub mod bytemuck;
+mod checksum;
 mod error;
+mod events;
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+mod fork_policy;
+pub mod io;
+mod metrics_exporter;
 mod non_purgeable_box;
+mod purge_priority;
 mod purgeable_box;
+#[cfg(feature = "bytes")]
+mod purgeable_bytes;
+pub mod recorder;
+mod registry;
+#[cfg(feature = "serde")]
+pub mod serde_bytes;
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+mod shared_handle;
+mod shared_purgeable_box;
+pub mod spill;
+mod stats;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod trace;
 mod unsafe_purgeable_box;
+#[cfg(feature = "zerocopy")]
+pub mod zerocopy;
 
+#[cfg(feature = "async")]
+pub use async_cache::{AsyncCache, AsyncCacheGuard};
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+pub use fork_policy::{fork_policy, set_fork_policy, ForkPolicy};
+#[cfg(feature = "metrics")]
+pub use metrics_exporter::set_exported_labels;
 pub use non_purgeable_box::NonPurgeableBox;
+pub use os::Backend;
+pub use purge_priority::PurgePriority;
 pub use purgeable_box::PurgeableBox;
+#[cfg(feature = "bytes")]
+pub use purgeable_bytes::PurgeableBytesMut;
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+pub use shared_handle::SharedHandle;
+pub use shared_purgeable_box::{SharedGuard, SharedPurgeableBox};
+pub use stats::{stats, LockStats, MemoryStats, Stats};
 
+pub use checksum::ByteContent;
 pub use error::{PurgeableAllocError, PurgeableBoxLockError};
+pub use events::{
+    on_purge, poll_purges, purge_events, PurgeDetection, PurgeEvent, PurgePoller, Subscription,
+};
+
+/// Controls the simulated backend; requires `--cfg purgeable_sim`
+#[cfg(purgeable_sim)]
+pub mod sim {
+    pub use crate::os::sim::{purge, purge_all, purge_tagged};
+}
+
+/// Debugging helpers; requires the `debug-registry` feature
+#[cfg(feature = "debug-registry")]
+pub mod debug {
+    pub use crate::registry::{dump_live, live_allocations, BoxState, LiveAllocation};
+}
 
 pub fn is_available() -> bool {
     os::is_available()
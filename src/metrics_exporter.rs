@@ -0,0 +1,98 @@
+//! `metrics` exporter; every function is a no-op if the `metrics` feature is disabled.
+//!
+//! Gauges: `purgeable_locked_bytes`, `purgeable_unlocked_bytes`, `purgeable_purged_bytes`.
+//! Counters: `purgeable_lock_successes_total`, `purgeable_lock_failures_total`.
+//! All metrics are labelled with `backend` and `label`: the box label if it has been passed
+//! to [set_exported_labels], `other` for other labels and an empty string for no label.
+
+use crate::Backend;
+#[cfg(feature = "metrics")]
+use std::collections::BTreeSet;
+#[cfg(feature = "metrics")]
+use std::sync::{Arc, RwLock};
+
+pub(crate) const LOCKED_BYTES: &str = "purgeable_locked_bytes";
+pub(crate) const UNLOCKED_BYTES: &str = "purgeable_unlocked_bytes";
+pub(crate) const PURGED_BYTES: &str = "purgeable_purged_bytes";
+
+#[cfg(feature = "metrics")]
+pub(crate) struct MetricLabels([metrics::Label; 2]);
+#[cfg(not(feature = "metrics"))]
+pub(crate) struct MetricLabels;
+
+/// Box labels are often derived from data, e.g. file names, so only the allowed ones are
+/// exported to keep the number of time series bounded. The strings are shared with the
+/// boxes, so creating the labels of a box doesn't allocate
+#[cfg(feature = "metrics")]
+static EXPORTED_LABELS: RwLock<BTreeSet<Arc<str>>> = RwLock::new(BTreeSet::new());
+
+/// Sets the box labels that are exported as the `label` of the `purgeable_*` metrics; the
+/// metrics of boxes with other labels are labelled `other`. Applies to boxes allocated
+/// afterwards.
+///
+/// # Examples
+///
+/// ```
+/// purgeable::set_exported_labels(["thumbnails", "decoded_images"]);
+/// ```
+#[cfg(feature = "metrics")]
+pub fn set_exported_labels<S: AsRef<str>>(labels: impl IntoIterator<Item = S>) {
+    let labels = labels
+        .into_iter()
+        .map(|it| Arc::from(it.as_ref()))
+        .collect();
+    *EXPORTED_LABELS.write().unwrap_or_else(|e| e.into_inner()) = labels;
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn labels(backend: Backend, label: Option<&str>) -> MetricLabels {
+    let label = match label {
+        None => metrics::Label::from_static_parts("label", ""),
+        Some(label) => match EXPORTED_LABELS
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(label)
+        {
+            Some(label) => metrics::Label::new("label", label.clone()),
+            None => metrics::Label::from_static_parts("label", "other"),
+        },
+    };
+    MetricLabels([
+        metrics::Label::from_static_parts("backend", backend.name()),
+        label,
+    ])
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(crate) fn labels(_backend: Backend, _label: Option<&str>) -> MetricLabels {
+    MetricLabels
+}
+
+#[inline(always)]
+#[allow(unused_variables)]
+pub(crate) fn add_bytes(labels: &MetricLabels, gauge: &'static str, size: usize) {
+    #[cfg(feature = "metrics")]
+    metrics::gauge!(gauge, labels.0.iter()).increment(size as f64);
+}
+
+#[inline(always)]
+#[allow(unused_variables)]
+pub(crate) fn sub_bytes(labels: &MetricLabels, gauge: &'static str, size: usize) {
+    #[cfg(feature = "metrics")]
+    metrics::gauge!(gauge, labels.0.iter()).decrement(size as f64);
+}
+
+#[inline(always)]
+#[allow(unused_variables)]
+pub(crate) fn locked(labels: &MetricLabels, success: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let counter = if success {
+            "purgeable_lock_successes_total"
+        } else {
+            "purgeable_lock_failures_total"
+        };
+        metrics::counter!(counter, labels.0.iter()).increment(1);
+    }
+}
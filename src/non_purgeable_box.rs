@@ -1,6 +1,7 @@
+use crate::checksum::ByteContent;
 use crate::error::PurgeableBoxLockError;
 use crate::unsafe_purgeable_box::UnsafePurgeableBox;
-use crate::{PurgeableAllocError, PurgeableBox};
+use crate::{Backend, PurgePriority, PurgeableAllocError, PurgeableBox};
 use std::borrow::{Borrow, BorrowMut};
 use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
@@ -34,8 +35,21 @@ impl<T: Copy> NonPurgeableBox<T> {
         handle_alloc_result(Self::try_new_uninit())
     }
 
+    /// Like [NonPurgeableBox::new], but the memory region is named with the `label`, if any.
+    /// See [NonPurgeableBox::label]
+    pub fn new_with_label(x: &T, label: Option<&str>) -> NonPurgeableBox<T> {
+        handle_alloc_result(Self::try_new_with_label(x, label))
+    }
+
     pub fn try_new(x: &T) -> Result<NonPurgeableBox<T>, PurgeableAllocError> {
-        let mut npb = Self::try_new_uninit()?;
+        Self::try_new_with_label(x, None)
+    }
+
+    pub fn try_new_with_label(
+        x: &T,
+        label: Option<&str>,
+    ) -> Result<NonPurgeableBox<T>, PurgeableAllocError> {
+        let mut npb = Self::try_new_uninit_with_label(label)?;
 
         // SAFETY: `npb.as_mut_ptr()` points to an allocated memory for size of `T`,
         //  `x` points to a `T` reference, `T` is `Copy`
@@ -48,7 +62,13 @@ impl<T: Copy> NonPurgeableBox<T> {
     }
 
     pub fn try_new_uninit() -> Result<NonPurgeableBox<MaybeUninit<T>>, PurgeableAllocError> {
-        let locked_inner = UnsafePurgeableBox::try_new_locked_uninit()?;
+        Self::try_new_uninit_with_label(None)
+    }
+
+    fn try_new_uninit_with_label(
+        label: Option<&str>,
+    ) -> Result<NonPurgeableBox<MaybeUninit<T>>, PurgeableAllocError> {
+        let locked_inner = UnsafePurgeableBox::try_new_locked_uninit(label)?;
         // SAFETY: `try_new_locked_uninit` guarantees that `locked_inner` is in the `LOCKED` state
         let npb = unsafe { NonPurgeableBox::from_locked_inner(locked_inner) };
         Ok(npb)
@@ -57,7 +77,37 @@ impl<T: Copy> NonPurgeableBox<T> {
 
 impl<T: Copy> NonPurgeableBox<[T]> {
     pub fn new_filled_slice(x: T, len: usize) -> NonPurgeableBox<[T]> {
-        let mut npb = Self::new_uninit_slice(len);
+        Self::fill_uninit_slice(Self::new_uninit_slice(len), x)
+    }
+
+    /// Like [NonPurgeableBox::new_filled_slice], but the memory region is named with the `label`, if any.
+    /// See [NonPurgeableBox::label]
+    pub fn new_filled_slice_with_label(
+        x: T,
+        len: usize,
+        label: Option<&str>,
+    ) -> NonPurgeableBox<[T]> {
+        let npb = handle_alloc_result(Self::try_new_uninit_slice_with_label(len, label));
+        Self::fill_uninit_slice(npb, x)
+    }
+
+    pub fn try_new_filled_slice(
+        x: T,
+        len: usize,
+    ) -> Result<NonPurgeableBox<[T]>, PurgeableAllocError> {
+        Ok(Self::fill_uninit_slice(Self::try_new_uninit_slice(len)?, x))
+    }
+
+    pub fn try_new_filled_slice_with_label(
+        x: T,
+        len: usize,
+        label: Option<&str>,
+    ) -> Result<NonPurgeableBox<[T]>, PurgeableAllocError> {
+        let npb = Self::try_new_uninit_slice_with_label(len, label)?;
+        Ok(Self::fill_uninit_slice(npb, x))
+    }
+
+    fn fill_uninit_slice(mut npb: NonPurgeableBox<[MaybeUninit<T>]>, x: T) -> NonPurgeableBox<[T]> {
         npb.fill(MaybeUninit::new(x));
         // SAFETY: `npb` is fully init because we just filled it with initialized values
         unsafe { npb.assume_init() }
@@ -70,7 +120,16 @@ impl<T: Copy> NonPurgeableBox<[T]> {
     pub fn try_new_uninit_slice(
         len: usize,
     ) -> Result<NonPurgeableBox<[MaybeUninit<T>]>, PurgeableAllocError> {
-        let locked_inner = UnsafePurgeableBox::<[T]>::try_new_locked_uninit_slice(len)?;
+        Self::try_new_uninit_slice_with_label(len, None)
+    }
+
+    /// Like [NonPurgeableBox::try_new_uninit_slice], but the memory region is named with the `label`, if any.
+    /// See [NonPurgeableBox::label]
+    pub fn try_new_uninit_slice_with_label(
+        len: usize,
+        label: Option<&str>,
+    ) -> Result<NonPurgeableBox<[MaybeUninit<T>]>, PurgeableAllocError> {
+        let locked_inner = UnsafePurgeableBox::<[T]>::try_new_locked_uninit_slice(len, label)?;
         // SAFETY:
         // `try_new_locked_uninit_slice` guarantees that `locked_inner` is in the `LOCKED` state
         let npb = unsafe { NonPurgeableBox::from_locked_inner(locked_inner) };
@@ -81,8 +140,21 @@ impl<T: Copy> NonPurgeableBox<[T]> {
         handle_alloc_result(Self::try_new_slice(src))
     }
 
+    /// Like [NonPurgeableBox::new_slice], but the memory region is named with the `label`, if any.
+    /// See [NonPurgeableBox::label]
+    pub fn new_slice_with_label(src: &[T], label: Option<&str>) -> NonPurgeableBox<[T]> {
+        handle_alloc_result(Self::try_new_slice_with_label(src, label))
+    }
+
     pub fn try_new_slice(src: &[T]) -> Result<NonPurgeableBox<[T]>, PurgeableAllocError> {
-        let mut pb = Self::try_new_uninit_slice(src.len())?;
+        Self::try_new_slice_with_label(src, None)
+    }
+
+    pub fn try_new_slice_with_label(
+        src: &[T],
+        label: Option<&str>,
+    ) -> Result<NonPurgeableBox<[T]>, PurgeableAllocError> {
+        let mut pb = Self::try_new_uninit_slice_with_label(src.len(), label)?;
 
         // SAFETY: &[T] and &[MaybeUninit<T>] have the same layout
         let uninit_src: &[MaybeUninit<T>] = unsafe { std::mem::transmute(src) };
@@ -95,6 +167,33 @@ impl<T: Copy> NonPurgeableBox<[T]> {
     }
 }
 
+impl NonPurgeableBox<str> {
+    pub fn new_str(src: &str) -> NonPurgeableBox<str> {
+        handle_alloc_result(Self::try_new_str(src))
+    }
+
+    /// Like [NonPurgeableBox::new_str], but the memory region is named with the `label`, if any.
+    /// See [NonPurgeableBox::label]
+    pub fn new_str_with_label(src: &str, label: Option<&str>) -> NonPurgeableBox<str> {
+        handle_alloc_result(Self::try_new_str_with_label(src, label))
+    }
+
+    pub fn try_new_str(src: &str) -> Result<NonPurgeableBox<str>, PurgeableAllocError> {
+        Self::try_new_str_with_label(src, None)
+    }
+
+    pub fn try_new_str_with_label(
+        src: &str,
+        label: Option<&str>,
+    ) -> Result<NonPurgeableBox<str>, PurgeableAllocError> {
+        let npb = NonPurgeableBox::try_new_slice_with_label(src.as_bytes(), label)?;
+        // SAFETY: the bytes have been copied from a `str`
+        Ok(NonPurgeableBox {
+            inner: unsafe { npb.inner.assume_utf8() },
+        })
+    }
+}
+
 impl<T: ?Sized> NonPurgeableBox<T> {
     /// Safety: `pb` must be in the `LOCKED` state
     unsafe fn from_locked_inner(inner: UnsafePurgeableBox<T>) -> Self {
@@ -115,20 +214,177 @@ impl<T: ?Sized> NonPurgeableBox<T> {
         }
     }
 
+    /// An arbitrary user-defined value that is reported in [PurgeEvent](crate::PurgeEvent)s.
+    /// `0` by default
+    pub fn tag(this: &Self) -> u64 {
+        this.inner.tag()
+    }
+
+    pub fn set_tag(this: &mut Self, tag: u64) {
+        this.inner.set_tag(tag)
+    }
+
+    /// The label the box has been created with, e.g. with [NonPurgeableBox::new_with_label].
+    ///
+    /// On Linux the memory region is named `purgeable:<label>` (`purgeable` if there is no
+    /// label), so it can be found in `/proc/<pid>/maps` and `/proc/<pid>/smaps`.
+    /// Other platforms don't support naming memory regions.
+    pub fn label(this: &Self) -> Option<&str> {
+        this.inner.label()
+    }
+
+    /// The OS mechanism backing the box
+    pub fn backend(this: &Self) -> Backend {
+        this.inner.backend()
+    }
+
+    /// Returns how many pages of the box are present in physical memory.
+    /// Pages that have never been touched are usually not resident.
+    ///
+    /// Implemented with `mincore`; its errors are returned as is
+    #[cfg(unix)]
+    pub fn resident_pages(this: &Self) -> std::io::Result<usize> {
+        this.inner.resident_pages()
+    }
+
+    /// See [NonPurgeableBox::resident_pages]
+    #[cfg(unix)]
+    pub fn resident_bytes(this: &Self) -> std::io::Result<usize> {
+        this.inner.resident_bytes()
+    }
+
+    pub fn is_integrity_verified(this: &Self) -> bool {
+        this.inner.is_integrity_verified()
+    }
+
+    /// See [ForkPolicy](crate::ForkPolicy)
+    #[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+    pub fn fork_policy(this: &Self) -> crate::ForkPolicy {
+        this.inner.fork_policy()
+    }
+
+    /// Changes what a forked child process gets of the box, see [ForkPolicy](crate::ForkPolicy).
+    ///
+    /// Fails with [io::ErrorKind::Unsupported](std::io::ErrorKind::Unsupported) if the box is
+    /// backed by `ashmem` and the policy requires a private copy, and with the `madvise` error
+    /// if the kernel doesn't support the policy
+    #[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+    pub fn set_fork_policy(this: &mut Self, policy: crate::ForkPolicy) -> std::io::Result<()> {
+        this.inner.set_fork_policy(policy)
+    }
+
     pub fn unlock(this: Self) -> PurgeableBox<T> {
+        Self::unlock_with_priority(this, PurgePriority::DEFAULT)
+    }
+
+    /// Like [NonPurgeableBox::unlock], but allows to specify the order in which the box
+    /// is purged relative to other unlocked boxes. See [PurgePriority]
+    pub fn unlock_with_priority(this: Self, priority: PurgePriority) -> PurgeableBox<T> {
         let mut pb = this.inner;
         // SAFETY: `NonPurgeableBox` guarantees that `pb` is in the `LOCKED` state;
         //  then we're turning it into `UNLOCKED` state by calling `unlock`;
         //  then we're passing it to `PurgeableBox::from_unlocked` which requires `UNLOCKED` state.
         unsafe {
-            pb.unlock();
+            pb.unlock(priority);
             PurgeableBox::from_unlocked(pb)
         }
     }
 }
 
+impl<T: ByteContent + ?Sized> NonPurgeableBox<T> {
+    /// Enables an integrity verification of the box content.
+    ///
+    /// When enabled, a checksum of the content is recorded on every unlock, and
+    /// [PurgeableBox::lock] returns [PurgeableBoxLockError] if the content doesn't match it.
+    /// This catches partial purges that the backend reports as successful locks at the cost
+    /// of reading the whole box on every lock and unlock. Disabled by default.
+    ///
+    /// Only byte content (`[u8]`, `[u8; N]` and `str`) can be verified: the checksum reads
+    /// every byte, and other types may have uninitialized padding
+    pub fn set_integrity_verified(this: &mut Self, enabled: bool) {
+        this.inner.set_integrity_verified(enabled)
+    }
+}
+
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+impl NonPurgeableBox<[u8]> {
+    /// A new file descriptor of the memory region, e.g. to pass it to another process.
+    /// See [SharedHandle](crate::SharedHandle)
+    ///
+    /// Fails with [io::ErrorKind::Unsupported](std::io::ErrorKind::Unsupported) unless the
+    /// box is backed by `ashmem` or `memfd`; `MADV_FREE` memory is private to the process,
+    /// see [NonPurgeableBox::into_shared_handle] to share it.
+    pub fn export_fd(this: &Self) -> std::io::Result<std::os::fd::OwnedFd> {
+        match this.inner.shared_fd() {
+            Some(fd) => fd.try_clone_to_owned(),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "only ashmem and memfd regions can be shared",
+            )),
+        }
+    }
+
+    /// Converts the box into a handle that other processes can map with
+    /// [NonPurgeableBox::from_shared_handle]. The region stays locked.
+    ///
+    /// `ashmem` and `memfd` regions are shared as is; the content of other boxes is copied
+    /// into a new `ashmem` region, or a `memfd` region if `ashmem` is not available, with
+    /// the same label. Fails with [io::ErrorKind::InvalidInput](std::io::ErrorKind::InvalidInput)
+    /// if the box is empty
+    pub fn into_shared_handle(this: Self) -> std::io::Result<crate::SharedHandle> {
+        let fd = match this.inner.shared_fd() {
+            Some(fd) => fd.try_clone_to_owned()?,
+            None => UnsafePurgeableBox::new_shared_fd(&this, this.inner.label())?,
+        };
+        // SAFETY: the box is consumed, so this process doesn't access the region anymore
+        Ok(unsafe { crate::SharedHandle::new(fd, this.len()) })
+    }
+
+    /// Maps a region shared by another process and locks it.
+    ///
+    /// The box gets the label of the region and the global [fork policy](crate::fork_policy).
+    /// Fails with [io::ErrorKind::InvalidInput](std::io::ErrorKind::InvalidInput) if the
+    /// handle is not an `ashmem` or sealed `memfd` region of at least its length, with
+    /// [io::ErrorKind::Unsupported](std::io::ErrorKind::Unsupported) if the fork policy
+    /// requires a private mapping, and with [PurgeableBoxLockError] if the region has been
+    /// purged
+    ///
+    /// # Safety
+    ///
+    /// The region is mapped shared, so the box aliases every other mapping of it, in this or
+    /// other processes, and they all share one pin state. Until the returned box, and the
+    /// boxes it is unlocked and locked into, is dropped, the caller must guarantee that:
+    /// - the region is not written through another mapping while this box is borrowed, and
+    ///   this box is not written while another mapping of the region is read;
+    /// - another mapping doesn't unlock the region while this box is locked, and this box
+    ///   isn't unlocked while another mapping is locked and borrowed: unlocking unpins the
+    ///   region for every process, so the kernel may purge pages that are being read.
+    pub unsafe fn from_shared_handle(
+        handle: crate::SharedHandle,
+    ) -> std::io::Result<NonPurgeableBox<[u8]>> {
+        let (fd, len) = handle.into_parts();
+        match UnsafePurgeableBox::try_new_locked_from_shared_fd(fd, len)? {
+            // SAFETY: the box is returned in the `LOCKED` state
+            Some(inner) => Ok(unsafe { Self::from_locked_inner(inner) }),
+            None => Err(std::io::Error::other(PurgeableBoxLockError)),
+        }
+    }
+}
+
+#[cfg(any(feature = "bytemuck", feature = "zerocopy"))]
+impl<A> NonPurgeableBox<[A]> {
+    /// Safety: see [UnsafePurgeableBox::cast_slice]
+    pub(crate) unsafe fn cast_slice<B>(self) -> NonPurgeableBox<[B]> {
+        NonPurgeableBox::from_locked_inner(self.inner.cast_slice())
+    }
+}
+
 impl<T: Copy> NonPurgeableBox<[MaybeUninit<T>]> {
     /// See docs for [MaybeUninit::assume_init]
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that every element of the slice is initialized.
     #[inline(always)]
     pub unsafe fn assume_init(self) -> NonPurgeableBox<[T]> {
         // SAFETY: `NonPurgeableBox` guarantees that `self.inner` is in the `LOCKED` state
@@ -139,7 +395,7 @@ impl<T: Copy> NonPurgeableBox<[MaybeUninit<T>]> {
 impl<T> NonPurgeableBox<MaybeUninit<T>> {
     /// See docs for [MaybeUninit::assume_init]
     #[inline(always)]
-    unsafe fn assume_init(self) -> NonPurgeableBox<T> {
+    pub(crate) unsafe fn assume_init(self) -> NonPurgeableBox<T> {
         NonPurgeableBox {
             inner: self.inner.assume_init(),
         }
@@ -192,7 +448,7 @@ impl<T: ?Sized> ops::DerefMut for NonPurgeableBox<T> {
 
 impl<T: ?Sized> AsRef<T> for NonPurgeableBox<T> {
     fn as_ref(&self) -> &T {
-        &*self
+        self
     }
 }
 
@@ -204,7 +460,7 @@ impl<T: ?Sized> AsMut<T> for NonPurgeableBox<T> {
 
 impl<T: ?Sized> Borrow<T> for NonPurgeableBox<T> {
     fn borrow(&self) -> &T {
-        &*self
+        self
     }
 }
 
@@ -219,10 +475,6 @@ impl<T: ?Sized + PartialEq> PartialEq for NonPurgeableBox<T> {
     fn eq(&self, other: &Self) -> bool {
         PartialEq::eq(&**self, &**other)
     }
-    #[inline]
-    fn ne(&self, other: &Self) -> bool {
-        PartialEq::ne(&**self, &**other)
-    }
 }
 
 impl<T: ?Sized + PartialOrd> PartialOrd for NonPurgeableBox<T> {
@@ -258,7 +510,7 @@ impl<T: ?Sized + Hash> Hash for NonPurgeableBox<T> {
     }
 }
 
-fn handle_alloc_result<T: ?Sized>(
+pub(crate) fn handle_alloc_result<T: ?Sized>(
     result: Result<NonPurgeableBox<T>, PurgeableAllocError>,
 ) -> NonPurgeableBox<T> {
     match result {
@@ -283,7 +535,9 @@ impl<T: ?Sized> TryFrom<PurgeableBox<T>> for NonPurgeableBox<T> {
 #[cfg(feature = "serde")]
 mod serde_impls {
     use crate::NonPurgeableBox;
+    use serde::de::{self, Visitor};
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::fmt;
 
     impl<T: Serialize + ?Sized> Serialize for NonPurgeableBox<T> {
         #[inline]
@@ -309,6 +563,100 @@ mod serde_impls {
             Deserialize::deserialize(deserializer).map(|v: Vec<T>| Self::new_slice(&v))
         }
     }
+
+    impl<'de> Deserialize<'de> for NonPurgeableBox<str> {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            struct StrVisitor;
+
+            impl Visitor<'_> for StrVisitor {
+                type Value = NonPurgeableBox<str>;
+
+                fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    f.write_str("a string")
+                }
+
+                fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                    Ok(NonPurgeableBox::new_str(v))
+                }
+
+                fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                    match std::str::from_utf8(v) {
+                        Ok(s) => Ok(NonPurgeableBox::new_str(s)),
+                        Err(_) => Err(E::invalid_value(de::Unexpected::Bytes(v), &self)),
+                    }
+                }
+            }
+
+            deserializer.deserialize_str(StrVisitor)
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+mod rayon_impls {
+    use crate::non_purgeable_box::handle_alloc_result;
+    use crate::{NonPurgeableBox, PurgeableAllocError};
+    use rayon::prelude::*;
+    use std::mem::{self, MaybeUninit};
+
+    /// The number of elements each task handles, so every thread faults in its own pages
+    fn chunk_len<T>() -> usize {
+        (page_size::get() * 16 / mem::size_of::<T>().max(1)).max(1)
+    }
+
+    impl<T: Copy + Send> NonPurgeableBox<[T]> {
+        /// Allocates a slice of `len` elements, initializing them with `f(index)` on the rayon
+        /// thread pool. Much faster than filling a huge box from one thread, which is bound by
+        /// page faults
+        pub fn par_new_from_fn(len: usize, f: impl Fn(usize) -> T + Sync) -> NonPurgeableBox<[T]> {
+            handle_alloc_result(Self::try_par_new_from_fn(len, f))
+        }
+
+        pub fn try_par_new_from_fn(
+            len: usize,
+            f: impl Fn(usize) -> T + Sync,
+        ) -> Result<NonPurgeableBox<[T]>, PurgeableAllocError> {
+            let mut npb = Self::try_new_uninit_slice(len)?;
+            let chunk_len = chunk_len::<T>();
+            npb.par_chunks_mut(chunk_len)
+                .enumerate()
+                .for_each(|(i, chunk)| {
+                    for (j, x) in chunk.iter_mut().enumerate() {
+                        *x = MaybeUninit::new(f(i * chunk_len + j));
+                    }
+                });
+            // SAFETY: every element has been initialized
+            Ok(unsafe { npb.assume_init() })
+        }
+    }
+
+    impl<T: Copy + Send + Sync> NonPurgeableBox<[T]> {
+        /// Like [slice::fill], but on the rayon thread pool
+        pub fn par_fill(this: &mut Self, x: T) {
+            this.par_chunks_mut(chunk_len::<T>())
+                .for_each(|chunk| chunk.fill(x));
+        }
+
+        /// Like [slice::copy_from_slice], but on the rayon thread pool
+        ///
+        /// # Panics
+        ///
+        /// Panics if the lengths differ
+        pub fn par_copy_from_slice(this: &mut Self, src: &[T]) {
+            assert_eq!(
+                this.len(),
+                src.len(),
+                "source slice length does not match the box length"
+            );
+            let chunk_len = chunk_len::<T>();
+            this.par_chunks_mut(chunk_len)
+                .zip(src.par_chunks(chunk_len))
+                .for_each(|(dst, src)| dst.copy_from_slice(src));
+        }
+    }
 }
 
 #[cfg(feature = "stable_deref_trait")]
@@ -1,22 +1,119 @@
-#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[cfg(all(any(target_os = "macos", target_os = "ios"), not(purgeable_sim)))]
 mod mach;
 
-#[cfg(any(target_os = "macos", target_os = "ios"))]
-pub(crate) use mach::{is_available, SystemPurgeableBox};
+#[cfg(all(
+    any(target_os = "macos", target_os = "ios"),
+    not(purgeable_sim),
+    feature = "testing"
+))]
+pub(crate) use mach::available_backends;
+#[cfg(all(any(target_os = "macos", target_os = "ios"), not(purgeable_sim)))]
+pub(crate) use mach::{is_available, PurgeProbe, SystemPurgeableBox};
 use std::alloc::Layout;
+use std::fmt;
 
-#[cfg(windows)]
+#[cfg(all(windows, not(purgeable_sim)))]
 mod windows;
-#[cfg(windows)]
-pub(crate) use windows::{is_available, SystemPurgeableBox};
+#[cfg(all(windows, not(purgeable_sim), feature = "testing"))]
+pub(crate) use windows::available_backends;
+#[cfg(all(windows, not(purgeable_sim)))]
+pub(crate) use windows::{is_available, PurgeProbe, SystemPurgeableBox};
 
-#[cfg(any(target_os = "linux", target_os = "android"))]
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
 mod linux;
-#[cfg(any(target_os = "linux", target_os = "android"))]
-pub(crate) use linux::{is_available, SystemPurgeableBox};
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    not(purgeable_sim),
+    feature = "testing"
+))]
+pub(crate) use linux::available_backends;
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+pub(crate) use linux::{is_available, PurgeProbe, SystemPurgeableBox};
+
+#[cfg(purgeable_sim)]
+pub(crate) mod sim;
+#[cfg(all(purgeable_sim, feature = "testing"))]
+pub(crate) use sim::available_backends;
+#[cfg(purgeable_sim)]
+pub(crate) use sim::{is_available, PurgeProbe, SystemPurgeableBox};
 
 mod impls;
 
+/// The OS mechanism used to implement purgeable memory
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Backend {
+    /// macOS/iOS `vm_allocate` + `vm_purgable_control`
+    Mach,
+    /// Linux/Android `ashmem` pin/unpin
+    Ashmem,
+    /// Linux anonymous memory released with `MADV_FREE` (Linux 4.5+); used if `ashmem` is not
+    /// available or the [fork policy](crate::ForkPolicy) requires a private mapping
+    MadvFree,
+    /// Linux `memfd` shared memory; used for boxes shared with other processes with
+    /// [SharedHandle](crate::SharedHandle) if `ashmem` is not available. The kernel never
+    /// purges it, so unlocked boxes are only swapped out like other shared memory
+    Memfd,
+    /// Windows `VirtualAlloc` + `MEM_RESET`/`MEM_RESET_UNDO`
+    Windows,
+    /// Heap memory with simulated purging, enabled with `--cfg purgeable_sim`
+    Sim,
+}
+
+impl Backend {
+    pub(crate) const ALL: [Backend; 6] = [
+        Backend::Mach,
+        Backend::Ashmem,
+        Backend::MadvFree,
+        Backend::Memfd,
+        Backend::Windows,
+        Backend::Sim,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Mach => "mach",
+            Backend::Ashmem => "ashmem",
+            Backend::MadvFree => "madv_free",
+            Backend::Memfd => "memfd",
+            Backend::Windows => "windows",
+            Backend::Sim => "sim",
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(feature = "testing")]
+thread_local! {
+    static FORCED_BACKEND: std::cell::Cell<Option<Backend>> = const { std::cell::Cell::new(None) };
+}
+
+/// The backend new boxes must use on the current thread, see `testing::with_backend`.
+/// Only Linux chooses between several backends
+#[cfg(feature = "testing")]
+#[allow(dead_code)]
+pub(crate) fn forced_backend() -> Option<Backend> {
+    FORCED_BACKEND.with(|it| it.get())
+}
+
+#[cfg(not(feature = "testing"))]
+#[inline(always)]
+#[allow(dead_code)]
+pub(crate) fn forced_backend() -> Option<Backend> {
+    None
+}
+
+/// Returns the previously forced backend
+#[cfg(feature = "testing")]
+pub(crate) fn set_forced_backend(backend: Option<Backend>) -> Option<Backend> {
+    FORCED_BACKEND.with(|it| it.replace(backend))
+}
+
 fn check_alignment(layout: Layout) {
     if layout.align() > page_size::get() {
         panic!(
@@ -5,16 +5,31 @@ use std::mem::MaybeUninit;
 use std::{mem, ptr};
 
 impl<T: Copy> SystemPurgeableBox<T> {
-    pub(crate) fn new_uninit() -> Result<SystemPurgeableBox<MaybeUninit<T>>, PurgeableAllocError> {
-        SystemPurgeableBox::<[u8]>::new_uninit_with_layout(Layout::new::<T>())
+    pub(crate) fn new_uninit(
+        label: Option<&str>,
+    ) -> Result<SystemPurgeableBox<MaybeUninit<T>>, PurgeableAllocError> {
+        SystemPurgeableBox::<[u8]>::new_uninit_with_layout(Layout::new::<T>(), label)
             .map(|b| unsafe { b.cast() })
     }
 }
 
+impl<T> SystemPurgeableBox<T> {
+    /// A box that owns no memory whatever the size of `T` is, so its content must never be
+    /// accessed
+    #[cfg(feature = "serde")]
+    pub(crate) fn new_empty() -> SystemPurgeableBox<T> {
+        let layout = Layout::from_size_align(0, mem::align_of::<T>()).unwrap();
+        let empty = SystemPurgeableBox::<[u8]>::new_uninit_with_layout(layout, None)
+            .expect("empty boxes don't allocate");
+        // SAFETY: the pointer is dangling, but it is aligned for `T` and never dereferenced
+        unsafe { empty.cast() }
+    }
+}
+
 impl<T: ?Sized> SystemPurgeableBox<T> {
     #[inline]
     pub(crate) unsafe fn cast<R>(self) -> SystemPurgeableBox<R> {
-        return self.map_ptr(|ptr| ptr.cast());
+        self.map_ptr(|ptr| ptr.cast())
     }
 
     pub(crate) fn size(&self) -> usize {
@@ -23,6 +38,41 @@ impl<T: ?Sized> SystemPurgeableBox<T> {
     }
 }
 
+#[cfg(all(unix, not(purgeable_sim)))]
+impl<T: ?Sized> SystemPurgeableBox<T> {
+    pub(crate) fn resident_pages(&self) -> std::io::Result<usize> {
+        Ok(self
+            .page_residency()?
+            .iter()
+            .filter(|&&resident| resident)
+            .count())
+    }
+
+    pub(crate) fn resident_bytes(&self) -> std::io::Result<usize> {
+        let page_size = page_size::get();
+        Ok(self
+            .page_residency()?
+            .iter()
+            .enumerate()
+            .filter(|(_, &resident)| resident)
+            .map(|(i, _)| page_size.min(self.size - i * page_size))
+            .sum())
+    }
+
+    /// Queries which pages of the box are resident in physical memory using `mincore`
+    fn page_residency(&self) -> std::io::Result<Vec<bool>> {
+        if self.size == 0 {
+            return Ok(Vec::new());
+        }
+        let mut vec = vec![0; self.size.div_ceil(page_size::get())];
+        let ret = unsafe { libc::mincore(self.ptr() as *mut _, self.size, vec.as_mut_ptr()) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(vec.iter().map(|&it| it & 1 != 0).collect())
+    }
+}
+
 impl<T> SystemPurgeableBox<MaybeUninit<T>> {
     #[inline]
     pub(crate) unsafe fn assume_init(self) -> SystemPurgeableBox<T> {
@@ -33,16 +83,42 @@ impl<T> SystemPurgeableBox<MaybeUninit<T>> {
 impl<T: Copy> SystemPurgeableBox<[mem::MaybeUninit<T>]> {
     #[inline]
     pub(crate) unsafe fn assume_init(self) -> SystemPurgeableBox<[T]> {
-        return self.map_ptr(|ptr| ptr::NonNull::new_unchecked(ptr.as_ptr() as *mut [T]));
+        self.map_ptr(|ptr| ptr::NonNull::new_unchecked(ptr.as_ptr() as *mut [T]))
+    }
+}
+
+#[cfg(any(feature = "bytemuck", feature = "zerocopy"))]
+impl<A> SystemPurgeableBox<[A]> {
+    /// The caller must guarantee that the memory is aligned for `B`, that its size is
+    /// a multiple of the size of `B` and that any content of `[A]` is a valid `[B]`
+    pub(crate) unsafe fn cast_slice<B>(self) -> SystemPurgeableBox<[B]> {
+        let len = self.size.checked_div(mem::size_of::<B>()).unwrap_or(0);
+        self.map_ptr(|ptr| {
+            // Empty boxes have a dangling pointer that is aligned only for `A`
+            let data = if len == 0 {
+                ptr::NonNull::<B>::dangling().as_ptr()
+            } else {
+                ptr.as_ptr().cast()
+            };
+            ptr::NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(data, len))
+        })
+    }
+}
+
+impl SystemPurgeableBox<[u8]> {
+    #[inline]
+    pub(crate) unsafe fn assume_utf8(self) -> SystemPurgeableBox<str> {
+        self.map_ptr(|ptr| ptr::NonNull::new_unchecked(ptr.as_ptr() as *mut str))
     }
 }
 
 impl<T: Copy> SystemPurgeableBox<[T]> {
     pub(crate) fn new_uninit_slice(
         len: usize,
+        label: Option<&str>,
     ) -> Result<SystemPurgeableBox<[mem::MaybeUninit<T>]>, PurgeableAllocError> {
         let layout = Layout::array::<T>(len).unwrap();
-        SystemPurgeableBox::<[u8]>::new_uninit_with_layout(layout).map(|b| unsafe {
+        SystemPurgeableBox::<[u8]>::new_uninit_with_layout(layout, label).map(|b| unsafe {
             b.map_ptr(|ptr| {
                 ptr::NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(ptr.as_ptr().cast(), len))
             })
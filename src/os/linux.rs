@@ -1,4 +1,376 @@
-pub(crate) mod ashmem;
+use crate::{Backend, ForkPolicy, PurgePriority, PurgeableAllocError};
+use std::alloc::Layout;
+use std::ffi::{CStr, CString};
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::ptr::NonNull;
+use std::{io, ptr};
 
-pub(crate) use ashmem::is_available;
-pub(crate) use ashmem::SystemPurgeableBox;
+mod ashmem;
+mod madv_free;
+mod memfd;
+
+/// `ashmem` is used if it is available (Android and older kernels); otherwise, anonymous
+/// memory released with `MADV_FREE` is used. Boxes shared with other processes are backed
+/// by `memfd` regions where `ashmem` is not available
+pub(crate) struct SystemPurgeableBox<T: ?Sized> {
+    ptr: NonNull<T>,
+    region: Region,
+    pub(crate) size: usize, // Remove it after `size_of_val_raw` stabilization
+    fork_policy: ForkPolicy,
+}
+
+enum Region {
+    /// A zero-sized allocation; holds the backend a non-empty box would have used
+    Empty(Backend),
+    Ashmem(ashmem::AshmemRegion),
+    MadvFree(madv_free::MadvFreeRegion),
+    Memfd(memfd::MemfdRegion),
+}
+
+impl SystemPurgeableBox<[u8]> {
+    pub(crate) fn new_uninit_with_layout(
+        layout: Layout,
+        label: Option<&str>,
+    ) -> Result<SystemPurgeableBox<[u8]>, PurgeableAllocError> {
+        super::check_alignment(layout);
+        let fork_policy = crate::fork_policy();
+        let backend = new_box_backend(fork_policy);
+        if layout.size() == 0 {
+            return Ok(SystemPurgeableBox {
+                ptr: unsafe {
+                    NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(
+                        layout.align() as *mut u8,
+                        0,
+                    ))
+                },
+                region: Region::Empty(backend),
+                size: 0,
+                fork_policy,
+            });
+        }
+
+        let mut spb = Self::new_region(layout.size(), backend, &region_name(label))
+            .ok_or_else(|| PurgeableAllocError::new(layout))?;
+        spb.set_fork_policy(fork_policy)
+            .map_err(|e| PurgeableAllocError::with_cause(layout, e))?;
+        Ok(spb)
+    }
+
+    /// Maps a new non-empty region of `backend` with the [ForkPolicy::Inherit] policy
+    fn new_region(size: usize, backend: Backend, name: &CStr) -> Option<SystemPurgeableBox<[u8]>> {
+        let (address, region) = match backend {
+            Backend::Ashmem => ashmem::AshmemRegion::new(size, name)
+                .map(|(address, region)| (address, Region::Ashmem(region))),
+            Backend::Memfd => memfd::MemfdRegion::new(size, name)
+                .map(|(address, region)| (address, Region::Memfd(region))),
+            _ => madv_free::MadvFreeRegion::new(size, name)
+                .map(|(address, region)| (address, Region::MadvFree(region))),
+        }?;
+        Some(SystemPurgeableBox {
+            ptr: unsafe { NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(address, size)) },
+            region,
+            size,
+            fork_policy: ForkPolicy::Inherit,
+        })
+    }
+
+    /// Copies `content` into a new region that other processes can map and returns its file
+    /// descriptor: `ashmem` if it is available, `memfd` if not. The region is named like the
+    /// regions of boxes with `label`
+    pub(crate) fn new_shared_fd(content: &[u8], label: Option<&str>) -> io::Result<OwnedFd> {
+        if content.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty regions can't be shared",
+            ));
+        }
+        let backend = if ashmem::is_available() {
+            Backend::Ashmem
+        } else {
+            Backend::Memfd
+        };
+        let spb = Self::new_region(content.len(), backend, &region_name(label))
+            .ok_or_else(io::Error::last_os_error)?;
+        unsafe {
+            ptr::copy_nonoverlapping(content.as_ptr(), spb.ptr() as *mut u8, content.len());
+        }
+        // The region stays pinned for the process that maps it
+        spb.shared_fd().unwrap().try_clone_to_owned()
+    }
+}
+
+impl SystemPurgeableBox<[u8]> {
+    /// Maps `size` bytes of a region exported with [SystemPurgeableBox::shared_fd] and pins it.
+    /// The mapping gets the global [fork policy](crate::fork_policy), so it fails with
+    /// [io::ErrorKind::Unsupported] if the policy requires a private mapping.
+    /// Returns `Ok(None)` if the region has been purged
+    pub(crate) fn from_shared_fd(
+        fd: OwnedFd,
+        size: usize,
+    ) -> io::Result<Option<SystemPurgeableBox<[u8]>>> {
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty regions can't be shared",
+            ));
+        }
+        let (address, region) = if ashmem::AshmemRegion::is_region(fd.as_fd()) {
+            let (address, region) = ashmem::AshmemRegion::from_fd(fd, size)?;
+            (address, Region::Ashmem(region))
+        } else {
+            let (address, region) = memfd::MemfdRegion::from_fd(fd, size)?;
+            (address, Region::Memfd(region))
+        };
+        let mut spb = SystemPurgeableBox {
+            ptr: unsafe { NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(address, size)) },
+            region,
+            size,
+            fork_policy: ForkPolicy::Inherit,
+        };
+        spb.set_fork_policy(crate::fork_policy())?;
+        Ok(spb.lock().then_some(spb))
+    }
+}
+
+impl<T: ?Sized> Drop for SystemPurgeableBox<T> {
+    fn drop(&mut self) {
+        if let Region::Empty(_) = self.region {
+            return;
+        }
+        // The region itself is released after unmapping
+        unsafe {
+            libc::munmap(self.ptr() as *mut _, self.size());
+        }
+    }
+}
+
+impl<T: ?Sized> SystemPurgeableBox<T> {
+    pub(crate) fn lock(&mut self) -> bool {
+        let address = self.ptr.cast::<u8>().as_ptr();
+        match &mut self.region {
+            Region::Empty(_) => true,
+            Region::Ashmem(region) => region.pin(),
+            Region::MadvFree(region) => unsafe { region.lock(address) },
+            Region::Memfd(_) => true,
+        }
+    }
+
+    /// Neither `ashmem` nor `MADV_FREE` support ordering, and `memfd` regions are never
+    /// purged, so `_priority` is ignored
+    pub(crate) unsafe fn unlock(&mut self, _priority: PurgePriority) {
+        let address = self.ptr.cast::<u8>().as_ptr();
+        match &mut self.region {
+            Region::Empty(_) => {}
+            Region::Ashmem(region) => region.unpin(),
+            Region::MadvFree(region) => region.unlock(address, self.size),
+            Region::Memfd(region) => region.unlock(address, self.size),
+        }
+    }
+
+    /// Returns `true` if the next `lock` is guaranteed to fail
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn force_purge(&mut self) -> bool {
+        let address = self.ptr.cast::<u8>().as_ptr();
+        match &mut self.region {
+            Region::Empty(_) | Region::Memfd(_) => false,
+            Region::Ashmem(region) => region.purge(),
+            Region::MadvFree(region) => region.purge(address, self.size),
+        }
+    }
+
+    /// Flips the bits of the last byte without the backend noticing, like a partial purge.
+    /// Returns `false` if the box is empty or `lock` would restore the byte
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn corrupt(&mut self) -> bool {
+        let offset = self.size.wrapping_sub(1);
+        let restored_on_lock = offset % page_size::get() < madv_free::SAVED_WORD_SIZE;
+        match &self.region {
+            Region::Empty(_) => return false,
+            Region::MadvFree(_) if restored_on_lock => return false,
+            _ => {}
+        }
+        let last = self.ptr.cast::<u8>().as_ptr().add(offset);
+        last.write(!last.read());
+        true
+    }
+
+    /// Allows checking the state of the unlocked box from another thread; only `MADV_FREE`
+    /// regions can be checked without pinning them
+    pub(crate) fn purge_probe(&self) -> Option<PurgeProbe> {
+        match self.region {
+            Region::MadvFree(_) => Some(PurgeProbe {
+                address: self.ptr.cast::<u8>().as_ptr(),
+                size: self.size,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The file descriptor of the region if it can be mapped by other processes; only
+    /// `ashmem` and `memfd` regions can
+    pub(crate) fn shared_fd(&self) -> Option<BorrowedFd<'_>> {
+        match &self.region {
+            Region::Ashmem(region) => Some(region.fd()),
+            Region::Memfd(region) => Some(region.fd()),
+            Region::Empty(_) | Region::MadvFree(_) => None,
+        }
+    }
+
+    /// The label of a shared region, recovered from its name, see [region_name]
+    pub(crate) fn shared_label(&self) -> Option<String> {
+        let name = match &self.region {
+            Region::Ashmem(region) => region.name(),
+            Region::Memfd(region) => region.name(),
+            Region::Empty(_) | Region::MadvFree(_) => None,
+        }?;
+        name.strip_prefix("purgeable:").map(str::to_owned)
+    }
+
+    pub(crate) fn fork_policy(&self) -> ForkPolicy {
+        self.fork_policy
+    }
+
+    /// Fails with [io::ErrorKind::Unsupported] if the policy requires a private mapping
+    /// and the region is shared
+    pub(crate) fn set_fork_policy(&mut self, policy: ForkPolicy) -> io::Result<()> {
+        let shared = match self.region {
+            Region::Empty(_) => {
+                self.fork_policy = policy;
+                return Ok(());
+            }
+            Region::Ashmem(_) | Region::Memfd(_) => true,
+            Region::MadvFree(_) => false,
+        };
+        if shared && !policy.allows_shared_mapping() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "ashmem and memfd regions are always shared with forked processes",
+            ));
+        }
+
+        // Only the flags that change are applied, so the default policy works on any kernel
+        let flags = [
+            (ForkPolicy::DontFork, libc::MADV_DONTFORK, libc::MADV_DOFORK),
+            (
+                ForkPolicy::WipeOnFork,
+                libc::MADV_WIPEONFORK,
+                libc::MADV_KEEPONFORK,
+            ),
+        ];
+        for (flag_policy, set, clear) in flags {
+            let (was_set, is_set) = (self.fork_policy == flag_policy, policy == flag_policy);
+            if was_set == is_set {
+                continue;
+            }
+            let advice = if is_set { set } else { clear };
+            let ret = unsafe { libc::madvise(self.ptr() as *mut _, self.size, advice) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        self.fork_policy = policy;
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn backend(&self) -> Backend {
+        match self.region {
+            Region::Empty(backend) => backend,
+            Region::Ashmem(_) => Backend::Ashmem,
+            Region::MadvFree(_) => Backend::MadvFree,
+            Region::Memfd(_) => Backend::Memfd,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn ptr(&self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    #[inline]
+    pub(crate) unsafe fn map_ptr<R: ?Sized>(
+        self,
+        f: impl FnOnce(NonNull<T>) -> NonNull<R>,
+    ) -> SystemPurgeableBox<R> {
+        let s = ManuallyDrop::new(self);
+        let ptr = s.ptr;
+        let region = ptr::read(&s.region);
+        let size = s.size;
+        SystemPurgeableBox {
+            ptr: f(ptr),
+            region,
+            size,
+            fork_policy: s.fork_policy,
+        }
+    }
+}
+
+/// See [SystemPurgeableBox::purge_probe]
+pub(crate) struct PurgeProbe {
+    address: *mut u8,
+    size: usize,
+}
+
+// SAFETY: the probe only reads the canaries atomically
+unsafe impl Send for PurgeProbe {}
+
+impl PurgeProbe {
+    /// # Safety
+    ///
+    /// The box must be alive and unlocked
+    pub(crate) unsafe fn is_purged(&self) -> bool {
+        madv_free::is_purged(self.address, self.size)
+    }
+}
+
+/// The backend of new boxes: the forced one in tests; otherwise `ashmem` if it is available
+/// and `fork_policy` allows shared mappings, `MADV_FREE` memory if not
+fn new_box_backend(fork_policy: ForkPolicy) -> Backend {
+    match super::forced_backend() {
+        Some(Backend::Ashmem) => Backend::Ashmem,
+        Some(Backend::Memfd) => Backend::Memfd,
+        Some(_) => Backend::MadvFree,
+        None if ashmem::is_available() && fork_policy.allows_shared_mapping() => Backend::Ashmem,
+        None => Backend::MadvFree,
+    }
+}
+
+/// Regions are named `purgeable` or `purgeable:<label>`, so they can be found in
+/// `/proc/<pid>/maps`; `memfd` regions are listed as `/memfd:purgeable:<label>`.
+/// Characters not allowed in anonymous VMA names are replaced with `_`
+fn region_name(label: Option<&str>) -> CString {
+    // The kernel limit of anonymous VMA names including the terminating NUL
+    const MAX_NAME_LEN: usize = 80;
+
+    let mut name = String::from("purgeable");
+    if let Some(label) = label {
+        name.push(':');
+        name.extend(label.chars().map(|c| match c {
+            '[' | ']' | '\\' | '$' | '`' => '_',
+            c if c.is_ascii_graphic() || c == ' ' => c,
+            _ => '_',
+        }));
+    }
+    name.truncate(MAX_NAME_LEN - 1);
+    CString::new(name).unwrap()
+}
+
+#[cfg(feature = "testing")]
+pub(crate) fn available_backends() -> Vec<Backend> {
+    let mut backends = Vec::new();
+    if ashmem::is_available() {
+        backends.push(Backend::Ashmem);
+    }
+    if madv_free::is_supported() {
+        backends.push(Backend::MadvFree);
+    }
+    if memfd::is_available() {
+        backends.push(Backend::Memfd);
+    }
+    backends
+}
+
+pub fn is_available() -> bool {
+    ashmem::is_available() || madv_free::is_supported()
+}
@@ -1,120 +1,119 @@
-use crate::PurgeableAllocError;
-use std::alloc::Layout;
-use std::mem::ManuallyDrop;
-use std::ptr;
-use std::ptr::NonNull;
+use std::ffi::CStr;
+use std::os::fd::{AsRawFd, BorrowedFd, IntoRawFd, OwnedFd};
+use std::sync::OnceLock;
+use std::{io, ptr};
 
 mod ashmem_sys;
 
-pub(crate) struct SystemPurgeableBox<T: ?Sized> {
-    ptr: ptr::NonNull<T>,
+/// An `ashmem` file descriptor. The mapping itself is owned by
+/// [SystemPurgeableBox](super::SystemPurgeableBox)
+pub(crate) struct AshmemRegion {
     fd: libc::c_int,
-    pub(crate) size: usize, // Remove it after `size_of_val_raw` stabilization
 }
 
-impl SystemPurgeableBox<[u8]> {
-    pub(crate) fn new_uninit_with_layout(
-        layout: Layout,
-    ) -> Result<SystemPurgeableBox<[u8]>, PurgeableAllocError> {
-        super::super::check_alignment(layout);
-        if layout.size() == 0 {
-            return Ok(SystemPurgeableBox {
-                ptr: unsafe {
-                    NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(
-                        layout.align() as *mut u8,
-                        0,
-                    ))
-                },
-                fd: 0,
-                size: 0,
-            });
-        }
-
-        let fd = unsafe { ashmem_sys::create(ptr::null(), layout.size() as libc::size_t) };
+impl AshmemRegion {
+    /// Creates an `ashmem` region of `size` bytes and maps it
+    pub(crate) fn new(size: usize, name: &CStr) -> Option<(*mut u8, AshmemRegion)> {
+        let fd = unsafe { ashmem_sys::create(name.as_ptr(), size as libc::size_t) };
         if fd < 0 {
             // return Err(io::Error::last_os_error());
-            return Err(PurgeableAllocError::new(layout));
+            return None;
         }
+        let region = AshmemRegion { fd };
+        let address = region.map(size).ok()?;
+        Some((address, region))
+    }
 
+    /// Maps the first `size` bytes of an `ashmem` region shared by another box, possibly
+    /// in another process
+    pub(crate) fn from_fd(fd: OwnedFd, size: usize) -> io::Result<(*mut u8, AshmemRegion)> {
+        let region = AshmemRegion {
+            fd: fd.into_raw_fd(),
+        };
+        let region_size = unsafe { ashmem_sys::get_size(region.fd) };
+        if region_size < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the file descriptor is not an ashmem region",
+            ));
+        }
+        if (region_size as usize) < size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the ashmem region is smaller than the requested size",
+            ));
+        }
+        let address = region.map(size)?;
+        Ok((address, region))
+    }
+
+    /// `true` if `fd` is an `ashmem` region
+    pub(crate) fn is_region(fd: BorrowedFd<'_>) -> bool {
+        unsafe { ashmem_sys::get_size(fd.as_raw_fd()) >= 0 }
+    }
+
+    fn map(&self, size: usize) -> io::Result<*mut u8> {
         let address = unsafe {
             libc::mmap(
                 ptr::null_mut(),
-                layout.size() as libc::size_t,
+                size as libc::size_t,
                 libc::PROT_READ | libc::PROT_WRITE,
                 libc::MAP_SHARED,
-                fd,
+                self.fd,
                 0,
             )
         };
         if address == libc::MAP_FAILED {
-            // return Err(io::Error::last_os_error());
-            return Err(PurgeableAllocError::new(layout));
+            return Err(io::Error::last_os_error());
         }
+        Ok(address as *mut u8)
+    }
 
-        let ptr = unsafe {
-            ptr::NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(
-                address as *mut u8,
-                layout.size(),
-            ))
-        };
-
-        Ok(SystemPurgeableBox {
-            ptr,
-            fd,
-            size: layout.size(),
-        })
+    pub(crate) fn fd(&self) -> BorrowedFd<'_> {
+        // SAFETY: the fd is open until the region is dropped
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
     }
-}
 
-impl<T: ?Sized> Drop for SystemPurgeableBox<T> {
-    fn drop(&mut self) {
-        if self.fd == 0 {
-            return;
-        }
-        unsafe {
-            libc::munmap(self.ptr() as *mut _, self.size());
-            libc::close(self.fd);
-        }
+    /// The name the region was created with
+    pub(crate) fn name(&self) -> Option<String> {
+        let name = unsafe { ashmem_sys::get_name(self.fd) }?;
+        name.into_string().ok()
     }
-}
 
-impl<T: ?Sized> SystemPurgeableBox<T> {
-    pub(crate) fn lock(&self) -> bool {
-        if self.fd == 0 {
-            return true;
-        }
+    pub(crate) fn pin(&self) -> bool {
         unsafe { ashmem_sys::pin(self.fd) }
     }
 
-    pub(crate) unsafe fn unlock(&self) {
-        if self.fd == 0 {
-            return;
-        }
+    pub(crate) unsafe fn unpin(&self) {
         ashmem_sys::unpin(self.fd);
     }
 
-    #[inline]
-    pub(crate) fn ptr(&self) -> *mut T {
-        self.ptr.as_ptr()
+    /// Purges all unpinned `ashmem` regions in the system; requires `CAP_SYS_ADMIN`.
+    /// Returns `true` on success
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn purge(&self) -> bool {
+        ashmem_sys::purge_all_caches(self.fd)
     }
+}
 
-    #[inline]
-    pub(crate) unsafe fn map_ptr<R: ?Sized>(
-        self,
-        f: impl FnOnce(ptr::NonNull<T>) -> ptr::NonNull<R>,
-    ) -> SystemPurgeableBox<R> {
-        let s = ManuallyDrop::new(self);
-        let ptr = s.ptr;
-        let fd = s.fd;
-        let size = s.size;
-        return SystemPurgeableBox {
-            ptr: f(ptr),
-            fd,
-            size,
-        };
+impl Drop for AshmemRegion {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
     }
 }
 
-pub fn is_available() -> bool {
-    SystemPurgeableBox::<[u8]>::new_uninit_with_layout(Layout::new::<u8>()).is_ok()
+pub(crate) fn is_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        let fd = unsafe { ashmem_sys::create(ptr::null(), page_size::get()) };
+        if fd < 0 {
+            return false;
+        }
+        unsafe {
+            libc::close(fd);
+        }
+        true
+    })
 }
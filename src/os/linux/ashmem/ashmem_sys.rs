@@ -2,41 +2,48 @@
 // ISC license (compatible with MIT and APACHE 2.0)
 
 use ioctl_sys::iow;
+use std::ffi::CStr;
 use std::mem::size_of;
 
 const __ASHMEMIOC: u32 = 0x77;
+const ASHMEM_NAME_LEN: usize = 256;
 
-static mut LIBANDROID_ASHAREDMEMORY_CREATE: Option<
-    extern "C" fn(*const libc::c_char, libc::size_t) -> libc::c_int,
-> = None;
-static mut LIBANDROID_ASHAREDMEMORY_GETSIZE: Option<extern "C" fn(libc::c_int) -> libc::size_t> =
-    None;
-static mut LIBANDROID_ASHAREDMEMORY_SETPROT: Option<
-    extern "C" fn(libc::c_int, libc::c_int) -> libc::c_int,
-> = None;
+type ASharedMemoryCreate = extern "C" fn(*const libc::c_char, libc::size_t) -> libc::c_int;
+type ASharedMemoryGetSize = extern "C" fn(libc::c_int) -> libc::size_t;
+type ASharedMemorySetProt = extern "C" fn(libc::c_int, libc::c_int) -> libc::c_int;
+
+static mut LIBANDROID_ASHAREDMEMORY_CREATE: Option<ASharedMemoryCreate> = None;
+static mut LIBANDROID_ASHAREDMEMORY_GETSIZE: Option<ASharedMemoryGetSize> = None;
+static mut LIBANDROID_ASHAREDMEMORY_SETPROT: Option<ASharedMemorySetProt> = None;
 
 unsafe fn maybe_init() {
-    const LIBANDROID_NAME: *const libc::c_char = "libandroid\0".as_ptr() as *const libc::c_char;
-    const LIBANDROID_ASHAREDMEMORY_CREATE_NAME: *const libc::c_char =
-        "ASharedMemory_create\0".as_ptr() as _;
-    const LIBANDROID_ASHAREDMEMORY_GETSIZE_NAME: *const libc::c_char =
-        "ASharedMemory_getSize\0".as_ptr() as _;
-    const LIBANDROID_ASHAREDMEMORY_SETPROT_NAME: *const libc::c_char =
-        "ASharedMemory_setProt\0".as_ptr() as _;
+    const LIBANDROID_NAME: &CStr = c"libandroid";
+    const LIBANDROID_ASHAREDMEMORY_CREATE_NAME: &CStr = c"ASharedMemory_create";
+    const LIBANDROID_ASHAREDMEMORY_GETSIZE_NAME: &CStr = c"ASharedMemory_getSize";
+    const LIBANDROID_ASHAREDMEMORY_SETPROT_NAME: &CStr = c"ASharedMemory_setProt";
     static ONCE: std::sync::Once = std::sync::Once::new();
     ONCE.call_once(|| {
         // Leak the handle, there's no safe time to close it.
-        let handle = libc::dlopen(LIBANDROID_NAME, libc::RTLD_LAZY | libc::RTLD_LOCAL);
+        let handle = libc::dlopen(LIBANDROID_NAME.as_ptr(), libc::RTLD_LAZY | libc::RTLD_LOCAL);
         if handle.is_null() {
             return;
         }
         // Transmute guarantee for `fn -> Option<fn>`: https://doc.rust-lang.org/std/option/#representation
         LIBANDROID_ASHAREDMEMORY_CREATE =
-            std::mem::transmute(libc::dlsym(handle, LIBANDROID_ASHAREDMEMORY_CREATE_NAME));
+            std::mem::transmute::<*mut libc::c_void, Option<ASharedMemoryCreate>>(libc::dlsym(
+                handle,
+                LIBANDROID_ASHAREDMEMORY_CREATE_NAME.as_ptr(),
+            ));
         LIBANDROID_ASHAREDMEMORY_GETSIZE =
-            std::mem::transmute(libc::dlsym(handle, LIBANDROID_ASHAREDMEMORY_GETSIZE_NAME));
+            std::mem::transmute::<*mut libc::c_void, Option<ASharedMemoryGetSize>>(libc::dlsym(
+                handle,
+                LIBANDROID_ASHAREDMEMORY_GETSIZE_NAME.as_ptr(),
+            ));
         LIBANDROID_ASHAREDMEMORY_SETPROT =
-            std::mem::transmute(libc::dlsym(handle, LIBANDROID_ASHAREDMEMORY_SETPROT_NAME));
+            std::mem::transmute::<*mut libc::c_void, Option<ASharedMemorySetProt>>(libc::dlsym(
+                handle,
+                LIBANDROID_ASHAREDMEMORY_SETPROT_NAME.as_ptr(),
+            ));
     });
 }
 
@@ -47,8 +54,7 @@ unsafe fn maybe_init() {
 /// Directly calls C or kernel APIs.
 #[allow(non_snake_case)]
 pub(crate) unsafe fn create(name: *const libc::c_char, size: libc::size_t) -> libc::c_int {
-    const ASHMEM_NAME_DEF: *const libc::c_char = "/dev/ashmem\0".as_ptr() as _;
-    const ASHMEM_NAME_LEN: usize = 256;
+    const ASHMEM_NAME_DEF: &CStr = c"/dev/ashmem";
     const ASHMEM_SET_NAME: u32 = iow!(
         __ASHMEMIOC,
         1,
@@ -61,7 +67,11 @@ pub(crate) unsafe fn create(name: *const libc::c_char, size: libc::size_t) -> li
         return fun(name, size);
     }
 
-    let fd = libc::open(ASHMEM_NAME_DEF, libc::O_RDWR, 0o600);
+    let fd = libc::open(
+        ASHMEM_NAME_DEF.as_ptr(),
+        libc::O_RDWR | libc::O_CLOEXEC,
+        0o600,
+    );
     if fd < 0 {
         return fd;
     }
@@ -84,6 +94,24 @@ pub(crate) unsafe fn create(name: *const libc::c_char, size: libc::size_t) -> li
     fd
 }
 
+/// Returns the size of the region, or `-1` if `fd` is not an `ashmem` region
+pub(crate) unsafe fn get_size(fd: libc::c_int) -> libc::c_int {
+    use ioctl_sys::io;
+    const ASHMEM_GET_SIZE: u32 = io!(__ASHMEMIOC, 4);
+    libc::ioctl(fd, ASHMEM_GET_SIZE as _)
+}
+
+/// Returns the name of the region, or `None` if `fd` is not an `ashmem` region
+pub(crate) unsafe fn get_name(fd: libc::c_int) -> Option<std::ffi::CString> {
+    use ioctl_sys::ior;
+    const ASHMEM_GET_NAME: u32 = ior!(__ASHMEMIOC, 2, size_of::<[libc::c_char; ASHMEM_NAME_LEN]>());
+    let mut name = [0u8; ASHMEM_NAME_LEN];
+    if libc::ioctl(fd, ASHMEM_GET_NAME as _, name.as_mut_ptr()) != 0 {
+        return None;
+    }
+    CStr::from_bytes_until_nul(&name).ok().map(CStr::to_owned)
+}
+
 #[repr(C)]
 struct AshmemPin {
     offset: libc::__u32,
@@ -98,7 +126,7 @@ const ASHMEM_NOT_PURGED: libc::c_int = 0;
 pub(crate) unsafe fn pin(fd: libc::c_int) -> bool {
     const ASHMEM_PIN: u32 = iow!(__ASHMEMIOC, 7, size_of::<AshmemPin>());
     let pin = AshmemPin { offset: 0, len: 0 };
-    return libc::ioctl(fd, ASHMEM_PIN as _, &pin) == ASHMEM_NOT_PURGED;
+    libc::ioctl(fd, ASHMEM_PIN as _, &pin) == ASHMEM_NOT_PURGED
 }
 
 pub(crate) unsafe fn unpin(fd: libc::c_int) {
@@ -106,3 +134,10 @@ pub(crate) unsafe fn unpin(fd: libc::c_int) {
     let pin = AshmemPin { offset: 0, len: 0 };
     let _ = libc::ioctl(fd, ASHMEM_UNPIN as _, &pin);
 }
+
+#[cfg(feature = "testing")]
+pub(crate) unsafe fn purge_all_caches(fd: libc::c_int) -> bool {
+    use ioctl_sys::io;
+    const ASHMEM_PURGE_ALL_CACHES: u32 = io!(__ASHMEMIOC, 10);
+    libc::ioctl(fd, ASHMEM_PURGE_ALL_CACHES as _) >= 0
+}
@@ -0,0 +1,136 @@
+use std::ffi::CStr;
+use std::mem::size_of;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+/// Written to the first word of every page on unlock. Zero pages never contain it
+const CANARY: usize = usize::from_ne_bytes([0x5a; size_of::<usize>()]);
+/// The size of the first word of a page that `lock` restores
+#[cfg(feature = "testing")]
+pub(crate) const SAVED_WORD_SIZE: usize = size_of::<usize>();
+
+/// Anonymous private memory that is released with `MADV_FREE` on unlock.
+///
+/// The kernel silently replaces freed pages with zero pages, so the purge is detected with
+/// canaries: `unlock` saves the first word of every page and replaces it with [CANARY], then
+/// `lock` atomically swaps the saved words back. A freed page doesn't contain the canary,
+/// and a successful swap dirties the page, so the kernel can't free it anymore.
+pub(crate) struct MadvFreeRegion {
+    saved_words: Vec<usize>,
+}
+
+impl MadvFreeRegion {
+    /// Maps `size` bytes of anonymous memory. Fails if the kernel doesn't support
+    /// `MADV_FREE`: the memory could never be purged then
+    pub(crate) fn new(size: usize, name: &CStr) -> Option<(*mut u8, MadvFreeRegion)> {
+        if !is_supported() {
+            return None;
+        }
+        let address = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size as libc::size_t,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            return None;
+        }
+
+        // Requires `CONFIG_ANON_VMA_NAME` (Linux 5.17+); the name is optional, so errors
+        // are ignored
+        unsafe {
+            libc::prctl(
+                libc::PR_SET_VMA,
+                libc::PR_SET_VMA_ANON_NAME,
+                address,
+                size,
+                name.as_ptr(),
+            );
+        }
+
+        let region = MadvFreeRegion {
+            saved_words: Vec::with_capacity(size.div_ceil(page_size::get())),
+        };
+        Some((address as *mut u8, region))
+    }
+
+    /// # Safety
+    ///
+    /// `address` must be the address of this region; the region must be locked
+    pub(crate) unsafe fn unlock(&mut self, address: *mut u8, size: usize) {
+        self.saved_words.clear();
+        for offset in (0..size).step_by(page_size::get()) {
+            let word = address.add(offset) as *mut usize;
+            self.saved_words.push(word.read());
+            word.write(CANARY);
+        }
+
+        let ret = libc::madvise(address as *mut _, size, libc::MADV_FREE);
+        debug_assert_eq!(ret, 0);
+    }
+
+    /// Drops the pages immediately, as the kernel does under memory pressure.
+    /// Returns `true` on success
+    ///
+    /// # Safety
+    ///
+    /// `address` must be the address of this region; the region must be unlocked
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn purge(&mut self, address: *mut u8, size: usize) -> bool {
+        libc::madvise(address as *mut _, size, libc::MADV_DONTNEED) == 0
+    }
+
+    /// # Safety
+    ///
+    /// `address` must be the address of this region; the region must be unlocked
+    pub(crate) unsafe fn lock(&mut self, address: *mut u8) -> bool {
+        let page_size = page_size::get();
+        self.saved_words.iter().enumerate().all(|(i, &saved)| {
+            // SAFETY: mappings are page-aligned, so the first word of a page is aligned
+            let word = &*(address.add(i * page_size) as *const AtomicUsize);
+            word.compare_exchange(CANARY, saved, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+        })
+    }
+}
+
+/// Returns `true` if a page of the unlocked region has been freed. Unlike [MadvFreeRegion::lock],
+/// it only reads the canaries, so it can be called from any thread
+///
+/// # Safety
+///
+/// `address` must be the address of an unlocked region of `size` bytes
+pub(crate) unsafe fn is_purged(address: *mut u8, size: usize) -> bool {
+    (0..size).step_by(page_size::get()).any(|offset| {
+        // SAFETY: mappings are page-aligned, so the first word of a page is aligned
+        let word = &*(address.add(offset) as *const AtomicUsize);
+        word.load(Ordering::Relaxed) != CANARY
+    })
+}
+
+/// `MADV_FREE` is supported since Linux 4.5
+pub(crate) fn is_supported() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| unsafe {
+        let size = page_size::get();
+        let address = libc::mmap(
+            ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if address == libc::MAP_FAILED {
+            return false;
+        }
+        let supported = libc::madvise(address, size, libc::MADV_FREE) == 0;
+        libc::munmap(address, size);
+        supported
+    })
+}
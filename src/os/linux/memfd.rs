@@ -0,0 +1,128 @@
+use std::ffi::CStr;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::{io, ptr};
+
+/// A sealed `memfd` file descriptor, used to share boxes between processes where `ashmem`
+/// is not available. The mapping itself is owned by
+/// [SystemPurgeableBox](super::SystemPurgeableBox).
+///
+/// The kernel never discards `memfd` pages, it swaps them out like other shared memory, so
+/// the region is never purged: unlocking only marks its pages cold with `MADV_COLD`.
+pub(crate) struct MemfdRegion {
+    fd: OwnedFd,
+}
+
+impl MemfdRegion {
+    /// Creates a `memfd` region of `size` bytes named `name`, so it is listed as
+    /// `/memfd:<name>` in `/proc/<pid>/maps`, and maps it
+    pub(crate) fn new(size: usize, name: &CStr) -> Option<(*mut u8, MemfdRegion)> {
+        let fd = unsafe {
+            libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING)
+        };
+        if fd < 0 {
+            return None;
+        }
+        let region = MemfdRegion {
+            // SAFETY: `memfd_create` returned a new file descriptor
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        let raw = region.fd.as_raw_fd();
+        // The size is sealed, so the processes mapping the region can't truncate it under
+        // each other
+        let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+        unsafe {
+            if libc::ftruncate(raw, size as libc::off_t) != 0
+                || libc::fcntl(raw, libc::F_ADD_SEALS, seals) != 0
+            {
+                return None;
+            }
+        }
+        let address = region.map(size).ok()?;
+        Some((address, region))
+    }
+
+    /// Maps the first `size` bytes of a `memfd` region shared by another box, possibly in
+    /// another process. The region must be sealed against shrinking: pages truncated by
+    /// another process would fault on access
+    pub(crate) fn from_fd(fd: OwnedFd, size: usize) -> io::Result<(*mut u8, MemfdRegion)> {
+        let region = MemfdRegion { fd };
+        let seals = unsafe { libc::fcntl(region.fd.as_raw_fd(), libc::F_GET_SEALS) };
+        if seals < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the file descriptor is not an ashmem or memfd region",
+            ));
+        }
+        if seals & libc::F_SEAL_SHRINK == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the memfd region is not sealed against shrinking",
+            ));
+        }
+        let region_size = std::fs::File::from(region.fd.try_clone()?)
+            .metadata()?
+            .len();
+        if region_size < size as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the memfd region is smaller than the requested size",
+            ));
+        }
+        let address = region.map(size)?;
+        Ok((address, region))
+    }
+
+    fn map(&self, size: usize) -> io::Result<*mut u8> {
+        let address = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size as libc::size_t,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                self.fd.as_raw_fd(),
+                0,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(address as *mut u8)
+    }
+
+    pub(crate) fn fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+
+    /// The name the region was created with, without the `memfd:` prefix
+    pub(crate) fn name(&self) -> Option<String> {
+        let link = std::fs::read_link(format!("/proc/self/fd/{}", self.fd.as_raw_fd())).ok()?;
+        let link = link.to_str()?;
+        let name = link.strip_prefix("/memfd:")?;
+        Some(name.strip_suffix(" (deleted)").unwrap_or(name).to_owned())
+    }
+
+    /// Requires Linux 5.4+; the advice is only a hint, so errors are ignored
+    ///
+    /// # Safety
+    ///
+    /// `address` must be the address of this region
+    pub(crate) unsafe fn unlock(&self, address: *mut u8, size: usize) {
+        libc::madvise(address as *mut _, size, libc::MADV_COLD);
+    }
+}
+
+/// `memfd_create` is available since Linux 3.17, but can be blocked by seccomp filters
+#[cfg(feature = "testing")]
+pub(crate) fn is_available() -> bool {
+    static AVAILABLE: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        let size = page_size::get();
+        let Some((address, _region)) = MemfdRegion::new(size, c"purgeable") else {
+            return false;
+        };
+        unsafe {
+            libc::munmap(address as *mut _, size);
+        }
+        true
+    })
+}
@@ -1,14 +1,14 @@
-use crate::PurgeableAllocError;
+use crate::{Backend, PurgePriority, PurgeableAllocError};
 use mach_sys::{
-    vm_address_t, vm_size_t, KERN_SUCCESS, VM_FLAGS_ANYWHERE, VM_FLAGS_PURGABLE, VM_PURGABLE_EMPTY,
-    VM_PURGABLE_GET_STATE, VM_PURGABLE_NONVOLATILE, VM_PURGABLE_SET_STATE, VM_PURGABLE_VOLATILE,
-    VM_VOLATILE_GROUP_DEFAULT,
+    vm_address_t, vm_size_t, KERN_SUCCESS, VM_FLAGS_ANYWHERE, VM_FLAGS_PURGABLE,
+    VM_PURGABLE_BEHAVIOR_FIFO, VM_PURGABLE_BEHAVIOR_LIFO, VM_PURGABLE_EMPTY, VM_PURGABLE_GET_STATE,
+    VM_PURGABLE_NONVOLATILE, VM_PURGABLE_SET_STATE, VM_PURGABLE_VOLATILE, VM_VOLATILE_GROUP_SHIFT,
 };
 use std::alloc::Layout;
 use std::ffi::c_void;
 use std::mem::ManuallyDrop;
+use std::ptr;
 use std::ptr::NonNull;
-use std::{ptr};
 
 mod mach_sys;
 
@@ -18,8 +18,10 @@ pub(crate) struct SystemPurgeableBox<T: ?Sized> {
 }
 
 impl SystemPurgeableBox<[u8]> {
+    /// Regions can't be named on this platform, so `_label` is ignored
     pub(crate) fn new_uninit_with_layout(
         layout: Layout,
+        _label: Option<&str>,
     ) -> Result<SystemPurgeableBox<[u8]>, PurgeableAllocError> {
         super::check_alignment(layout);
         if layout.size() == 0 {
@@ -98,15 +100,15 @@ impl<T: ?Sized> SystemPurgeableBox<T> {
             return false;
         }
 
-        return state & VM_PURGABLE_EMPTY == 0;
+        state & VM_PURGABLE_EMPTY == 0
     }
 
-    pub(crate) unsafe fn unlock(&self) {
+    pub(crate) unsafe fn unlock(&self, priority: PurgePriority) {
         if self.size == 0 {
             return;
         }
 
-        let mut state = VM_PURGABLE_VOLATILE | VM_VOLATILE_GROUP_DEFAULT;
+        let mut state = VM_PURGABLE_VOLATILE | volatile_state_flags(priority);
 
         let ret = mach_sys::vm_purgable_control(
             mach_sys::mach_task_self(),
@@ -119,22 +121,50 @@ impl<T: ?Sized> SystemPurgeableBox<T> {
     }
 
     pub(crate) fn is_purged(&self) -> bool {
-        let mut state = 0;
+        is_purged(self.ptr.as_ptr() as *mut c_void as vm_address_t)
+    }
 
-        let ret = unsafe {
-            mach_sys::vm_purgable_control(
-                mach_sys::mach_task_self(),
-                self.ptr.as_ptr() as *mut c_void as vm_address_t,
-                VM_PURGABLE_GET_STATE,
-                &mut state,
-            )
-        };
+    /// Allows checking the state of the unlocked box from another thread
+    pub(crate) fn purge_probe(&self) -> Option<PurgeProbe> {
+        (self.size != 0).then_some(PurgeProbe {
+            address: self.ptr.as_ptr() as *mut c_void as vm_address_t,
+        })
+    }
 
-        if ret != KERN_SUCCESS {
-            return true;
+    /// Returns `true` if the next `lock` is guaranteed to fail
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn force_purge(&mut self) -> bool {
+        if self.size == 0 {
+            return false;
         }
 
-        return state & VM_PURGABLE_EMPTY != 0;
+        let mut state = VM_PURGABLE_EMPTY;
+
+        let ret = mach_sys::vm_purgable_control(
+            mach_sys::mach_task_self(),
+            self.ptr.as_ptr() as *mut c_void as vm_address_t,
+            VM_PURGABLE_SET_STATE,
+            &mut state,
+        );
+
+        ret == KERN_SUCCESS
+    }
+
+    /// Flips the bits of the last byte without the backend noticing, like a partial purge.
+    /// Returns `false` if the box is empty
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn corrupt(&mut self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let last = (self.ptr() as *mut u8).add(self.size - 1);
+        last.write(!last.read());
+        true
+    }
+
+    #[inline]
+    pub(crate) fn backend(&self) -> Backend {
+        Backend::Mach
     }
 
     pub(crate) fn ptr(&self) -> *mut T {
@@ -149,10 +179,60 @@ impl<T: ?Sized> SystemPurgeableBox<T> {
         let s = ManuallyDrop::new(self);
         let ptr = s.ptr;
         let size = s.size;
-        return SystemPurgeableBox { ptr: f(ptr), size };
+        SystemPurgeableBox { ptr: f(ptr), size }
     }
 }
 
+/// Whether the kernel has emptied the volatile object at `address`; errors count as purged
+fn is_purged(address: vm_address_t) -> bool {
+    let mut state = 0;
+
+    let ret = unsafe {
+        mach_sys::vm_purgable_control(
+            mach_sys::mach_task_self(),
+            address,
+            VM_PURGABLE_GET_STATE,
+            &mut state,
+        )
+    };
+
+    if ret != KERN_SUCCESS {
+        return true;
+    }
+
+    state & VM_PURGABLE_EMPTY != 0
+}
+
+/// See [SystemPurgeableBox::purge_probe]
+pub(crate) struct PurgeProbe {
+    address: vm_address_t,
+}
+
+impl PurgeProbe {
+    /// # Safety
+    ///
+    /// The box must be alive and unlocked
+    pub(crate) unsafe fn is_purged(&self) -> bool {
+        is_purged(self.address)
+    }
+}
+
+/// Group zero objects are purged before group 1, etc
+fn volatile_state_flags(priority: PurgePriority) -> libc::c_int {
+    let group = (priority.level() as libc::c_int) << VM_VOLATILE_GROUP_SHIFT;
+    let behavior = if priority.is_lifo() {
+        VM_PURGABLE_BEHAVIOR_LIFO
+    } else {
+        VM_PURGABLE_BEHAVIOR_FIFO
+    };
+    group | behavior
+}
+
+#[cfg(feature = "testing")]
+pub(crate) fn available_backends() -> Vec<Backend> {
+    vec![Backend::Mach]
+}
+
 pub fn is_available() -> bool {
     true
 }
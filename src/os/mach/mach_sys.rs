@@ -41,9 +41,9 @@ pub(crate) const VM_PURGABLE_GET_STATE: vm_purgable_t = 1;
  */
 pub(crate) const VM_VOLATILE_GROUP_SHIFT: ::libc::c_int = 8;
 // pub(crate) const VM_VOLATILE_GROUP_MASK: ::libc::c_int = 7 << VM_VOLATILE_GROUP_SHIFT;
-pub(crate) const VM_VOLATILE_GROUP_DEFAULT: ::libc::c_int = VM_VOLATILE_GROUP_0;
+// pub(crate) const VM_VOLATILE_GROUP_DEFAULT: ::libc::c_int = VM_VOLATILE_GROUP_0;
 
-pub(crate) const VM_VOLATILE_GROUP_0: ::libc::c_int = 0 << VM_VOLATILE_GROUP_SHIFT;
+// pub(crate) const VM_VOLATILE_GROUP_0: ::libc::c_int = 0 << VM_VOLATILE_GROUP_SHIFT;
 // pub(crate) const VM_VOLATILE_GROUP_1: ::libc::c_int = 1 << VM_VOLATILE_GROUP_SHIFT;
 // pub(crate) const VM_VOLATILE_GROUP_2: ::libc::c_int = 2 << VM_VOLATILE_GROUP_SHIFT;
 // pub(crate) const VM_VOLATILE_GROUP_3: ::libc::c_int = 3 << VM_VOLATILE_GROUP_SHIFT;
@@ -58,10 +58,10 @@ pub(crate) const VM_VOLATILE_GROUP_0: ::libc::c_int = 0 << VM_VOLATILE_GROUP_SHI
  * LIFO objects will be emptied after objects that are added later.
  * - Input only, not returned on state queries.
  */
-// pub(crate) const VM_PURGABLE_BEHAVIOR_SHIFT: ::libc::c_int = 6;
+pub(crate) const VM_PURGABLE_BEHAVIOR_SHIFT: ::libc::c_int = 6;
 // pub(crate) const VM_PURGABLE_BEHAVIOR_MASK: ::libc::c_int = 1 << VM_PURGABLE_BEHAVIOR_SHIFT;
-// pub(crate) const VM_PURGABLE_BEHAVIOR_FIFO: ::libc::c_int = 0 << VM_PURGABLE_BEHAVIOR_SHIFT;
-// pub(crate) const VM_PURGABLE_BEHAVIOR_LIFO: ::libc::c_int = 1 << VM_PURGABLE_BEHAVIOR_SHIFT;
+pub(crate) const VM_PURGABLE_BEHAVIOR_FIFO: ::libc::c_int = 0 << VM_PURGABLE_BEHAVIOR_SHIFT;
+pub(crate) const VM_PURGABLE_BEHAVIOR_LIFO: ::libc::c_int = 1 << VM_PURGABLE_BEHAVIOR_SHIFT;
 
 /*
  * Obsolete object.
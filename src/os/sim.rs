@@ -0,0 +1,379 @@
+//! A pure-Rust backend that keeps boxes in plain heap memory and simulates purging with an
+//! explicit state machine. It makes no syscalls, so the whole crate can run under Miri
+//! and loom.
+//!
+//! Unlocked boxes are purged only on demand, see [purge] and [purge_all]. Unlike real
+//! backends, the simulator reports the purges to [on_purge](crate::on_purge) subscribers
+//! right away with [PurgeDetection::Reclaim]. A purged box is deallocated immediately, so Miri
+//! reports any access to the purged memory. Contract violations (e.g. locking a box twice)
+//! panic.
+
+use crate::events::{self, PurgeDetection};
+use crate::{trace, Backend, PurgePriority, PurgeableAllocError};
+use std::alloc::{self, Layout};
+use std::collections::BTreeMap;
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::ptr::NonNull;
+
+#[cfg(loom)]
+use loom::sync::{Mutex, MutexGuard};
+#[cfg(not(loom))]
+use std::sync::{Mutex, MutexGuard};
+
+pub(crate) struct SystemPurgeableBox<T: ?Sized> {
+    ptr: NonNull<T>,
+    id: u64,
+    pub(crate) size: usize, // Remove it after `size_of_val_raw` stabilization
+}
+
+struct Simulator {
+    regions: BTreeMap<u64, Region>,
+    next_id: u64,
+    next_unlock_seq: u64,
+}
+
+struct Region {
+    address: *mut u8,
+    layout: Layout,
+    state: RegionState,
+    tag: u64,
+    /// Whether the purge has been reported with [PurgeDetection::Reclaim]
+    purge_reported: bool,
+}
+
+/// SAFETY: the memory behind `address` is accessed by the simulator only when it is
+/// `UNLOCKED`, i.e. when the owning box doesn't access it, and always under the mutex
+unsafe impl Send for Region {}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum RegionState {
+    Locked,
+    Unlocked { priority: PurgePriority, seq: u64 },
+    Purged,
+}
+
+#[cfg(loom)]
+loom::lazy_static! {
+    static ref SIMULATOR: Mutex<Simulator> = Mutex::new(Simulator::new());
+}
+#[cfg(not(loom))]
+static SIMULATOR: Mutex<Simulator> = Mutex::new(Simulator::new());
+
+fn simulator() -> MutexGuard<'static, Simulator> {
+    SIMULATOR.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Simulator {
+    const fn new() -> Simulator {
+        Simulator {
+            regions: BTreeMap::new(),
+            next_id: 0,
+            next_unlock_seq: 0,
+        }
+    }
+
+    fn region(&mut self, id: u64) -> &mut Region {
+        self.regions
+            .get_mut(&id)
+            .expect("the simulated purgeable region has already been released")
+    }
+
+    /// Purges unlocked regions matching the `filter` until at least `bytes` are purged,
+    /// in the [PurgePriority::purge_order].
+    ///
+    /// Returns the tags and the sizes of the purged regions
+    fn purge(&mut self, bytes: usize, filter: impl Fn(&Region) -> bool) -> Vec<(u64, usize)> {
+        let mut candidates: Vec<_> = self
+            .regions
+            .iter()
+            .filter(|(_, region)| filter(region))
+            .filter_map(|(&id, region)| match region.state {
+                RegionState::Unlocked { priority, seq } => Some((priority.purge_order(seq), id)),
+                _ => None,
+            })
+            .collect();
+        candidates.sort_unstable();
+
+        let mut purged = Vec::new();
+        let mut purged_bytes = 0;
+        for (_, id) in candidates {
+            if purged_bytes >= bytes {
+                break;
+            }
+            let region = self.region(id);
+            let size = region.purge();
+            region.purge_reported = true;
+            purged.push((region.tag, size));
+            purged_bytes += size;
+        }
+        purged
+    }
+}
+
+impl Region {
+    /// Returns the number of purged bytes
+    fn purge(&mut self) -> usize {
+        if !matches!(self.state, RegionState::Unlocked { .. }) {
+            return 0;
+        }
+        // SAFETY: the region is `UNLOCKED`, so nobody accesses its memory
+        unsafe { alloc::dealloc(self.address, self.layout) };
+        self.state = RegionState::Purged;
+        self.layout.size()
+    }
+}
+
+/// Purges unlocked boxes in the [PurgePriority] order until at least `bytes` are purged.
+/// Returns the number of purged bytes
+pub fn purge(bytes: usize) -> usize {
+    purge_matching(bytes, |_| true)
+}
+
+/// Purges all unlocked boxes. Returns the number of purged bytes
+pub fn purge_all() -> usize {
+    purge(usize::MAX)
+}
+
+/// Purges unlocked boxes with the `tag` (see [NonPurgeableBox::set_tag]).
+/// Returns the number of purged bytes
+///
+/// [NonPurgeableBox::set_tag]: crate::NonPurgeableBox::set_tag
+pub fn purge_tagged(tag: u64, bytes: usize) -> usize {
+    purge_matching(bytes, |region| region.tag == tag)
+}
+
+fn purge_matching(bytes: usize, filter: impl Fn(&Region) -> bool) -> usize {
+    let purged = simulator().purge(bytes, filter);
+    // Subscribers are called without holding the simulator lock, so they can lock boxes
+    for &(tag, size) in &purged {
+        trace::purge_detected(size, Backend::Sim, None, PurgeDetection::Reclaim);
+        events::emit(tag, size, Backend::Sim, PurgeDetection::Reclaim);
+    }
+    purged.iter().map(|&(_, size)| size).sum()
+}
+
+impl SystemPurgeableBox<[u8]> {
+    /// Regions are not named in the simulator, so `_label` is ignored
+    pub(crate) fn new_uninit_with_layout(
+        layout: Layout,
+        _label: Option<&str>,
+    ) -> Result<SystemPurgeableBox<[u8]>, PurgeableAllocError> {
+        super::check_alignment(layout);
+        if layout.size() == 0 {
+            return Ok(SystemPurgeableBox {
+                ptr: unsafe {
+                    NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(
+                        ptr::without_provenance_mut::<u8>(layout.align()),
+                        0,
+                    ))
+                },
+                id: 0,
+                size: 0,
+            });
+        }
+
+        // Real backends return page-aligned memory
+        let region_layout = Layout::from_size_align(layout.size(), page_size::get())
+            .map_err(|_| PurgeableAllocError::new(layout))?;
+        let address = unsafe { alloc::alloc(region_layout) };
+        if address.is_null() {
+            return Err(PurgeableAllocError::new(layout));
+        }
+
+        let mut simulator = simulator();
+        let id = simulator.next_id;
+        simulator.next_id += 1;
+        simulator.regions.insert(
+            id,
+            Region {
+                address,
+                layout: region_layout,
+                state: RegionState::Locked,
+                tag: 0,
+                purge_reported: false,
+            },
+        );
+
+        let ptr = unsafe {
+            NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(address, layout.size()))
+        };
+        Ok(SystemPurgeableBox {
+            ptr,
+            id,
+            size: layout.size(),
+        })
+    }
+}
+
+impl<T: ?Sized> Drop for SystemPurgeableBox<T> {
+    fn drop(&mut self) {
+        if self.size == 0 {
+            return;
+        }
+        let region = simulator().regions.remove(&self.id);
+        if let Some(region) = region {
+            if region.state != RegionState::Purged {
+                unsafe { alloc::dealloc(region.address, region.layout) };
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> SystemPurgeableBox<T> {
+    pub(crate) fn lock(&mut self) -> bool {
+        if self.size == 0 {
+            return true;
+        }
+        let mut simulator = simulator();
+        let region = simulator.region(self.id);
+        match region.state {
+            RegionState::Unlocked { .. } => {
+                region.state = RegionState::Locked;
+                true
+            }
+            RegionState::Purged => false,
+            RegionState::Locked => panic!("locking an already locked purgeable box"),
+        }
+    }
+
+    pub(crate) unsafe fn unlock(&mut self, priority: PurgePriority) {
+        if self.size == 0 {
+            return;
+        }
+        let mut simulator = simulator();
+        let seq = simulator.next_unlock_seq;
+        simulator.next_unlock_seq += 1;
+        let region = simulator.region(self.id);
+        assert_eq!(
+            region.state,
+            RegionState::Locked,
+            "unlocking a purgeable box that is not locked"
+        );
+        region.state = RegionState::Unlocked { priority, seq };
+    }
+
+    /// Allows [purge_tagged] to identify the box
+    pub(crate) fn set_tag(&mut self, tag: u64) {
+        if self.size == 0 {
+            return;
+        }
+        simulator().region(self.id).tag = tag;
+    }
+
+    /// Returns `true` if the next `lock` is guaranteed to fail
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn force_purge(&mut self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        simulator().region(self.id).purge();
+        true
+    }
+
+    /// Flips the bits of the last byte without the backend noticing, like a partial purge.
+    /// Returns `false` if the box is empty or purged
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn corrupt(&mut self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let mut simulator = simulator();
+        let region = simulator.region(self.id);
+        if region.state == RegionState::Purged {
+            return false;
+        }
+        // SAFETY: the region hasn't been deallocated, and the lock excludes purges
+        let last = region.address.add(self.size - 1);
+        last.write(!last.read());
+        true
+    }
+
+    pub(crate) fn is_purged(&self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        simulator().region(self.id).state == RegionState::Purged
+    }
+
+    /// Whether the purge has been reported when the simulator reclaimed the box
+    pub(crate) fn is_purge_reported(&self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        simulator().region(self.id).purge_reported
+    }
+
+    /// Allows checking the state of the unlocked box from another thread
+    pub(crate) fn purge_probe(&self) -> Option<PurgeProbe> {
+        (self.size != 0).then_some(PurgeProbe { id: self.id })
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn resident_pages(&self) -> std::io::Result<usize> {
+        if self.is_purged() {
+            return Ok(0);
+        }
+        Ok(self.size.div_ceil(page_size::get()))
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn resident_bytes(&self) -> std::io::Result<usize> {
+        if self.is_purged() {
+            return Ok(0);
+        }
+        Ok(self.size)
+    }
+
+    #[inline]
+    pub(crate) fn backend(&self) -> Backend {
+        Backend::Sim
+    }
+
+    #[inline]
+    pub(crate) fn ptr(&self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    #[inline]
+    pub(crate) unsafe fn map_ptr<R: ?Sized>(
+        self,
+        f: impl FnOnce(NonNull<T>) -> NonNull<R>,
+    ) -> SystemPurgeableBox<R> {
+        let s = ManuallyDrop::new(self);
+        SystemPurgeableBox {
+            ptr: f(s.ptr),
+            id: s.id,
+            size: s.size,
+        }
+    }
+}
+
+/// See [SystemPurgeableBox::purge_probe]
+pub(crate) struct PurgeProbe {
+    id: u64,
+}
+
+impl PurgeProbe {
+    /// Purges reclaimed by the simulator have already been reported, so only purges
+    /// forced by [testing::force_purge](crate::testing::force_purge) are found
+    ///
+    /// # Safety
+    ///
+    /// The box must be alive and unlocked
+    pub(crate) unsafe fn is_purged(&self) -> bool {
+        simulator()
+            .regions
+            .get(&self.id)
+            .is_some_and(|region| region.state == RegionState::Purged && !region.purge_reported)
+    }
+}
+
+#[cfg(feature = "testing")]
+pub(crate) fn available_backends() -> Vec<Backend> {
+    vec![Backend::Sim]
+}
+
+pub fn is_available() -> bool {
+    true
+}
@@ -1,4 +1,4 @@
-use crate::PurgeableAllocError;
+use crate::{Backend, PurgePriority, PurgeableAllocError};
 use std::alloc::Layout;
 use std::ffi::c_void;
 use std::mem::ManuallyDrop;
@@ -16,8 +16,10 @@ pub(crate) struct SystemPurgeableBox<T: ?Sized> {
 }
 
 impl SystemPurgeableBox<[u8]> {
+    /// Regions can't be named on this platform, so `_label` is ignored
     pub(crate) fn new_uninit_with_layout(
         layout: Layout,
+        _label: Option<&str>,
     ) -> Result<SystemPurgeableBox<[u8]>, PurgeableAllocError> {
         super::check_alignment(layout);
         if layout.size() == 0 {
@@ -85,10 +87,11 @@ impl<T: ?Sized> SystemPurgeableBox<T> {
             )
         };
 
-        return !ret.is_null();
+        !ret.is_null()
     }
 
-    pub(crate) unsafe fn unlock(&self) {
+    /// `MEM_RESET` doesn't support any ordering, so `_priority` is ignored
+    pub(crate) unsafe fn unlock(&self, _priority: PurgePriority) {
         if self.size == 0 {
             return;
         }
@@ -102,6 +105,34 @@ impl<T: ?Sized> SystemPurgeableBox<T> {
         debug_assert!(!ret.is_null())
     }
 
+    /// Windows can't discard the pages on demand, so the next `lock` may succeed
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn force_purge(&mut self) -> bool {
+        false
+    }
+
+    /// Flips the bits of the last byte without the backend noticing, like a partial purge.
+    /// Returns `false` if the box is empty
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn corrupt(&mut self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let last = (self.ptr() as *mut u8).add(self.size - 1);
+        last.write(!last.read());
+        true
+    }
+
+    /// The state can't be checked without locking the box
+    pub(crate) fn purge_probe(&self) -> Option<PurgeProbe> {
+        None
+    }
+
+    #[inline]
+    pub(crate) fn backend(&self) -> Backend {
+        Backend::Windows
+    }
+
     pub(crate) fn ptr(&self) -> *mut T {
         self.ptr.as_ptr()
     }
@@ -114,10 +145,27 @@ impl<T: ?Sized> SystemPurgeableBox<T> {
         let s = ManuallyDrop::new(self);
         let ptr = s.ptr;
         let size = s.size;
-        return SystemPurgeableBox { ptr: f(ptr), size };
+        SystemPurgeableBox { ptr: f(ptr), size }
+    }
+}
+
+/// See [SystemPurgeableBox::purge_probe]
+pub(crate) enum PurgeProbe {}
+
+impl PurgeProbe {
+    /// # Safety
+    ///
+    /// The box must be alive and unlocked
+    pub(crate) unsafe fn is_purged(&self) -> bool {
+        match *self {}
     }
 }
 
+#[cfg(feature = "testing")]
+pub(crate) fn available_backends() -> Vec<Backend> {
+    vec![Backend::Windows]
+}
+
 pub fn is_available() -> bool {
     true
 }
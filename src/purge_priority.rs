@@ -0,0 +1,82 @@
+/// The order in which unlocked boxes are purged under memory pressure.
+///
+/// Boxes unlocked with a lower [level](PurgePriority::level) are purged before boxes with a
+/// higher level. Within the same level, boxes are purged in the FIFO order by default (the box
+/// that has been unlocked first is purged first); [PurgePriority::lifo] reverses it.
+///
+/// On macOS/iOS the level is mapped to a volatility group of `vm_purgable_control`.
+/// Backends that don't support ordering (`ashmem`, `MADV_FREE`, `memfd`, Windows) ignore the
+/// priority.
+///
+/// # Examples
+///
+/// ```
+/// use purgeable::{NonPurgeableBox, PurgePriority};
+///
+/// let npb = NonPurgeableBox::new(&1);
+/// let pb = NonPurgeableBox::unlock_with_priority(npb, PurgePriority::HIGHEST);
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct PurgePriority {
+    level: u8,
+    lifo: bool,
+}
+
+impl PurgePriority {
+    /// The maximum supported level
+    pub const MAX_LEVEL: u8 = 7;
+
+    /// Purged first
+    pub const LOWEST: PurgePriority = PurgePriority::new(0);
+
+    /// Purged last
+    pub const HIGHEST: PurgePriority = PurgePriority::new(Self::MAX_LEVEL);
+
+    /// Used by [NonPurgeableBox::unlock](crate::NonPurgeableBox::unlock)
+    pub const DEFAULT: PurgePriority = Self::LOWEST;
+
+    /// # Panics
+    ///
+    /// Panics if `level` is greater than [PurgePriority::MAX_LEVEL]
+    pub const fn new(level: u8) -> PurgePriority {
+        assert!(
+            level <= Self::MAX_LEVEL,
+            "PurgePriority level must not be greater than 7"
+        );
+        PurgePriority { level, lifo: false }
+    }
+
+    /// Returns the same priority, but boxes within the level are purged in the LIFO order
+    pub const fn lifo(self) -> PurgePriority {
+        PurgePriority { lifo: true, ..self }
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    pub fn is_lifo(&self) -> bool {
+        self.lifo
+    }
+
+    /// The key that orders unlocked boxes for purging: the box with the least key is purged
+    /// first. `unlock_seq` is the position of the unlock among all unlocks, e.g. a counter
+    /// incremented on every unlock.
+    ///
+    /// This is the order of the simulated backend; it allows modeling it elsewhere, e.g. when
+    /// replaying a trace.
+    pub fn purge_order(&self, unlock_seq: u64) -> impl Ord + Copy {
+        let seq = if self.lifo {
+            -(unlock_seq as i128)
+        } else {
+            unlock_seq as i128
+        };
+        (self.level, self.lifo, seq)
+    }
+}
+
+impl Default for PurgePriority {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
@@ -1,38 +1,148 @@
 use crate::error::PurgeableBoxLockError;
 use crate::non_purgeable_box::NonPurgeableBox;
 use crate::unsafe_purgeable_box::UnsafePurgeableBox;
+use crate::Backend;
+#[cfg(feature = "serde")]
+use std::cell::Cell;
+use std::cell::UnsafeCell;
 use std::fmt;
 
 pub struct PurgeableBox<T: ?Sized> {
-    // Invariant: `inner` is in the `UNLOCKED` state
-    inner: UnsafePurgeableBox<T>,
+    // Invariant: `inner` is in the `UNLOCKED` state, except while `transiently_locked` is set.
+    // `UnsafeCell` allows locking the box temporarily to serialize it by reference;
+    // `PurgeableBox` is not `Sync`, so only the serializing thread can observe it
+    inner: UnsafeCell<UnsafePurgeableBox<T>>,
+    /// Set during `with_transient_lock`, so a serialization of the same box from within it
+    /// fails instead of locking the box twice
+    #[cfg(feature = "serde")]
+    transiently_locked: Cell<bool>,
 }
 
 impl<T: ?Sized> PurgeableBox<T> {
     /// Safety: `pb` must be in the `UNLOCKED` state
     pub(crate) unsafe fn from_unlocked(pb: UnsafePurgeableBox<T>) -> PurgeableBox<T> {
         // SAFETY: the caller must guarantee that `pb` is in the `UNLOCKED` state
-        PurgeableBox { inner: pb }
+        PurgeableBox {
+            inner: UnsafeCell::new(pb),
+            #[cfg(feature = "serde")]
+            transiently_locked: Cell::new(false),
+        }
+    }
+
+    fn inner(&self) -> &UnsafePurgeableBox<T> {
+        // SAFETY: through `&self`, `inner` is mutated only by `with_transient_lock`, which
+        //  can't run concurrently because `PurgeableBox` is not `Sync`, and which holds no
+        //  mutable reference to `inner` while it calls back
+        unsafe { &*self.inner.get() }
+    }
+
+    /// Locks the box for the duration of `f`, see [UnsafePurgeableBox::with_transient_lock].
+    ///
+    /// # Panics
+    ///
+    /// If it is called from `f` for the same box
+    #[cfg(feature = "serde")]
+    pub(crate) fn with_transient_lock<R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        struct Reset<'a>(&'a Cell<bool>);
+
+        impl Drop for Reset<'_> {
+            fn drop(&mut self) {
+                self.0.set(false);
+            }
+        }
+
+        assert!(
+            !self.transiently_locked.replace(true),
+            "the PurgeableBox is already transiently locked"
+        );
+        let _reset = Reset(&self.transiently_locked);
+        // SAFETY: `PurgeableBox` guarantees that `inner` is in the `UNLOCKED` state, and it
+        //  is back in it when the call returns. `PurgeableBox` is not `Sync`, and the flag
+        //  rules out nested transient locks, so the box is only read through `&self` meanwhile
+        unsafe { UnsafePurgeableBox::with_transient_lock(self.inner.get(), f) }
     }
 
     pub fn lock(self) -> Result<NonPurgeableBox<T>, PurgeableBoxLockError> {
         // SAFETY: `PurgeableBox` guarantees that `self.inner` is in the `UNLOCKED` state
-        unsafe { NonPurgeableBox::try_from_unlocked(self.inner) }
+        unsafe { NonPurgeableBox::try_from_unlocked(self.inner.into_inner()) }
+    }
+
+    /// Locks the box or, if it has been purged, awaits `regen` for new content.
+    /// `regen` is polled only if the box has been purged, so it can read a file or a socket
+    /// without blocking the runtime. See [AsyncCache](crate::AsyncCache) for single-flight
+    /// regeneration of shared values
+    #[cfg(feature = "async")]
+    pub async fn lock_or_else_async(
+        self,
+        regen: impl std::future::Future<Output = NonPurgeableBox<T>>,
+    ) -> NonPurgeableBox<T> {
+        match self.lock() {
+            Ok(npb) => npb,
+            Err(_) => regen.await,
+        }
     }
 
-    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    #[cfg(any(target_os = "macos", target_os = "ios", purgeable_sim))]
     pub fn is_purged(&self) -> bool {
-        self.inner.is_purged()
+        self.inner().is_purged()
+    }
+
+    /// See [NonPurgeableBox::tag]
+    pub fn tag(&self) -> u64 {
+        self.inner().tag()
+    }
+
+    /// See [NonPurgeableBox::label]
+    pub fn label(&self) -> Option<&str> {
+        self.inner().label()
     }
 
     pub fn size(&self) -> usize {
-        self.inner.size()
+        self.inner().size()
+    }
+
+    /// See [NonPurgeableBox::backend]
+    pub fn backend(&self) -> Backend {
+        self.inner().backend()
+    }
+
+    /// See [testing::force_purge](crate::testing::force_purge)
+    #[cfg(feature = "testing")]
+    pub(crate) fn force_purge(&mut self) -> bool {
+        // SAFETY: `PurgeableBox` guarantees that `self.inner` is in the `UNLOCKED` state
+        unsafe { self.inner.get_mut().force_purge() }
+    }
+
+    /// See [testing::corrupt](crate::testing::corrupt)
+    #[cfg(feature = "testing")]
+    pub(crate) fn corrupt(&mut self) -> bool
+    where
+        T: crate::ByteContent,
+    {
+        // SAFETY: `PurgeableBox` guarantees that `self.inner` is in the `UNLOCKED` state
+        unsafe { self.inner.get_mut().corrupt() }
+    }
+
+    /// Returns how many bytes of the box are still present in physical memory, without
+    /// locking it. Note that the memory can be purged at any moment after the call,
+    /// so the result is only a hint.
+    ///
+    /// Implemented with `mincore`; its errors are returned as is
+    #[cfg(unix)]
+    pub fn resident_bytes(&self) -> std::io::Result<usize> {
+        self.inner().resident_bytes()
+    }
+
+    /// See [PurgeableBox::resident_bytes]
+    #[cfg(unix)]
+    pub fn resident_pages(&self) -> std::io::Result<usize> {
+        self.inner().resident_pages()
     }
 }
 
 impl<T: ?Sized> fmt::Pointer for PurgeableBox<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Pointer::fmt(&self.inner, f)
+        fmt::Pointer::fmt(self.inner(), f)
     }
 }
 
@@ -47,3 +157,96 @@ impl<T: ?Sized> From<NonPurgeableBox<T>> for PurgeableBox<T> {
         NonPurgeableBox::unlock(npb)
     }
 }
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use crate::unsafe_purgeable_box::UnsafePurgeableBox;
+    use crate::{NonPurgeableBox, PurgeableBox};
+    use serde::ser::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    impl<T: ?Sized> PurgeableBox<T> {
+        /// Serializes the content with `f` under a transient lock, or `None` if the box has
+        /// been purged. Fails if `f` reaches the box and serializes it again
+        pub(crate) fn serialize_with<S: Serializer>(
+            &self,
+            serializer: S,
+            f: impl FnOnce(&T, S) -> Result<S::Ok, S::Error>,
+        ) -> Result<S::Ok, S::Error> {
+            if self.transiently_locked.get() {
+                return Err(S::Error::custom(
+                    "the PurgeableBox is serialized from within its own serialization",
+                ));
+            }
+            let mut serializer = Some(serializer);
+            let result = self.with_transient_lock(|content| f(content, serializer.take().unwrap()));
+            match result {
+                Some(result) => result,
+                None => serializer.unwrap().serialize_none(),
+            }
+        }
+    }
+
+    /// Serializes as `Some(content)` or `None` if the box has been purged. The box is locked
+    /// during the serialization and unlocked with the same priority afterwards; serializing
+    /// the box again from within its own serialization fails.
+    /// A serialized `None` deserializes into a purged box that can't be locked
+    impl<T: Serialize + ?Sized> Serialize for PurgeableBox<T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.serialize_with(serializer, |content, serializer| {
+                serializer.serialize_some(content)
+            })
+        }
+    }
+
+    impl<'de, T: Deserialize<'de> + Copy> Deserialize<'de> for PurgeableBox<T> {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            Ok(
+                match Option::<NonPurgeableBox<T>>::deserialize(deserializer)? {
+                    Some(npb) => NonPurgeableBox::unlock(npb),
+                    // SAFETY: the purged box is in the `UNLOCKED` state
+                    None => unsafe {
+                        PurgeableBox::from_unlocked(UnsafePurgeableBox::<T>::new_purged())
+                    },
+                },
+            )
+        }
+    }
+
+    impl<'de, T: Deserialize<'de> + Copy> Deserialize<'de> for PurgeableBox<[T]> {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            Ok(
+                match Option::<NonPurgeableBox<[T]>>::deserialize(deserializer)? {
+                    Some(npb) => NonPurgeableBox::unlock(npb),
+                    // SAFETY: the purged box is in the `UNLOCKED` state
+                    None => unsafe {
+                        PurgeableBox::from_unlocked(UnsafePurgeableBox::<[T]>::new_purged())
+                    },
+                },
+            )
+        }
+    }
+
+    impl<'de> Deserialize<'de> for PurgeableBox<str> {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            Ok(
+                match Option::<NonPurgeableBox<str>>::deserialize(deserializer)? {
+                    Some(npb) => NonPurgeableBox::unlock(npb),
+                    // SAFETY: the purged box is in the `UNLOCKED` state
+                    None => unsafe {
+                        PurgeableBox::from_unlocked(UnsafePurgeableBox::<str>::new_purged())
+                    },
+                },
+            )
+        }
+    }
+}
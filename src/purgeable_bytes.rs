@@ -0,0 +1,260 @@
+//! Zero-copy conversions into [Bytes]; requires the `bytes` feature
+
+use crate::non_purgeable_box::handle_alloc_result;
+use crate::{NonPurgeableBox, PurgeableAllocError};
+use bytes::buf::UninitSlice;
+use bytes::{BufMut, Bytes};
+use std::mem::MaybeUninit;
+use std::{fmt, ops};
+
+/// The box stays locked while any clone of the [Bytes] is alive and is dropped with the last one
+impl From<NonPurgeableBox<[u8]>> for Bytes {
+    fn from(npb: NonPurgeableBox<[u8]>) -> Bytes {
+        Bytes::from_owner(npb)
+    }
+}
+
+impl NonPurgeableBox<[u8]> {
+    /// Converts the box into [Bytes] without copying. The box stays locked while any clone
+    /// of the `Bytes` is alive and is passed to `on_release` when the last one is dropped,
+    /// e.g. to unlock it and put it back into a cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use purgeable::{NonPurgeableBox, PurgeableBox};
+    /// use std::sync::mpsc;
+    ///
+    /// let (sender, receiver) = mpsc::channel::<PurgeableBox<[u8]>>();
+    /// let bytes = NonPurgeableBox::into_bytes_with(NonPurgeableBox::new_slice(b"payload"), move |npb| {
+    ///     let _ = sender.send(NonPurgeableBox::unlock(npb));
+    /// });
+    /// let clone = bytes.slice(1..);
+    /// drop(bytes);
+    /// assert_eq!(&clone[..], b"ayload");
+    /// drop(clone);
+    /// assert!(receiver.try_recv().is_ok());
+    /// ```
+    pub fn into_bytes_with(
+        this: Self,
+        on_release: impl FnOnce(NonPurgeableBox<[u8]>) + Send + 'static,
+    ) -> Bytes {
+        Bytes::from_owner(ReleasedOnDrop {
+            npb: Some(this),
+            on_release: Some(on_release),
+        })
+    }
+}
+
+struct ReleasedOnDrop<F: FnOnce(NonPurgeableBox<[u8]>)> {
+    npb: Option<NonPurgeableBox<[u8]>>,
+    on_release: Option<F>,
+}
+
+impl<F: FnOnce(NonPurgeableBox<[u8]>)> AsRef<[u8]> for ReleasedOnDrop<F> {
+    fn as_ref(&self) -> &[u8] {
+        self.npb.as_deref().unwrap()
+    }
+}
+
+impl<F: FnOnce(NonPurgeableBox<[u8]>)> Drop for ReleasedOnDrop<F> {
+    fn drop(&mut self) {
+        if let (Some(npb), Some(on_release)) = (self.npb.take(), self.on_release.take()) {
+            on_release(npb);
+        }
+    }
+}
+
+/// A growable buffer like [bytes::BytesMut] that writes directly into purgeable memory
+/// and is [frozen](PurgeableBytesMut::freeze) into [Bytes] without copying.
+///
+/// The capacity is rounded up to whole pages because the memory is allocated in pages anyway.
+///
+/// # Examples
+///
+/// ```
+/// use bytes::BufMut;
+/// use purgeable::PurgeableBytesMut;
+///
+/// let mut buf = PurgeableBytesMut::with_capacity(16);
+/// buf.put_slice(b"hello ");
+/// buf.put_u8(b'w');
+/// buf.extend_from_slice(b"orld");
+/// let bytes = buf.freeze();
+/// assert_eq!(&bytes[..], b"hello world");
+/// ```
+pub struct PurgeableBytesMut {
+    // Invariant: `buf[..len]` is initialized
+    buf: NonPurgeableBox<[MaybeUninit<u8>]>,
+    len: usize,
+}
+
+impl PurgeableBytesMut {
+    /// An empty buffer with no capacity
+    pub fn new() -> PurgeableBytesMut {
+        PurgeableBytesMut::with_capacity(0)
+    }
+
+    pub fn with_capacity(capacity: usize) -> PurgeableBytesMut {
+        PurgeableBytesMut::from_uninit(handle_alloc_result(NonPurgeableBox::try_new_uninit_slice(
+            round_to_pages(capacity),
+        )))
+    }
+
+    /// Like [PurgeableBytesMut::with_capacity], but the memory region is named with the `label`,
+    /// if any, also after it has grown. See [NonPurgeableBox::label]
+    pub fn with_capacity_and_label(capacity: usize, label: Option<&str>) -> PurgeableBytesMut {
+        PurgeableBytesMut::from_uninit(handle_alloc_result(
+            NonPurgeableBox::try_new_uninit_slice_with_label(round_to_pages(capacity), label),
+        ))
+    }
+
+    pub fn try_with_capacity(capacity: usize) -> Result<PurgeableBytesMut, PurgeableAllocError> {
+        NonPurgeableBox::try_new_uninit_slice(round_to_pages(capacity))
+            .map(PurgeableBytesMut::from_uninit)
+    }
+
+    fn from_uninit(buf: NonPurgeableBox<[MaybeUninit<u8>]>) -> PurgeableBytesMut {
+        PurgeableBytesMut { buf, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Ensures that at least `additional` more bytes can be written without reallocating.
+    /// Growing copies the content into a new purgeable region
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocation fails
+    pub fn reserve(&mut self, additional: usize) {
+        if let Err(e) = self.try_reserve(additional) {
+            panic!(
+                "PurgeableBytesMut memory allocation of {} bytes failed",
+                e.layout.size()
+            )
+        }
+    }
+
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), PurgeableAllocError> {
+        let required = self.len.checked_add(additional).expect("capacity overflow");
+        if required <= self.capacity() {
+            return Ok(());
+        }
+        let capacity = round_to_pages(required.max(self.capacity() * 2));
+        let label = NonPurgeableBox::label(&self.buf);
+        let mut buf = NonPurgeableBox::try_new_uninit_slice_with_label(capacity, label)?;
+        buf[..self.len].copy_from_slice(&self.buf[..self.len]);
+        self.buf = buf;
+        Ok(())
+    }
+
+    pub fn extend_from_slice(&mut self, src: &[u8]) {
+        self.put_slice(src);
+    }
+
+    /// Converts the buffer into [Bytes] without copying; the memory stays locked while any
+    /// clone of the `Bytes` is alive
+    pub fn freeze(self) -> Bytes {
+        if self.len == self.capacity() {
+            Bytes::from(self.into_box())
+        } else {
+            Bytes::from_owner(self)
+        }
+    }
+
+    /// Converts the buffer into a box. Copies the content unless the buffer is full
+    pub fn into_box(self) -> NonPurgeableBox<[u8]> {
+        if self.len == self.capacity() {
+            // SAFETY: the whole buffer is initialized
+            unsafe { self.buf.assume_init() }
+        } else {
+            NonPurgeableBox::new_slice_with_label(&self, NonPurgeableBox::label(&self.buf))
+        }
+    }
+}
+
+fn round_to_pages(size: usize) -> usize {
+    size.next_multiple_of(page_size::get())
+}
+
+impl Default for PurgeableBytesMut {
+    fn default() -> PurgeableBytesMut {
+        PurgeableBytesMut::new()
+    }
+}
+
+impl ops::Deref for PurgeableBytesMut {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the first `len` bytes are initialized
+        unsafe { std::slice::from_raw_parts(self.buf.as_ptr().cast(), self.len) }
+    }
+}
+
+impl ops::DerefMut for PurgeableBytesMut {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: the first `len` bytes are initialized
+        unsafe { std::slice::from_raw_parts_mut(self.buf.as_mut_ptr().cast(), self.len) }
+    }
+}
+
+impl AsRef<[u8]> for PurgeableBytesMut {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for PurgeableBytesMut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PurgeableBytesMut")
+            .field("len", &self.len)
+            .field("capacity", &self.capacity())
+            .finish()
+    }
+}
+
+// SAFETY: `chunk_mut` returns only the uninitialized tail and `advance_mut` is called for the
+// bytes written to it
+unsafe impl BufMut for PurgeableBytesMut {
+    fn remaining_mut(&self) -> usize {
+        isize::MAX as usize - self.len
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        assert!(
+            cnt <= self.capacity() - self.len,
+            "cannot advance past the capacity"
+        );
+        self.len += cnt;
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        if self.len == self.capacity() {
+            self.reserve(64);
+        }
+        UninitSlice::uninit(&mut self.buf[self.len..])
+    }
+
+    fn put_slice(&mut self, src: &[u8]) {
+        self.reserve(src.len());
+        // SAFETY: &[u8] and &[MaybeUninit<u8>] have the same layout
+        let uninit_src: &[MaybeUninit<u8>] = unsafe { std::mem::transmute(src) };
+        self.buf[self.len..self.len + src.len()].copy_from_slice(uninit_src);
+        self.len += src.len();
+    }
+}
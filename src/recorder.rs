@@ -0,0 +1,341 @@
+//! Records allocations, locks and unlocks of all boxes into a compact binary trace, so
+//! a workload can be replayed against a simulated backend with `purgeable-replay`.
+//!
+//! The trace starts with [MAGIC] and a version byte, followed by records of a kind byte and
+//! LEB128 varints: the time since the previous record in microseconds, the box id and
+//! the kind-specific payload (size and backend code for allocations, priority for unlocks).
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use purgeable::recorder::{self, TraceReader};
+//! use std::fs::File;
+//! use std::io::BufReader;
+//!
+//! let recording = recorder::record(File::create("app.trace")?)?;
+//! // ... run the workload
+//! recording.finish()?;
+//!
+//! for event in TraceReader::new(BufReader::new(File::open("app.trace")?))? {
+//!     println!("{:?}", event?);
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::{Backend, PurgePriority};
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const MAGIC: &[u8; 8] = b"PGTRACE\0";
+const VERSION: u8 = 1;
+
+const ALLOCATED: u8 = 0;
+const LOCKED: u8 = 1;
+const LOCK_FAILED: u8 = 2;
+const UNLOCKED: u8 = 3;
+const DROPPED: u8 = 4;
+
+#[non_exhaustive]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TraceEvent {
+    /// The time since the recording start
+    pub time: Duration,
+    /// A process-wide unique id of the box
+    pub box_id: u64,
+    pub kind: TraceEventKind,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceEventKind {
+    Allocated { size: usize, backend: Backend },
+    Locked { success: bool },
+    Unlocked { priority: PurgePriority },
+    Dropped,
+}
+
+/// Starts recording events of all boxes into the `writer`.
+///
+/// Only one recording can be active at a time; [io::ErrorKind::AlreadyExists] is returned
+/// otherwise. The recording stops when the returned [Recording] is dropped or finished.
+///
+/// The trace is written by a background thread, so the `writer` never runs while a box is
+/// being recorded, and it may use purgeable boxes itself; their events are recorded too.
+/// Records are queued without a limit if the writer is slower than the workload
+pub fn record(writer: impl Write + Send + 'static) -> io::Result<Recording> {
+    let mut recorder = recorder();
+    if recorder.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "a recording is already active",
+        ));
+    }
+
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+    let thread = thread::Builder::new()
+        .name("purgeable-recorder".to_owned())
+        .spawn(move || {
+            let mut writer = BufWriter::new(writer);
+            writer.write_all(MAGIC)?;
+            writer.write_all(&[VERSION])?;
+            for record in receiver {
+                writer.write_all(&record)?;
+            }
+            writer.flush()
+        })?;
+    *recorder = Some(Recorder {
+        sender,
+        thread,
+        start: Instant::now(),
+        last_time: 0,
+    });
+    RECORDING.store(true, Ordering::Release);
+    Ok(Recording { _private: () })
+}
+
+/// An active recording, see [record]
+#[must_use = "the recording stops when it is dropped"]
+#[derive(Debug)]
+pub struct Recording {
+    _private: (),
+}
+
+impl Recording {
+    /// Stops the recording and flushes the trace.
+    /// Returns the first error that has occurred while writing the trace
+    pub fn finish(self) -> io::Result<()> {
+        stop()
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let _ = stop();
+    }
+}
+
+fn stop() -> io::Result<()> {
+    RECORDING.store(false, Ordering::Release);
+    let recorder = recorder().take();
+    match recorder {
+        Some(Recorder { sender, thread, .. }) => {
+            // The writer thread flushes the trace once the queue is closed and drained
+            drop(sender);
+            thread
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("the trace writer has panicked")))
+        }
+        None => Ok(()),
+    }
+}
+
+struct Recorder {
+    /// Queues encoded records for the writer thread, which stops at the first error
+    sender: mpsc::Sender<Vec<u8>>,
+    thread: JoinHandle<io::Result<()>>,
+    start: Instant,
+    /// Microseconds since `start` of the last record
+    last_time: u64,
+}
+
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+/// Allows skipping the `RECORDER` lock when nothing is recorded
+static RECORDING: AtomicBool = AtomicBool::new(false);
+static NEXT_BOX_ID: AtomicU64 = AtomicU64::new(0);
+
+fn recorder() -> MutexGuard<'static, Option<Recorder>> {
+    RECORDER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn next_box_id() -> u64 {
+    NEXT_BOX_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[inline]
+fn write(kind: u8, box_id: u64, payload: impl FnOnce(&mut Vec<u8>)) {
+    if RECORDING.load(Ordering::Acquire) {
+        write_slow(kind, box_id, payload);
+    }
+}
+
+#[cold]
+fn write_slow(kind: u8, box_id: u64, payload: impl FnOnce(&mut Vec<u8>)) {
+    let mut recorder = recorder();
+    let Some(recorder) = recorder.as_mut() else {
+        return;
+    };
+
+    // The time is taken under the lock, so records are ordered by time
+    let time = recorder.start.elapsed().as_micros() as u64;
+    let mut record = vec![kind];
+    write_varint(&mut record, time - recorder.last_time);
+    write_varint(&mut record, box_id);
+    payload(&mut record);
+    recorder.last_time = time;
+    // Fails only if the writer thread has stopped at an error, which `stop` returns
+    let _ = recorder.sender.send(record);
+}
+
+pub(crate) fn allocated(box_id: u64, size: usize, backend: Backend) {
+    write(ALLOCATED, box_id, |record| {
+        write_varint(record, size as u64);
+        record.push(backend_code(backend));
+    });
+}
+
+pub(crate) fn locked(box_id: u64, success: bool) {
+    let kind = if success { LOCKED } else { LOCK_FAILED };
+    write(kind, box_id, |_| {});
+}
+
+pub(crate) fn unlocked(box_id: u64, priority: PurgePriority) {
+    write(UNLOCKED, box_id, |record| {
+        record.push(priority.level() | (priority.is_lifo() as u8) << 7);
+    });
+}
+
+pub(crate) fn dropped(box_id: u64) {
+    write(DROPPED, box_id, |_| {});
+}
+
+/// Backend codes are a part of the trace format, so they must never change
+fn backend_code(backend: Backend) -> u8 {
+    match backend {
+        Backend::Mach => 0,
+        Backend::Ashmem => 1,
+        Backend::MadvFree => 2,
+        Backend::Windows => 3,
+        Backend::Sim => 4,
+        Backend::Memfd => 5,
+    }
+}
+
+fn backend_from_code(code: u8) -> Option<Backend> {
+    match code {
+        0 => Some(Backend::Mach),
+        1 => Some(Backend::Ashmem),
+        2 => Some(Backend::MadvFree),
+        3 => Some(Backend::Windows),
+        4 => Some(Backend::Sim),
+        5 => Some(Backend::Memfd),
+        _ => None,
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Reads [TraceEvent]s from a trace written by [record]. The iteration ends after the first
+/// error, as nothing after a truncated or corrupted record can be decoded
+pub struct TraceReader<R> {
+    reader: R,
+    time: Duration,
+    failed: bool,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Checks the trace header
+    pub fn new(mut reader: R) -> io::Result<TraceReader<R>> {
+        let mut header = [0; MAGIC.len() + 1];
+        reader.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(invalid_data("not a purgeable trace"));
+        }
+        if header[MAGIC.len()] != VERSION {
+            return Err(invalid_data("unsupported trace version"));
+        }
+        Ok(TraceReader {
+            reader,
+            time: Duration::ZERO,
+            failed: false,
+        })
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.reader.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_data("varint is too long"))
+    }
+
+    /// Returns `None` at the end of the trace
+    fn read_event(&mut self) -> io::Result<Option<TraceEvent>> {
+        let mut kind = [0];
+        loop {
+            match self.reader.read(&mut kind) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let delta = self.read_varint()?;
+        self.time += Duration::from_micros(delta);
+        let box_id = self.read_varint()?;
+        let kind = match kind[0] {
+            ALLOCATED => {
+                let size = self.read_varint()? as usize;
+                let backend = backend_from_code(self.read_byte()?)
+                    .ok_or_else(|| invalid_data("unknown backend"))?;
+                TraceEventKind::Allocated { size, backend }
+            }
+            LOCKED => TraceEventKind::Locked { success: true },
+            LOCK_FAILED => TraceEventKind::Locked { success: false },
+            UNLOCKED => {
+                let byte = self.read_byte()?;
+                let level = byte & 0x7f;
+                if level > PurgePriority::MAX_LEVEL {
+                    return Err(invalid_data("invalid priority"));
+                }
+                let mut priority = PurgePriority::new(level);
+                if byte & 0x80 != 0 {
+                    priority = priority.lifo();
+                }
+                TraceEventKind::Unlocked { priority }
+            }
+            DROPPED => TraceEventKind::Dropped,
+            _ => return Err(invalid_data("unknown record kind")),
+        };
+        Ok(Some(TraceEvent {
+            time: self.time,
+            box_id,
+            kind,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceEvent>;
+
+    fn next(&mut self) -> Option<io::Result<TraceEvent>> {
+        if self.failed {
+            return None;
+        }
+        let event = self.read_event().transpose();
+        self.failed = matches!(event, Some(Err(_)));
+        event
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,136 @@
+//! The registry of live boxes; every hook is a no-op if the `debug-registry` feature is disabled
+
+#[cfg(not(feature = "debug-registry"))]
+use crate::Backend;
+
+#[cfg(feature = "debug-registry")]
+pub use enabled::*;
+
+#[cfg(not(feature = "debug-registry"))]
+pub(crate) struct RegistryId;
+
+#[cfg(not(feature = "debug-registry"))]
+#[inline(always)]
+pub(crate) fn register(_size: usize, _backend: Backend, _label: Option<&str>) -> RegistryId {
+    RegistryId
+}
+
+#[cfg(not(feature = "debug-registry"))]
+#[inline(always)]
+pub(crate) fn set_state(_id: &RegistryId, _state: u8) {}
+
+#[cfg(not(feature = "debug-registry"))]
+#[inline(always)]
+pub(crate) fn unregister(_id: &RegistryId) {}
+
+#[cfg(feature = "debug-registry")]
+mod enabled {
+    use crate::stats::{LOCKED_STATE, UNLOCKED_STATE};
+    use crate::Backend;
+    use std::backtrace::Backtrace;
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    /// A live box captured by [live_allocations]
+    #[non_exhaustive]
+    #[derive(Clone, Debug)]
+    pub struct LiveAllocation {
+        pub size: usize,
+        pub label: Option<String>,
+        pub backend: Backend,
+        pub state: BoxState,
+        /// Where the box has been allocated
+        pub backtrace: Arc<Backtrace>,
+    }
+
+    impl fmt::Display for LiveAllocation {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(
+                f,
+                "{} bytes ({}, {:?}, label: {}) allocated at:",
+                self.size,
+                self.backend,
+                self.state,
+                self.label.as_deref().unwrap_or("<none>")
+            )?;
+            write!(f, "{}", self.backtrace)
+        }
+    }
+
+    #[non_exhaustive]
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+    pub enum BoxState {
+        Locked,
+        Unlocked,
+        /// The box is known to be purged
+        Purged,
+    }
+
+    impl BoxState {
+        fn from_raw(state: u8) -> BoxState {
+            match state {
+                LOCKED_STATE => BoxState::Locked,
+                UNLOCKED_STATE => BoxState::Unlocked,
+                _ => BoxState::Purged,
+            }
+        }
+    }
+
+    /// Returns a snapshot of all live boxes in the allocation order
+    pub fn live_allocations() -> Vec<LiveAllocation> {
+        registry().values().cloned().collect()
+    }
+
+    /// Prints all live boxes with their allocation backtraces to `stderr`.
+    ///
+    /// Backtraces are always captured regardless of `RUST_BACKTRACE`
+    pub fn dump_live() {
+        let allocations = live_allocations();
+        let total: usize = allocations.iter().map(|it| it.size).sum();
+        let mut stderr = std::io::stderr().lock();
+        let _ = writeln!(
+            stderr,
+            "{} live purgeable boxes, {} bytes total",
+            allocations.len(),
+            total
+        );
+        for allocation in allocations {
+            let _ = writeln!(stderr, "{}", allocation);
+        }
+    }
+
+    pub(crate) struct RegistryId(u64);
+
+    static REGISTRY: Mutex<BTreeMap<u64, LiveAllocation>> = Mutex::new(BTreeMap::new());
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    fn registry() -> MutexGuard<'static, BTreeMap<u64, LiveAllocation>> {
+        REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn register(size: usize, backend: Backend, label: Option<&str>) -> RegistryId {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let allocation = LiveAllocation {
+            size,
+            label: label.map(String::from),
+            backend,
+            state: BoxState::Locked,
+            backtrace: Arc::new(Backtrace::force_capture()),
+        };
+        registry().insert(id, allocation);
+        RegistryId(id)
+    }
+
+    pub(crate) fn set_state(id: &RegistryId, state: u8) {
+        if let Some(allocation) = registry().get_mut(&id.0) {
+            allocation.state = BoxState::from_raw(state);
+        }
+    }
+
+    pub(crate) fn unregister(id: &RegistryId) {
+        registry().remove(&id.0);
+    }
+}
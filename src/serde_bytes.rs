@@ -0,0 +1,127 @@
+//! Serializes byte boxes as byte strings instead of sequences of numbers, which is much more
+//! compact and faster in binary formats. Use it with `#[serde(with = "purgeable::serde_bytes")]`:
+//!
+//! ```
+//! use purgeable::{NonPurgeableBox, PurgeableBox};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Cache {
+//!     #[serde(with = "purgeable::serde_bytes")]
+//!     thumbnail: NonPurgeableBox<[u8]>,
+//!     /// `None` in the serialized form if it has been purged
+//!     #[serde(with = "purgeable::serde_bytes")]
+//!     image: PurgeableBox<[u8]>,
+//! }
+//! ```
+
+use crate::unsafe_purgeable_box::UnsafePurgeableBox;
+use crate::{NonPurgeableBox, PurgeableBox};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserializer, Serializer};
+use std::fmt;
+
+pub fn serialize<T, S>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: SerializeBytes + ?Sized,
+    S: Serializer,
+{
+    bytes.serialize_bytes(serializer)
+}
+
+pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: DeserializeBytes,
+    D: Deserializer<'de>,
+{
+    T::deserialize_bytes(deserializer)
+}
+
+/// Types supported by [serialize]
+pub trait SerializeBytes {
+    fn serialize_bytes<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+}
+
+/// Types supported by [deserialize]
+pub trait DeserializeBytes: Sized {
+    fn deserialize_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>;
+}
+
+impl SerializeBytes for NonPurgeableBox<[u8]> {
+    fn serialize_bytes<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self)
+    }
+}
+
+impl SerializeBytes for PurgeableBox<[u8]> {
+    fn serialize_bytes<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.serialize_with(serializer, |bytes, serializer| {
+            serializer.serialize_some(&Bytes(bytes))
+        })
+    }
+}
+
+impl DeserializeBytes for NonPurgeableBox<[u8]> {
+    fn deserialize_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+impl DeserializeBytes for PurgeableBox<[u8]> {
+    fn deserialize_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_option(OptionVisitor)
+    }
+}
+
+struct Bytes<'a>(&'a [u8]);
+
+impl serde::Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = NonPurgeableBox<[u8]>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a byte array")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(NonPurgeableBox::new_slice(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(NonPurgeableBox::new_slice(v.as_bytes()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(1 << 20));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(NonPurgeableBox::new_slice(&bytes))
+    }
+}
+
+struct OptionVisitor;
+
+impl<'de> Visitor<'de> for OptionVisitor {
+    type Value = PurgeableBox<[u8]>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("an optional byte array")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        // SAFETY: the purged box is in the `UNLOCKED` state
+        Ok(unsafe { PurgeableBox::from_unlocked(UnsafePurgeableBox::<[u8]>::new_purged()) })
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        NonPurgeableBox::deserialize_bytes(deserializer).map(NonPurgeableBox::unlock)
+    }
+}
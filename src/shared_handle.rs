@@ -0,0 +1,211 @@
+use std::io;
+use std::mem::{self, size_of};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+/// A purgeable memory region that can be mapped by other processes; Linux and Android only.
+///
+/// The region is an `ashmem` region if `ashmem` is available. Otherwise it is a
+/// [memfd](crate::Backend::Memfd) region, which the kernel never purges.
+///
+/// A producer converts a box with [NonPurgeableBox::into_shared_handle] and sends the handle
+/// over a Unix socket with [SharedHandle::send]; a consumer receives it with
+/// [SharedHandle::recv] and maps it with [NonPurgeableBox::from_shared_handle].
+///
+/// All processes share the same pages and pin state: writes through one box are seen by
+/// every other box of the region, and the region is unpinned as soon as any of them unlocks
+/// its box, even if the others still hold theirs locked. The compiler can't check that, so
+/// mapping a handle is `unsafe`, and processes have to agree on who writes and who unlocks
+/// the region, e.g. the producer doesn't touch it after sending the handle, and a single
+/// consumer unlocks it. See [NonPurgeableBox::from_shared_handle] for the exact contract.
+///
+/// # Examples
+///
+/// ```no_run
+/// use purgeable::{NonPurgeableBox, SharedHandle};
+/// use std::os::unix::net::UnixStream;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let (producer, consumer) = UnixStream::pair()?;
+///
+/// let npb = NonPurgeableBox::new_slice(b"generated data");
+/// NonPurgeableBox::into_shared_handle(npb)?.send(&producer)?;
+///
+/// // SAFETY: the producer has given up its box, and this is the only consumer
+/// let npb = unsafe { NonPurgeableBox::from_shared_handle(SharedHandle::recv(&consumer)?)? };
+/// assert_eq!(&*npb, b"generated data");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [NonPurgeableBox::into_shared_handle]: crate::NonPurgeableBox::into_shared_handle
+/// [NonPurgeableBox::from_shared_handle]: crate::NonPurgeableBox::from_shared_handle
+#[derive(Debug)]
+pub struct SharedHandle {
+    fd: OwnedFd,
+    len: usize,
+}
+
+impl SharedHandle {
+    /// A handle of the first `len` bytes of the region `fd`, e.g. from
+    /// [NonPurgeableBox::export_fd](crate::NonPurgeableBox::export_fd). The region is
+    /// validated when it is mapped
+    ///
+    /// # Safety
+    ///
+    /// Whoever maps the handle relies on its creator for the sharing protocol: the region
+    /// must be written and unlocked only as the contract of
+    /// [NonPurgeableBox::from_shared_handle](crate::NonPurgeableBox::from_shared_handle)
+    /// allows for as long as the handle or boxes mapped from it are alive
+    pub unsafe fn new(fd: OwnedFd, len: usize) -> SharedHandle {
+        SharedHandle { fd, len }
+    }
+
+    /// The length of the box in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn into_parts(self) -> (OwnedFd, usize) {
+        (self.fd, self.len)
+    }
+
+    /// Sends the handle over the `socket` passing the file descriptor with `SCM_RIGHTS`
+    pub fn send(&self, socket: &UnixStream) -> io::Result<()> {
+        let payload = (self.len as u64).to_ne_bytes();
+        let mut iov = libc::iovec {
+            iov_base: payload.as_ptr() as *mut _,
+            iov_len: payload.len(),
+        };
+        let mut control = Control::new();
+        // SAFETY: `msghdr` is a plain C struct
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.buf.as_mut_ptr().cast();
+        msg.msg_controllen = Control::space() as _;
+        // SAFETY: the control buffer is large enough and aligned for one header with one fd
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, self.fd.as_raw_fd());
+        }
+
+        let sent = retry_interrupted(|| unsafe {
+            libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+        })?;
+        if sent != payload.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "failed to send the whole handle",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Receives a handle sent with [SharedHandle::send]. The file descriptor is received
+    /// with `O_CLOEXEC`. The handle is only as trustworthy as the peer that has sent it,
+    /// which is why mapping it is `unsafe`
+    pub fn recv(socket: &UnixStream) -> io::Result<SharedHandle> {
+        let mut payload = [0u8; size_of::<u64>()];
+        let mut iov = libc::iovec {
+            iov_base: payload.as_mut_ptr().cast(),
+            iov_len: payload.len(),
+        };
+        let mut control = Control::new();
+        // SAFETY: `msghdr` is a plain C struct
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.buf.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(&control.buf) as _;
+
+        let received = retry_interrupted(|| unsafe {
+            libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC)
+        })?;
+
+        // Take ownership of every received fd first, so they are closed on errors
+        let mut fds = Vec::new();
+        // SAFETY: the kernel has filled the control buffer with valid headers
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                        / size_of::<RawFd>();
+                    for i in 0..count {
+                        fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+
+        if received == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 || received != payload.len() || fds.len() != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the message is not a shared handle",
+            ));
+        }
+        let len = u64::from_ne_bytes(payload)
+            .try_into()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(SharedHandle {
+            fd: fds.pop().unwrap(),
+            len,
+        })
+    }
+}
+
+impl AsFd for SharedHandle {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl From<SharedHandle> for OwnedFd {
+    fn from(handle: SharedHandle) -> OwnedFd {
+        handle.fd
+    }
+}
+
+/// A control message buffer for one fd, aligned like `cmsghdr`
+struct Control {
+    buf: [u64; 4],
+}
+
+impl Control {
+    fn new() -> Control {
+        debug_assert!(Control::space() <= size_of::<Control>());
+        Control { buf: [0; 4] }
+    }
+
+    fn space() -> usize {
+        // SAFETY: a pure size computation
+        unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as u32) as usize }
+    }
+}
+
+fn retry_interrupted(mut f: impl FnMut() -> isize) -> io::Result<usize> {
+    loop {
+        let ret = f();
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
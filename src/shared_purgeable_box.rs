@@ -0,0 +1,234 @@
+use crate::{NonPurgeableBox, PurgePriority, PurgeableBox};
+use std::marker::PhantomData;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+use std::{fmt, ops};
+
+/// A purgeable value shared between threads that is regenerated by only one of them when it
+/// has been purged.
+///
+/// The first thread that finds the value missing or purged runs its `regen` closure while the
+/// others wait and then read the fresh value. Readers share the locked value; it is unlocked
+/// when the last [SharedGuard] is dropped. If `regen` panics or fails, one of the waiting
+/// threads regenerates the value instead.
+///
+/// # Examples
+///
+/// ```
+/// use purgeable::{NonPurgeableBox, SharedPurgeableBox};
+/// use std::thread;
+///
+/// let shared = SharedPurgeableBox::<[u8]>::new();
+/// thread::scope(|scope| {
+///     for _ in 0..4 {
+///         scope.spawn(|| {
+///             let value = shared.get_or_regenerate(|| NonPurgeableBox::new_filled_slice(1, 4096));
+///             assert_eq!(value.len(), 4096);
+///         });
+///     }
+/// });
+/// ```
+pub struct SharedPurgeableBox<T: ?Sized> {
+    state: Mutex<State<T>>,
+    changed: Condvar,
+    priority: PurgePriority,
+    // Readers on different threads share `&T`
+    _marker: PhantomData<T>,
+}
+
+enum State<T: ?Sized> {
+    Empty,
+    Unlocked(PurgeableBox<T>),
+    Locked {
+        npb: NonPurgeableBox<T>,
+        readers: usize,
+    },
+    /// By the thread, so a reentrant call panics instead of waiting for itself
+    Regenerating(ThreadId),
+}
+
+impl<T: ?Sized> SharedPurgeableBox<T> {
+    /// An empty box; the first reader generates the value
+    pub fn new() -> SharedPurgeableBox<T> {
+        SharedPurgeableBox::with_priority(PurgePriority::DEFAULT)
+    }
+
+    /// Like [SharedPurgeableBox::new], but the value is unlocked with the `priority`,
+    /// see [PurgePriority]
+    pub fn with_priority(priority: PurgePriority) -> SharedPurgeableBox<T> {
+        SharedPurgeableBox {
+            state: Mutex::new(State::Empty),
+            changed: Condvar::new(),
+            priority,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the locked value, or regenerates it with `regen` if it is missing or has been
+    /// purged and no other thread is regenerating it already
+    ///
+    /// # Panics
+    ///
+    /// If it is called from `regen` for the same box: the call would wait for itself
+    ///
+    /// # Deadlocks
+    ///
+    /// If `regen` waits for a box whose regeneration waits for this one, e.g. two boxes
+    /// regenerated from each other on two threads
+    pub fn get_or_regenerate(
+        &self,
+        regen: impl FnOnce() -> NonPurgeableBox<T>,
+    ) -> SharedGuard<'_, T> {
+        match self.get_or_try_regenerate(|| Ok::<_, std::convert::Infallible>(regen())) {
+            Ok(guard) => guard,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Like [SharedPurgeableBox::get_or_regenerate], but the regeneration can fail. The error
+    /// is returned to the thread that has run `regen` only; a waiting thread tries its own.
+    /// Panics and deadlocks like [SharedPurgeableBox::get_or_regenerate]
+    pub fn get_or_try_regenerate<E>(
+        &self,
+        regen: impl FnOnce() -> Result<NonPurgeableBox<T>, E>,
+    ) -> Result<SharedGuard<'_, T>, E> {
+        let mut state = self.state();
+        loop {
+            match std::mem::replace(&mut *state, State::Empty) {
+                State::Locked { npb, readers } => {
+                    *state = State::Locked {
+                        npb,
+                        readers: readers + 1,
+                    };
+                    return Ok(self.guard(&state));
+                }
+                State::Unlocked(pb) => {
+                    if let Ok(npb) = pb.lock() {
+                        *state = State::Locked { npb, readers: 1 };
+                        return Ok(self.guard(&state));
+                    }
+                    break;
+                }
+                State::Empty => break,
+                State::Regenerating(thread) => {
+                    *state = State::Regenerating(thread);
+                    assert!(
+                        thread != thread::current().id(),
+                        "the SharedPurgeableBox is read from its own regeneration"
+                    );
+                    state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+            }
+        }
+        *state = State::Regenerating(thread::current().id());
+        drop(state);
+
+        // Resets the state if `regen` panics or fails, so a waiting thread takes over
+        struct Abandon<'a, T: ?Sized>(&'a SharedPurgeableBox<T>);
+
+        impl<T: ?Sized> Drop for Abandon<'_, T> {
+            fn drop(&mut self) {
+                *self.0.state() = State::Empty;
+                self.0.changed.notify_one();
+            }
+        }
+
+        let abandon = Abandon(self);
+        let npb = regen()?;
+        std::mem::forget(abandon);
+
+        let mut state = self.state();
+        *state = State::Locked { npb, readers: 1 };
+        self.changed.notify_all();
+        Ok(self.guard(&state))
+    }
+
+    /// Purges the value if it is unlocked, see [crate::testing::force_purge]
+    #[cfg(all(test, feature = "testing"))]
+    pub(crate) fn force_purge(&self) -> bool {
+        match &mut *self.state() {
+            State::Unlocked(pb) => crate::testing::force_purge(pb),
+            _ => false,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State<T>> {
+        // The state is consistent at any panic point
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn guard(&self, state: &State<T>) -> SharedGuard<'_, T> {
+        let State::Locked { npb, .. } = state else {
+            unreachable!()
+        };
+        SharedGuard {
+            shared: self,
+            // The content doesn't move while the box is locked
+            content: &**npb as *const T,
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state();
+        if let State::Locked { readers, .. } = &mut *state {
+            *readers -= 1;
+            if *readers == 0 {
+                if let State::Locked { npb, .. } = std::mem::replace(&mut *state, State::Empty) {
+                    *state =
+                        State::Unlocked(NonPurgeableBox::unlock_with_priority(npb, self.priority));
+                }
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> Default for SharedPurgeableBox<T> {
+    fn default() -> SharedPurgeableBox<T> {
+        SharedPurgeableBox::new()
+    }
+}
+
+impl<T: ?Sized> From<PurgeableBox<T>> for SharedPurgeableBox<T> {
+    fn from(pb: PurgeableBox<T>) -> SharedPurgeableBox<T> {
+        let shared = SharedPurgeableBox::new();
+        *shared.state() = State::Unlocked(pb);
+        shared
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for SharedPurgeableBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedPurgeableBox")
+    }
+}
+
+/// Shared access to the locked value of a [SharedPurgeableBox]
+pub struct SharedGuard<'a, T: ?Sized> {
+    shared: &'a SharedPurgeableBox<T>,
+    content: *const T,
+}
+
+// SAFETY: the guard gives only shared access to `T`, like `&SharedPurgeableBox<T>` does
+unsafe impl<T: Send + Sync + ?Sized> Send for SharedGuard<'_, T> {}
+unsafe impl<T: Send + Sync + ?Sized> Sync for SharedGuard<'_, T> {}
+
+impl<T: ?Sized> ops::Deref for SharedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the box stays locked and in place while it has readers
+        unsafe { &*self.content }
+    }
+}
+
+impl<T: ?Sized> Drop for SharedGuard<'_, T> {
+    fn drop(&mut self) {
+        self.shared.release();
+    }
+}
+
+impl<T: fmt::Debug + ?Sized> fmt::Debug for SharedGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,597 @@
+//! A disk tier for purgeable byte buffers: unlocked boxes are written to spill files in the
+//! background, and a lock that finds the memory purged reloads the content from the file.
+//!
+//! This turns "purged" into "slower" for data that is expensive to regenerate but cheap to
+//! read back. A spill file starts with [MAGIC], the content length and a checksum of the
+//! content as little-endian `u64`s; files that don't match are never reloaded. A file is
+//! removed when its box is locked or dropped, and files left by processes that have exited
+//! are removed by [SpillDir::new].
+//!
+//! Spilling is opt-in wrapping, not a property of the box: only [SpilledBox::lock] reloads
+//! the content. A [PurgeableBox] unlocked with [NonPurgeableBox::unlock] is never spilled,
+//! and the reload needs the [SpilledBox] that [SpillDir::unlock] returns, so code that
+//! should survive purges has to keep and lock the `SpilledBox` rather than a `PurgeableBox`.
+//!
+//! Every process using a directory holds an exclusive `flock` on its own `<owner>.lock` file
+//! there, and names its spill files `<owner>-<id>.spill`. The lock is released when the
+//! process exits, so files whose owner's lock can be taken are stale. Unlike process ids, the
+//! lock works across PID namespaces and is never reused. On other platforms than Unix, files
+//! of other processes are never considered stale.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use purgeable::spill::SpillDir;
+//! use purgeable::NonPurgeableBox;
+//!
+//! let dir = SpillDir::in_cache_dir(1 << 30)?;
+//! let spilled = dir.unlock(NonPurgeableBox::new_slice(b"expensive to regenerate"));
+//! // ...
+//! match spilled.lock() {
+//!     Ok(npb) => assert_eq!(&*npb, b"expensive to regenerate"),
+//!     Err(_) => println!("Purged and not spilled, e.g. the directory is full"),
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::checksum::checksum;
+use crate::{NonPurgeableBox, PurgePriority, PurgeableBox, PurgeableBoxLockError};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::{env, fmt, mem, thread};
+
+pub const MAGIC: &[u8; 8] = b"PGSPILL\0";
+const HEADER_LEN: u64 = MAGIC.len() as u64 + 2 * 8;
+
+/// A directory of spill files with a size limit, and the background thread writing them.
+///
+/// Boxes that don't fit under the limit or fail to be written stay purgeable only. The limit
+/// applies to the files of all [SpillDir]s of the directory in the process. On Unix, the
+/// directory is created with mode `0700` and the files with mode `0600`
+pub struct SpillDir {
+    shared: Arc<Shared>,
+    sender: mpsc::Sender<Message>,
+}
+
+struct Shared {
+    path: PathBuf,
+    max_bytes: u64,
+    /// Shared by all `SpillDir`s of the directory, see [DIR_OWNERS]
+    owner: Arc<DirOwner>,
+}
+
+/// The registration of the process in a directory: the size of its spill files, and the
+/// lock file that tells other processes that the files are in use
+struct DirOwner {
+    /// Unique in the directory; the spill files are named `<name>-<id>.spill`
+    name: String,
+    used_bytes: AtomicU64,
+    lock_path: PathBuf,
+    /// Holds the `flock` while the process uses the directory
+    _lock: File,
+}
+
+/// The registrations of every directory in use by the process, by canonical path
+static DIR_OWNERS: Mutex<BTreeMap<PathBuf, Weak<DirOwner>>> = Mutex::new(BTreeMap::new());
+
+enum Message {
+    Spill(Arc<Entry>),
+    Flush(mpsc::Sender<()>),
+}
+
+impl SpillDir {
+    /// Spills into the directory at `path`, created if it is missing, using at most
+    /// `max_bytes` of disk space including the file headers.
+    ///
+    /// If the process doesn't use the directory yet, spill files of processes that have exited
+    /// are removed from it
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64) -> io::Result<SpillDir> {
+        let path = path.into();
+        create_dir(&path)?;
+        let owner = dir_owner(&path)?;
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("purgeable-spill".to_owned())
+            .spawn(move || {
+                for message in receiver {
+                    match message {
+                        Message::Spill(entry) => entry.spill(),
+                        Message::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })?;
+        Ok(SpillDir {
+            shared: Arc::new(Shared {
+                path,
+                max_bytes,
+                owner,
+            }),
+            sender,
+        })
+    }
+
+    /// Spills into `$XDG_CACHE_HOME/purgeable`, falling back to `$HOME/.cache/purgeable`.
+    /// Fails with [io::ErrorKind::NotFound] if neither is set: the shared temporary directory
+    /// is not a safe fallback
+    pub fn in_cache_dir(max_bytes: u64) -> io::Result<SpillDir> {
+        let cache_dir = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(|it| it.is_absolute())
+            .or_else(|| {
+                let home = PathBuf::from(env::var_os("HOME")?);
+                home.is_absolute().then(|| home.join(".cache"))
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "neither XDG_CACHE_HOME nor HOME is set",
+                )
+            })?;
+        SpillDir::new(cache_dir.join("purgeable"), max_bytes)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.shared.path
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.shared.max_bytes
+    }
+
+    /// The size of the spill files of live boxes of all [SpillDir]s of the directory
+    pub fn used_bytes(&self) -> u64 {
+        self.shared.owner.used_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Like [NonPurgeableBox::unlock], but the content is written to a spill file first.
+    /// The box stays locked until it has been written
+    pub fn unlock(&self, npb: NonPurgeableBox<[u8]>) -> SpilledBox {
+        self.unlock_with_priority(npb, PurgePriority::DEFAULT)
+    }
+
+    /// See [SpillDir::unlock] and [NonPurgeableBox::unlock_with_priority]
+    pub fn unlock_with_priority(
+        &self,
+        npb: NonPurgeableBox<[u8]>,
+        priority: PurgePriority,
+    ) -> SpilledBox {
+        static NEXT_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
+        let file_id = NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(Entry {
+            shared: self.shared.clone(),
+            path: self
+                .shared
+                .path
+                .join(format!("{}-{file_id}.spill", self.shared.owner.name)),
+            integrity_verified: NonPurgeableBox::is_integrity_verified(&npb),
+            inner: Mutex::new(EntryInner {
+                state: State::Pending { npb, priority },
+                file: None,
+            }),
+            written: Condvar::new(),
+        });
+        if self.sender.send(Message::Spill(entry.clone())).is_err() {
+            // The writer thread has panicked
+            entry.unlock_without_spilling();
+        }
+        SpilledBox { entry }
+    }
+
+    /// Blocks until the boxes unlocked so far have been written
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+impl fmt::Debug for SpillDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpillDir")
+            .field("path", &self.shared.path)
+            .field("max_bytes", &self.shared.max_bytes)
+            .field("used_bytes", &self.used_bytes())
+            .finish()
+    }
+}
+
+impl Shared {
+    fn reserve(&self, bytes: u64) -> bool {
+        self.owner
+            .used_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&it| it <= self.max_bytes)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: u64) {
+        self.owner.used_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// An unlocked box with a copy of its content on disk, see [SpillDir::unlock]
+pub struct SpilledBox {
+    entry: Arc<Entry>,
+}
+
+struct Entry {
+    shared: Arc<Shared>,
+    path: PathBuf,
+    /// Restored on reload, see [NonPurgeableBox::set_integrity_verified]
+    integrity_verified: bool,
+    inner: Mutex<EntryInner>,
+    /// Notified when the writer thread leaves [State::Writing]
+    written: Condvar,
+}
+
+struct EntryInner {
+    state: State,
+    /// `Some` if the content has been written
+    file: Option<SpillFile>,
+}
+
+enum State {
+    /// Waiting for the writer thread
+    Pending {
+        npb: NonPurgeableBox<[u8]>,
+        priority: PurgePriority,
+    },
+    /// Taken by the writer thread, which writes it without holding the lock
+    Writing {
+        len: usize,
+    },
+    Unlocked(PurgeableBox<[u8]>),
+    /// Locked by [SpilledBox::lock]
+    Taken,
+}
+
+#[derive(Clone, Copy)]
+struct SpillFile {
+    len: u64,
+    checksum: u64,
+}
+
+impl SpilledBox {
+    /// Like [PurgeableBox::lock], but the content is reloaded from the spill file if the
+    /// memory has been purged. Fails only if the box has been purged and its spill file is
+    /// missing or corrupted. Waits for the write if the box is being written.
+    ///
+    /// A reloaded box keeps the tag, the label and the integrity verification of the spilled
+    /// one. Like any locked box, it gets its [PurgePriority] from the next unlock
+    pub fn lock(self) -> Result<NonPurgeableBox<[u8]>, PurgeableBoxLockError> {
+        let mut inner = self.entry.inner();
+        while let State::Writing { .. } = inner.state {
+            inner = self
+                .entry
+                .written
+                .wait(inner)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        match mem::replace(&mut inner.state, State::Taken) {
+            State::Pending { npb, .. } => Ok(npb),
+            State::Unlocked(pb) => {
+                let (tag, label) = (pb.tag(), pb.label().map(Box::<str>::from));
+                pb.lock().or_else(|e| {
+                    let file = inner.file.ok_or(e.clone())?;
+                    let mut npb = self.entry.reload(file, label.as_deref()).map_err(|_| e)?;
+                    NonPurgeableBox::set_tag(&mut npb, tag);
+                    NonPurgeableBox::set_integrity_verified(
+                        &mut npb,
+                        self.entry.integrity_verified,
+                    );
+                    Ok(npb)
+                })
+            }
+            State::Writing { .. } | State::Taken => unreachable!(),
+        }
+    }
+
+    /// Whether the content has been written to a spill file
+    pub fn is_spilled(&self) -> bool {
+        self.entry.inner().file.is_some()
+    }
+
+    /// Purges the memory if the box has been unlocked, see [crate::testing::force_purge]
+    #[cfg(all(test, feature = "testing"))]
+    pub(crate) fn force_purge(&self) -> bool {
+        match &mut self.entry.inner().state {
+            State::Unlocked(pb) => crate::testing::force_purge(pb),
+            _ => false,
+        }
+    }
+
+    /// The size of the content in bytes
+    pub fn size(&self) -> usize {
+        match &self.entry.inner().state {
+            State::Pending { npb, .. } => npb.len(),
+            State::Writing { len } => *len,
+            State::Unlocked(pb) => pb.size(),
+            State::Taken => unreachable!(),
+        }
+    }
+}
+
+impl fmt::Debug for SpilledBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpilledBox")
+            .field("path", &self.entry.path)
+            .field("is_spilled", &self.is_spilled())
+            .finish()
+    }
+}
+
+impl Entry {
+    fn inner(&self) -> MutexGuard<'_, EntryInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Called on the writer thread. The box is taken out of the state for the write, so the
+    /// lock is not held meanwhile; a concurrent [SpilledBox::lock] waits for [Entry::written]
+    fn spill(&self) {
+        let mut inner = self.inner();
+        let len = match &inner.state {
+            State::Pending { npb, .. } => npb.len(),
+            // Locked before it has been written
+            _ => return,
+        };
+        let State::Pending { npb, priority } =
+            mem::replace(&mut inner.state, State::Writing { len })
+        else {
+            unreachable!()
+        };
+        drop(inner);
+
+        let file = self.write(&npb).ok();
+        let pb = NonPurgeableBox::unlock_with_priority(npb, priority);
+        let mut inner = self.inner();
+        inner.file = file;
+        inner.state = State::Unlocked(pb);
+        drop(inner);
+        self.written.notify_all();
+    }
+
+    fn unlock_without_spilling(&self) {
+        let mut inner = self.inner();
+        if let State::Pending { npb, priority } = mem::replace(&mut inner.state, State::Taken) {
+            inner.state = State::Unlocked(NonPurgeableBox::unlock_with_priority(npb, priority));
+        }
+    }
+
+    fn write(&self, content: &[u8]) -> io::Result<SpillFile> {
+        let len = HEADER_LEN + content.len() as u64;
+        if !self.shared.reserve(len) {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "the spill directory is full",
+            ));
+        }
+        // SAFETY: the box memory is page-aligned
+        let checksum = unsafe { checksum(content.as_ptr(), content.len()) };
+        // An existing file is never overwritten nor removed: it isn't ours
+        let result = create_file(&self.path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            let written = (|| {
+                writer.write_all(MAGIC)?;
+                writer.write_all(&(content.len() as u64).to_le_bytes())?;
+                writer.write_all(&checksum.to_le_bytes())?;
+                writer.write_all(content)?;
+                writer.flush()
+            })();
+            if written.is_err() {
+                let _ = fs::remove_file(&self.path);
+            }
+            written
+        });
+        match result {
+            Ok(()) => Ok(SpillFile { len, checksum }),
+            Err(e) => {
+                self.shared.release(len);
+                Err(e)
+            }
+        }
+    }
+
+    fn reload(&self, file: SpillFile, label: Option<&str>) -> io::Result<NonPurgeableBox<[u8]>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupted spill file");
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut header = [0; HEADER_LEN as usize];
+        reader.read_exact(&mut header)?;
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(header[offset..][..8].try_into().unwrap());
+        if &header[..MAGIC.len()] != MAGIC
+            || HEADER_LEN + read_u64(MAGIC.len()) != file.len
+            || read_u64(MAGIC.len() + 8) != file.checksum
+        {
+            return Err(invalid());
+        }
+
+        let len = (file.len - HEADER_LEN) as usize;
+        let mut npb = NonPurgeableBox::try_new_uninit_slice_with_label(len, label)
+            .map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))?;
+        npb.fill(mem::MaybeUninit::new(0));
+        // SAFETY: the content has been zeroed
+        let mut npb = unsafe { npb.assume_init() };
+        reader.read_exact(&mut npb)?;
+        // SAFETY: the box memory is page-aligned
+        if unsafe { checksum(npb.as_ptr(), npb.len()) } != file.checksum {
+            return Err(invalid());
+        }
+        Ok(npb)
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        if let Some(file) = self.inner().file {
+            let _ = fs::remove_file(&self.path);
+            self.shared.release(file.len);
+        }
+    }
+}
+
+fn create_dir(path: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(path)
+}
+
+/// Fails with [io::ErrorKind::AlreadyExists] if the file exists, so a file planted under
+/// a predictable name is never written through
+fn create_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Returns the registration of the process in the directory, registering it and removing
+/// stale spill files if the process doesn't use the directory yet
+fn dir_owner(path: &Path) -> io::Result<Arc<DirOwner>> {
+    let path = fs::canonicalize(path)?;
+    let mut dirs = DIR_OWNERS.lock().unwrap_or_else(|e| e.into_inner());
+    dirs.retain(|_, owner| owner.strong_count() > 0);
+    if let Some(owner) = dirs.get(&path).and_then(Weak::upgrade) {
+        return Ok(owner);
+    }
+    let owner = Arc::new(DirOwner::new(&path)?);
+    remove_stale_files(&path, &owner.name)?;
+    dirs.insert(path, Arc::downgrade(&owner));
+    Ok(owner)
+}
+
+impl DirOwner {
+    /// Creates and locks a lock file with a random name. It is locked under a temporary name
+    /// and then renamed, so other processes never see it unlocked
+    fn new(dir: &Path) -> io::Result<DirOwner> {
+        loop {
+            let name = format!("{:016x}", random_u64());
+            let lock_path = dir.join(format!("{name}.lock"));
+            let tmp_path = dir.join(format!(".{name}.lock.tmp"));
+            let lock = match create_file(&tmp_path) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                result => result?,
+            };
+            // `rename` would replace an existing lock file
+            let locked = flock_exclusive(&lock).and_then(|()| fs::hard_link(&tmp_path, &lock_path));
+            let _ = fs::remove_file(&tmp_path);
+            match locked {
+                Ok(()) => {
+                    return Ok(DirOwner {
+                        name,
+                        used_bytes: AtomicU64::new(0),
+                        lock_path,
+                        _lock: lock,
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for DirOwner {
+    fn drop(&mut self) {
+        // The spill files are removed by their entries, which hold the owner
+        let _ = fs::remove_file(&self.lock_path);
+    }
+}
+
+/// Removes the spill files named `<owner>-<id>.spill` and the lock files `<owner>.lock` of
+/// owners whose lock can be taken, other than `own`. Spill files without a lock file are
+/// stale too: the lock file is created before the first spill file and removed after the last
+fn remove_stale_files(dir: &Path, own: &str) -> io::Result<()> {
+    // The locks of stale owners are held until their files are removed
+    let mut owners = BTreeMap::<String, Option<Option<File>>>::new();
+    let mut is_stale = |owner: &str| {
+        let lock = owners.entry(owner.to_owned()).or_insert_with(|| {
+            if owner == own {
+                return None;
+            }
+            match File::open(dir.join(format!("{owner}.lock"))) {
+                Ok(lock) => is_unlocked(&lock).then_some(Some(lock)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Some(None),
+                Err(_) => None,
+            }
+        });
+        lock.is_some()
+    };
+    let mut stale_locks = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if let Some(owner) = name.strip_suffix(".lock") {
+            if is_stale(owner) {
+                stale_locks.push(entry.path());
+            }
+            continue;
+        }
+        let owner = name
+            .strip_suffix(".spill")
+            .and_then(|it| it.rsplit_once('-'))
+            .filter(|(_, id)| id.parse::<u64>().is_ok())
+            .map(|(owner, _)| owner);
+        if owner.is_some_and(&mut is_stale) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+    for path in stale_locks {
+        let _ = fs::remove_file(path);
+    }
+    Ok(())
+}
+
+/// Takes an exclusive `flock` without blocking
+#[cfg(unix)]
+fn flock_exclusive(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the file descriptor is open
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn flock_exclusive(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Takes the lock of an owner if it has exited; errors are treated like a held lock
+#[cfg(unix)]
+fn is_unlocked(file: &File) -> bool {
+    flock_exclusive(file).is_ok()
+}
+
+/// Files of other processes are never considered stale
+#[cfg(not(unix))]
+fn is_unlocked(_file: &File) -> bool {
+    false
+}
+
+fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    // `RandomState` is seeded randomly per process, and differently for each instance
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u64(std::process::id() as u64);
+    hasher.finish()
+}
@@ -0,0 +1,247 @@
+use crate::metrics_exporter::{self, MetricLabels};
+use crate::recorder;
+use crate::registry::{self, RegistryId};
+use crate::{Backend, PurgePriority};
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// Returns a snapshot of the global purgeable memory statistics.
+///
+/// The counters are updated independently, so the snapshot may be slightly inconsistent
+/// if boxes are concurrently allocated, locked or unlocked on other threads.
+///
+/// # Examples
+///
+/// ```
+/// use purgeable::NonPurgeableBox;
+///
+/// let npb = NonPurgeableBox::new(&1);
+/// let stats = purgeable::stats();
+/// assert!(stats.locked.count >= 1);
+/// ```
+pub fn stats() -> Stats {
+    let mut backends = [LockStats::default(); Backend::ALL.len()];
+    for (stats, counters) in backends.iter_mut().zip(&BACKEND_LOCKS) {
+        *stats = counters.load();
+    }
+    let locks = backends
+        .iter()
+        .fold(LockStats::default(), |acc, it| LockStats {
+            attempts: acc.attempts + it.attempts,
+            failures: acc.failures + it.failures,
+        });
+    Stats {
+        allocated: ALLOCATED.load(),
+        locked: LOCKED.load(),
+        unlocked: UNLOCKED.load(),
+        purged: PURGED.load(),
+        locks,
+        backends,
+    }
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Stats {
+    /// All live boxes regardless of their state
+    pub allocated: MemoryStats,
+    /// Live [NonPurgeableBox](crate::NonPurgeableBox)es
+    pub locked: MemoryStats,
+    /// Live [PurgeableBox](crate::PurgeableBox)es that are not known to be purged
+    pub unlocked: MemoryStats,
+    /// Live boxes that are known to be purged. Note that most backends can detect a purge
+    /// only when the box is being locked
+    pub purged: MemoryStats,
+    /// Cumulative lock statistics of all backends
+    pub locks: LockStats,
+    backends: [LockStats; Backend::ALL.len()],
+}
+
+impl Stats {
+    /// Cumulative lock statistics of the `backend`
+    pub fn backend(&self, backend: Backend) -> LockStats {
+        self.backends[backend as usize]
+    }
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct MemoryStats {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct LockStats {
+    pub attempts: u64,
+    /// The number of locks that failed because the box had been purged
+    pub failures: u64,
+}
+
+struct MemoryCounters {
+    count: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl MemoryCounters {
+    const fn new() -> MemoryCounters {
+        MemoryCounters {
+            count: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    fn add(&self, size: usize) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn sub(&self, size: usize) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> MemoryStats {
+        MemoryStats {
+            count: self.count.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct LockCounters {
+    attempts: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl LockCounters {
+    const fn new() -> LockCounters {
+        LockCounters {
+            attempts: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    fn load(&self) -> LockStats {
+        LockStats {
+            attempts: self.attempts.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static ALLOCATED: MemoryCounters = MemoryCounters::new();
+static LOCKED: MemoryCounters = MemoryCounters::new();
+static UNLOCKED: MemoryCounters = MemoryCounters::new();
+static PURGED: MemoryCounters = MemoryCounters::new();
+static BACKEND_LOCKS: [LockCounters; Backend::ALL.len()] =
+    [const { LockCounters::new() }; Backend::ALL.len()];
+
+pub(crate) const LOCKED_STATE: u8 = 0;
+pub(crate) const UNLOCKED_STATE: u8 = 1;
+pub(crate) const PURGED_STATE: u8 = 2;
+
+/// Accounts a single box in the global statistics for the whole box lifetime.
+/// It is moved along with the box when the box is cast to another type.
+/// Also exports the same numbers as `metrics` and tracks the box in the debug registry
+/// if the corresponding features are enabled, and writes the box events to the active
+/// [recording](crate::recorder).
+pub(crate) struct BoxStats {
+    size: usize,
+    state: AtomicU8,
+    metric_labels: MetricLabels,
+    registry_id: RegistryId,
+    box_id: u64,
+}
+
+impl BoxStats {
+    pub(crate) fn new_locked(size: usize, backend: Backend, label: Option<&str>) -> BoxStats {
+        let metric_labels = metrics_exporter::labels(backend, label);
+        ALLOCATED.add(size);
+        LOCKED.add(size);
+        metrics_exporter::add_bytes(&metric_labels, state_gauge(LOCKED_STATE), size);
+        let registry_id = registry::register(size, backend, label);
+        let box_id = recorder::next_box_id();
+        recorder::allocated(box_id, size, backend);
+        BoxStats {
+            size,
+            state: AtomicU8::new(LOCKED_STATE),
+            metric_labels,
+            registry_id,
+            box_id,
+        }
+    }
+
+    pub(crate) fn on_lock(&mut self, backend: Backend, success: bool) {
+        metrics_exporter::locked(&self.metric_labels, success);
+        recorder::locked(self.box_id, success);
+        let counters = &BACKEND_LOCKS[backend as usize];
+        counters.attempts.fetch_add(1, Ordering::Relaxed);
+        if success {
+            self.set_state(LOCKED_STATE);
+        } else {
+            counters.failures.fetch_add(1, Ordering::Relaxed);
+            self.set_state(PURGED_STATE);
+        }
+    }
+
+    pub(crate) fn on_unlock(&mut self, priority: PurgePriority) {
+        recorder::unlocked(self.box_id, priority);
+        self.set_state(UNLOCKED_STATE);
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn on_purged(&mut self) {
+        self.set_state(PURGED_STATE);
+    }
+
+    /// Called when the unlocked box has been found purged without locking it
+    #[cfg(any(target_os = "macos", target_os = "ios", purgeable_sim))]
+    pub(crate) fn on_purge_detected(&self) {
+        if self.state.swap(PURGED_STATE, Ordering::Relaxed) == UNLOCKED_STATE {
+            self.on_state_changed(UNLOCKED_STATE, PURGED_STATE);
+        }
+    }
+
+    fn set_state(&mut self, new_state: u8) {
+        let old_state = std::mem::replace(self.state.get_mut(), new_state);
+        if old_state != new_state {
+            self.on_state_changed(old_state, new_state);
+        }
+    }
+
+    fn on_state_changed(&self, old_state: u8, new_state: u8) {
+        state_counters(old_state).sub(self.size);
+        state_counters(new_state).add(self.size);
+        metrics_exporter::sub_bytes(&self.metric_labels, state_gauge(old_state), self.size);
+        metrics_exporter::add_bytes(&self.metric_labels, state_gauge(new_state), self.size);
+        registry::set_state(&self.registry_id, new_state);
+    }
+}
+
+impl Drop for BoxStats {
+    fn drop(&mut self) {
+        let state = *self.state.get_mut();
+        ALLOCATED.sub(self.size);
+        state_counters(state).sub(self.size);
+        metrics_exporter::sub_bytes(&self.metric_labels, state_gauge(state), self.size);
+        registry::unregister(&self.registry_id);
+        recorder::dropped(self.box_id);
+    }
+}
+
+fn state_counters(state: u8) -> &'static MemoryCounters {
+    match state {
+        LOCKED_STATE => &LOCKED,
+        UNLOCKED_STATE => &UNLOCKED,
+        _ => &PURGED,
+    }
+}
+
+fn state_gauge(state: u8) -> &'static str {
+    match state {
+        LOCKED_STATE => metrics_exporter::LOCKED_BYTES,
+        UNLOCKED_STATE => metrics_exporter::UNLOCKED_BYTES,
+        _ => metrics_exporter::PURGED_BYTES,
+    }
+}
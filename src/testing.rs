@@ -0,0 +1,59 @@
+//! Helpers for testing code that uses purgeable memory and for validating backends;
+//! requires the `testing` feature
+
+use crate::{os, Backend, ByteContent, PurgeableBox};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod balloon;
+pub mod conformance;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use balloon::{Balloon, BalloonLimits, StopReason};
+
+/// Backends that can be used on this system, see [with_backend]
+pub fn available_backends() -> Vec<Backend> {
+    os::available_backends()
+}
+
+/// Calls `f` so that all boxes allocated by the current thread inside it use the `backend`.
+///
+/// # Panics
+///
+/// Panics if the `backend` is not in [available_backends]
+pub fn with_backend<R>(backend: Backend, f: impl FnOnce() -> R) -> R {
+    assert!(
+        available_backends().contains(&backend),
+        "the {} backend is not available",
+        backend
+    );
+
+    struct Restore(Option<Backend>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            os::set_forced_backend(self.0);
+        }
+    }
+
+    let _restore = Restore(os::set_forced_backend(Some(backend)));
+    f()
+}
+
+/// Purges the box as if the system were under memory pressure.
+///
+/// Returns `true` if the next [PurgeableBox::lock] is guaranteed to fail. Some backends can't
+/// purge on demand (Windows) or need privileges (`ashmem` needs `CAP_SYS_ADMIN`; note that it
+/// purges all unpinned `ashmem` regions in the system); `false` is returned then.
+pub fn force_purge<T: ?Sized>(b: &mut PurgeableBox<T>) -> bool {
+    b.force_purge()
+}
+
+/// Changes the last byte of the box behind the backend's back, like a partial purge that the
+/// backend doesn't detect. Only [NonPurgeableBox::set_integrity_verified] makes the next
+/// [PurgeableBox::lock] fail then.
+///
+/// Returns `false` if the box is empty or the backend would undo the change.
+///
+/// [NonPurgeableBox::set_integrity_verified]: crate::NonPurgeableBox::set_integrity_verified
+pub fn corrupt<T: ByteContent + ?Sized>(b: &mut PurgeableBox<T>) -> bool {
+    b.corrupt()
+}
@@ -0,0 +1,238 @@
+//! A memory balloon that induces real kernel reclaim
+
+use std::fs;
+use std::path::PathBuf;
+use std::ptr;
+use std::time::Duration;
+
+/// Anonymous memory allocated and touched page by page until a target size is reached or
+/// the kernel starts reclaiming memory. The memory is released on drop.
+///
+/// It never takes the memory the system or the cgroup needs: inflation stops when
+/// `MemAvailable` from `/proc/meminfo` (or the cgroup headroom below `memory.max`) falls below
+/// [BalloonLimits::min_available]. Nothing is inflated if the available memory can't be read.
+///
+/// # Examples
+///
+/// ```no_run
+/// use purgeable::testing::{Balloon, BalloonLimits};
+/// use purgeable::NonPurgeableBox;
+///
+/// let u = NonPurgeableBox::unlock(NonPurgeableBox::new_filled_slice(1u8, 64 << 20));
+/// let balloon = Balloon::inflate(usize::MAX, &BalloonLimits::default());
+/// println!("Inflated {} bytes: {:?}", balloon.size(), balloon.stop_reason());
+/// drop(balloon);
+/// println!("Purged: {}", u.lock().is_err());
+/// ```
+#[derive(Debug)]
+pub struct Balloon {
+    chunks: Vec<(*mut u8, usize)>,
+    size: usize,
+    stop_reason: StopReason,
+}
+
+// SAFETY: the chunks are owned by the balloon and never accessed after inflation
+unsafe impl Send for Balloon {}
+
+/// Why [Balloon::inflate] has stopped
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum StopReason {
+    /// The target size has been reached
+    Target,
+    /// The kernel has reported memory pressure: a PSI memory stall or a cgroup `memory.high`
+    /// or `memory.max` event
+    Pressure,
+    /// Available memory has fallen to [BalloonLimits::min_available] or can't be read
+    SafetyLimit,
+    /// `mmap` has failed
+    AllocationFailed,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct BalloonLimits {
+    /// The memory that must stay available to the system and to the current cgroup.
+    /// 256 MiB or 5% of `MemTotal`, whichever is greater, by default
+    pub min_available: usize,
+    /// Stop when the tasks have stalled on memory (PSI `some` in `/proc/pressure/memory`)
+    /// longer than this since inflation start. `None` disables the check. 10 ms by default
+    pub max_stall: Option<Duration>,
+    /// Stop on cgroup `memory.high` or `memory.max` events. `true` by default
+    pub stop_on_cgroup_events: bool,
+    /// The balloon grows by chunks of this size; limits are checked after every chunk.
+    /// 16 MiB by default
+    pub chunk_size: usize,
+}
+
+impl Default for BalloonLimits {
+    fn default() -> BalloonLimits {
+        let total = meminfo_bytes("MemTotal").unwrap_or(0);
+        BalloonLimits {
+            min_available: (256 << 20).max(total / 20),
+            max_stall: Some(Duration::from_millis(10)),
+            stop_on_cgroup_events: true,
+            chunk_size: 16 << 20,
+        }
+    }
+}
+
+impl Balloon {
+    /// Inflates the balloon up to `target` bytes (rounded up to the chunk size) or until
+    /// one of the `limits` is hit
+    pub fn inflate(target: usize, limits: &BalloonLimits) -> Balloon {
+        let page_size = page_size::get();
+        let chunk_size = limits.chunk_size.max(page_size).next_multiple_of(page_size);
+        let cgroup = Cgroup::current();
+        let initial_stall = memory_stall();
+        let initial_events = cgroup.as_ref().and_then(Cgroup::events);
+
+        let mut balloon = Balloon {
+            chunks: Vec::new(),
+            size: 0,
+            stop_reason: StopReason::Target,
+        };
+        balloon.stop_reason = loop {
+            if balloon.size >= target {
+                break StopReason::Target;
+            }
+            let safe = available(cgroup.as_ref())
+                .is_some_and(|it| it >= limits.min_available.saturating_add(chunk_size));
+            if !safe {
+                break StopReason::SafetyLimit;
+            }
+            if !balloon.grow(chunk_size, page_size) {
+                break StopReason::AllocationFailed;
+            }
+
+            let stalled = match (limits.max_stall, initial_stall, memory_stall()) {
+                (Some(max_stall), Some(initial), Some(current)) => current - initial > max_stall,
+                _ => false,
+            };
+            let cgroup_events = limits.stop_on_cgroup_events
+                && initial_events.is_some()
+                && cgroup.as_ref().and_then(Cgroup::events) != initial_events;
+            if stalled || cgroup_events {
+                break StopReason::Pressure;
+            }
+        };
+        balloon
+    }
+
+    fn grow(&mut self, size: usize, page_size: usize) -> bool {
+        let address = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            return false;
+        }
+        let address = address as *mut u8;
+        for offset in (0..size).step_by(page_size) {
+            // SAFETY: the offset is inside the fresh mapping
+            unsafe { ptr::write_volatile(address.add(offset), 1) };
+        }
+        self.chunks.push((address, size));
+        self.size += size;
+        true
+    }
+
+    /// The inflated size in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn stop_reason(&self) -> StopReason {
+        self.stop_reason
+    }
+}
+
+impl Drop for Balloon {
+    fn drop(&mut self) {
+        for &(address, size) in &self.chunks {
+            unsafe {
+                libc::munmap(address as *mut _, size);
+            }
+        }
+    }
+}
+
+/// `MemAvailable` or the cgroup headroom, whichever is less
+fn available(cgroup: Option<&Cgroup>) -> Option<usize> {
+    let system = meminfo_bytes("MemAvailable");
+    let cgroup = cgroup.and_then(Cgroup::headroom);
+    match (system, cgroup) {
+        (Some(system), Some(cgroup)) => Some(system.min(cgroup)),
+        (system, cgroup) => system.or(cgroup),
+    }
+}
+
+fn meminfo_bytes(field: &str) -> Option<usize> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo.lines().find_map(|line| {
+        let value = line.strip_prefix(field)?.strip_prefix(':')?;
+        let kib = value
+            .trim()
+            .strip_suffix("kB")?
+            .trim()
+            .parse::<usize>()
+            .ok()?;
+        Some(kib * 1024)
+    })
+}
+
+/// The total time tasks have stalled on memory, see
+/// <https://docs.kernel.org/accounting/psi.html>
+fn memory_stall() -> Option<Duration> {
+    let pressure = fs::read_to_string("/proc/pressure/memory").ok()?;
+    let some = pressure.lines().find(|line| line.starts_with("some "))?;
+    let total = some
+        .split_whitespace()
+        .find_map(|it| it.strip_prefix("total="))?;
+    Some(Duration::from_micros(total.parse().ok()?))
+}
+
+/// The cgroup v2 of the current process
+struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    fn current() -> Option<Cgroup> {
+        let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+        let relative = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+        let path = PathBuf::from("/sys/fs/cgroup").join(relative.trim_start_matches('/'));
+        path.join("memory.current")
+            .exists()
+            .then_some(Cgroup { path })
+    }
+
+    fn read(&self, file: &str) -> Option<String> {
+        fs::read_to_string(self.path.join(file)).ok()
+    }
+
+    /// `memory.max - memory.current`; `None` if the cgroup is not limited
+    fn headroom(&self) -> Option<usize> {
+        let max = self.read("memory.max")?.trim().parse::<usize>().ok()?;
+        let current = self.read("memory.current")?.trim().parse::<usize>().ok()?;
+        Some(max.saturating_sub(current))
+    }
+
+    /// The `high` and `max` counters of `memory.events`
+    fn events(&self) -> Option<(u64, u64)> {
+        let events = self.read("memory.events")?;
+        let counter = |name: &str| {
+            events.lines().find_map(|line| {
+                let (key, value) = line.split_once(' ')?;
+                (key == name).then(|| value.trim().parse::<u64>().ok())?
+            })
+        };
+        Some((counter("high")?, counter("max")?))
+    }
+}
@@ -0,0 +1,269 @@
+//! A model-based conformance check for backends.
+//!
+//! [check] drives a random sequence of allocations, fills, unlocks, forced purges, locks and
+//! drops against a backend and compares the results to a reference model:
+//! - a successful lock preserves the content written before the unlock;
+//! - a lock never succeeds after a successful [force_purge](super::force_purge).
+//!
+//! A lock may fail without a forced purge since the system may purge memory at any time.
+//!
+//! # Examples
+//!
+//! ```
+//! use purgeable::testing::{available_backends, conformance};
+//!
+//! for backend in available_backends() {
+//!     conformance::check(backend, &conformance::Config::with_seed(42));
+//! }
+//! ```
+
+use super::{force_purge, with_backend};
+use crate::{Backend, NonPurgeableBox, PurgePriority, PurgeableBox};
+
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The sequence of operations is fully determined by the seed
+    pub seed: u64,
+    pub steps: usize,
+    /// The maximum number of simultaneously live boxes
+    pub max_boxes: usize,
+    /// The maximum size of a box in bytes
+    pub max_len: usize,
+}
+
+impl Config {
+    pub fn with_seed(seed: u64) -> Config {
+        Config {
+            seed,
+            ..Config::default()
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            seed: 0,
+            steps: 500,
+            max_boxes: 8,
+            max_len: 3 * page_size::get(),
+        }
+    }
+}
+
+/// Runs the check on the current thread.
+///
+/// # Panics
+///
+/// Panics with the seed and the executed steps if the backend doesn't conform to the model,
+/// or if the `backend` is not [available](super::available_backends)
+pub fn check(backend: Backend, config: &Config) {
+    with_backend(backend, || Checker::new(backend, config).run())
+}
+
+enum BoxState {
+    Locked(NonPurgeableBox<[u8]>),
+    Unlocked(PurgeableBox<[u8]>),
+}
+
+struct ModelBox {
+    id: usize,
+    state: BoxState,
+    /// The expected content
+    content: Vec<u8>,
+    /// `true` if the box has been purged by [force_purge] since the last unlock
+    purged: bool,
+}
+
+struct Checker<'a> {
+    backend: Backend,
+    config: &'a Config,
+    rng: Rng,
+    boxes: Vec<ModelBox>,
+    next_id: usize,
+    log: Vec<String>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(backend: Backend, config: &'a Config) -> Checker<'a> {
+        Checker {
+            backend,
+            config,
+            rng: Rng(config.seed),
+            boxes: Vec::new(),
+            next_id: 0,
+            log: Vec::new(),
+        }
+    }
+
+    fn run(mut self) {
+        for _ in 0..self.config.steps {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        let can_allocate = self.boxes.len() < self.config.max_boxes.max(1);
+        if self.boxes.is_empty() || (can_allocate && self.rng.below(4) == 0) {
+            self.allocate();
+            return;
+        }
+
+        let b = self.boxes.swap_remove(self.rng.below(self.boxes.len()));
+        match b.state {
+            BoxState::Locked(_) => match self.rng.below(5) {
+                0 | 1 => self.fill(b),
+                2 | 3 => self.unlock(b),
+                _ => self.log(format!("#{}: drop locked", b.id)),
+            },
+            BoxState::Unlocked(_) => match self.rng.below(5) {
+                0 => self.force_purge(b),
+                1..=3 => self.lock(b),
+                _ => self.log(format!("#{}: drop unlocked", b.id)),
+            },
+        }
+    }
+
+    fn allocate(&mut self) {
+        let page_size = page_size::get();
+        let max_len = self.config.max_len;
+        let len = match self.rng.below(3) {
+            0 => self.rng.below(page_size.min(max_len) + 1),
+            1 if max_len >= page_size => (self.rng.below(max_len / page_size) + 1) * page_size,
+            _ => self.rng.below(max_len + 1),
+        };
+        let byte = self.rng.byte();
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.log(format!("#{}: allocate {} bytes of {:#04x}", id, len, byte));
+
+        let l = NonPurgeableBox::new_filled_slice(byte, len);
+        if len != 0 && NonPurgeableBox::backend(&l) != self.backend {
+            self.fail(format!(
+                "#{} has been allocated with the {} backend",
+                id,
+                NonPurgeableBox::backend(&l)
+            ));
+        }
+        self.boxes.push(ModelBox {
+            id,
+            state: BoxState::Locked(l),
+            content: vec![byte; len],
+            purged: false,
+        });
+    }
+
+    fn fill(&mut self, mut b: ModelBox) {
+        let BoxState::Locked(l) = &mut b.state else {
+            unreachable!()
+        };
+        let start = self.rng.below(b.content.len() + 1);
+        let end = start + self.rng.below(b.content.len() - start + 1);
+        let byte = self.rng.byte();
+        self.log(format!(
+            "#{}: fill {}..{} with {:#04x}",
+            b.id, start, end, byte
+        ));
+
+        l[start..end].fill(byte);
+        b.content[start..end].fill(byte);
+        self.boxes.push(b);
+    }
+
+    fn unlock(&mut self, b: ModelBox) {
+        let BoxState::Locked(l) = b.state else {
+            unreachable!()
+        };
+        let mut priority =
+            PurgePriority::new(self.rng.below(PurgePriority::MAX_LEVEL as usize + 1) as u8);
+        if self.rng.below(2) == 0 {
+            priority = priority.lifo();
+        }
+        self.log(format!("#{}: unlock with {:?}", b.id, priority));
+
+        self.boxes.push(ModelBox {
+            state: BoxState::Unlocked(NonPurgeableBox::unlock_with_priority(l, priority)),
+            purged: false,
+            ..b
+        });
+    }
+
+    fn force_purge(&mut self, mut b: ModelBox) {
+        let BoxState::Unlocked(u) = &mut b.state else {
+            unreachable!()
+        };
+        let purged = force_purge(u);
+        self.log(format!("#{}: force purge -> {}", b.id, purged));
+
+        b.purged |= purged;
+        self.boxes.push(b);
+    }
+
+    fn lock(&mut self, b: ModelBox) {
+        let BoxState::Unlocked(u) = b.state else {
+            unreachable!()
+        };
+        let l = match u.lock() {
+            Ok(l) => l,
+            Err(_) => {
+                self.log(format!("#{}: lock -> purged", b.id));
+                return;
+            }
+        };
+        self.log(format!("#{}: lock -> ok", b.id));
+
+        if b.purged {
+            self.fail(format!("#{} has been locked after a forced purge", b.id));
+        }
+        if let Some(offset) = l.iter().zip(&b.content).position(|(a, b)| a != b) {
+            self.fail(format!(
+                "#{} has been locked, but its content differs at offset {}: {:#04x} != {:#04x}",
+                b.id, offset, l[offset], b.content[offset]
+            ));
+        }
+        self.boxes.push(ModelBox {
+            state: BoxState::Locked(l),
+            ..b
+        });
+    }
+
+    fn log(&mut self, step: String) {
+        self.log.push(step);
+    }
+
+    fn fail(&self, message: String) -> ! {
+        const LAST_STEPS: usize = 50;
+        let skipped = self.log.len().saturating_sub(LAST_STEPS);
+        panic!(
+            "the {} backend doesn't conform to the model (seed {}): {}\nlast steps:\n{}",
+            self.backend,
+            self.config.seed,
+            message,
+            self.log[skipped..].join("\n")
+        );
+    }
+}
+
+/// SplitMix64; the sequence must not depend on the platform or external crates
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..n`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn byte(&mut self) -> u8 {
+        self.next() as u8
+    }
+}
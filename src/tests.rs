@@ -1,4 +1,4 @@
-use crate::{NonPurgeableBox, PurgeableBox};
+use crate::{NonPurgeableBox, PurgePriority, PurgeableBox};
 
 #[test]
 fn box_impls_send_sync() {
@@ -31,3 +31,1295 @@ fn test_deref() {
         }
     }
 }
+
+#[test]
+fn test_unlock_with_priority() {
+    let l = NonPurgeableBox::new(&1i32);
+    let u = NonPurgeableBox::unlock_with_priority(l, PurgePriority::HIGHEST.lifo());
+    let l = u
+        .lock()
+        .expect("a box unlocked with a priority is not purged right away");
+    assert_eq!(*l, 1);
+}
+
+#[test]
+fn test_purge_order() {
+    let unlocks = [
+        PurgePriority::new(3),
+        PurgePriority::new(0).lifo(),
+        PurgePriority::new(3).lifo(),
+        PurgePriority::new(0),
+        PurgePriority::new(0).lifo(),
+        PurgePriority::new(0),
+    ];
+    let mut order: Vec<u64> = (0..).zip(unlocks).map(|(seq, _)| seq).collect();
+    order.sort_by_key(|&seq| unlocks[seq as usize].purge_order(seq));
+    // Lower levels first; within a level FIFO boxes in the unlock order, then LIFO boxes in
+    // the reverse order
+    assert_eq!(order, [3, 5, 4, 1, 0, 2]);
+}
+
+#[test]
+#[should_panic]
+fn purge_priority_level_out_of_range() {
+    let _ = PurgePriority::new(PurgePriority::MAX_LEVEL + 1);
+}
+
+#[test]
+fn test_stats() {
+    let size = 4096;
+    let l = NonPurgeableBox::new_filled_slice(0u8, size);
+    let stats = crate::stats();
+    assert!(stats.allocated.bytes >= size);
+    assert!(stats.locked.bytes >= size);
+
+    let u = NonPurgeableBox::unlock(l);
+    assert!(crate::stats().unlocked.bytes >= size);
+
+    let attempts_before = crate::stats().locks.attempts;
+    let _ = u.lock();
+    assert!(crate::stats().locks.attempts > attempts_before);
+}
+
+#[test]
+fn test_tag() {
+    let mut l = NonPurgeableBox::new(&1i32);
+    assert_eq!(NonPurgeableBox::tag(&l), 0);
+    NonPurgeableBox::set_tag(&mut l, 42);
+    let u = NonPurgeableBox::unlock(l);
+    assert_eq!(u.tag(), 42);
+    if let Ok(l) = u.lock() {
+        assert_eq!(NonPurgeableBox::tag(&l), 42);
+    }
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_purge_events_unsubscribe() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const TAG: u64 = 0x0e5b;
+    let purge = || {
+        let mut l = NonPurgeableBox::new_filled_slice(1u8, 10000);
+        NonPurgeableBox::set_tag(&mut l, TAG);
+        let mut u = NonPurgeableBox::unlock(l);
+        crate::testing::force_purge(&mut u) && u.lock().is_err()
+    };
+    let count = Arc::new(AtomicUsize::new(0));
+    let subscription = crate::on_purge({
+        let count = count.clone();
+        move |event| {
+            if event.tag == TAG {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+    let receiver = crate::purge_events();
+
+    let purged = purge() as usize;
+    assert_eq!(count.load(Ordering::Relaxed), purged);
+    assert_eq!(
+        receiver.try_iter().filter(|it| it.tag == TAG).count(),
+        purged
+    );
+
+    drop(subscription);
+    purge();
+    assert_eq!(count.load(Ordering::Relaxed), purged);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+#[cfg_attr(miri, ignore = "polls with a background thread")]
+fn test_poll_purges() {
+    use crate::{Backend, PurgeDetection};
+    use std::time::Duration;
+
+    const TAG: u64 = 0x0e5c;
+    let _poller = crate::poll_purges(Duration::from_millis(1)).unwrap();
+    for backend in crate::testing::available_backends() {
+        if !matches!(backend, Backend::Mach | Backend::MadvFree | Backend::Sim) {
+            continue;
+        }
+        crate::testing::with_backend(backend, || {
+            let events = crate::purge_events();
+            let mut l = NonPurgeableBox::new_filled_slice(1u8, 10000);
+            NonPurgeableBox::set_tag(&mut l, TAG);
+            let mut u = NonPurgeableBox::unlock(l);
+            assert!(crate::testing::force_purge(&mut u));
+
+            let event = events
+                .iter()
+                .find(|it| it.tag == TAG)
+                .expect("the purge is reported by the poller");
+            assert_eq!(event.detection, PurgeDetection::Poll);
+            assert_eq!((event.size, event.backend), (10000, backend));
+            // The purge is reported once
+            assert!(u.lock().is_err());
+            assert!(events.try_iter().all(|it| it.tag != TAG));
+        });
+    }
+}
+
+/// The conversions move the box state into a box of another type with `ptr::read`; Miri
+/// checks that it is moved exactly once with `--cfg purgeable_sim`
+#[test]
+fn test_conversions_keep_box_state() {
+    use std::mem::MaybeUninit;
+
+    let mut l =
+        NonPurgeableBox::<[u8]>::try_new_uninit_slice_with_label(3, Some("conversions")).unwrap();
+    NonPurgeableBox::set_tag(&mut l, 7);
+    l.fill(MaybeUninit::new(b'a'));
+    // SAFETY: the content has been initialized
+    let l = unsafe { l.assume_init() };
+    let u = NonPurgeableBox::unlock(l);
+    assert_eq!((u.tag(), u.label()), (7, Some("conversions")));
+    assert_eq!(*u.lock().unwrap(), *b"aaa");
+
+    let mut l = NonPurgeableBox::<u64>::new_uninit();
+    NonPurgeableBox::set_tag(&mut l, 8);
+    l.write(42);
+    // SAFETY: the content has been initialized
+    let l = unsafe { l.assume_init() };
+    assert_eq!((*l, NonPurgeableBox::tag(&l)), (42, 8));
+
+    let l = NonPurgeableBox::new_str_with_label("str", Some("conversions"));
+    let u = NonPurgeableBox::unlock(l);
+    assert_eq!(u.label(), Some("conversions"));
+    assert_eq!(&*u.lock().unwrap(), "str");
+}
+
+#[test]
+fn test_label() {
+    let l = NonPurgeableBox::new_slice_with_label(&[1u8, 2, 3], Some("test_label"));
+    assert_eq!(NonPurgeableBox::label(&l), Some("test_label"));
+    assert_eq!(*l, [1, 2, 3]);
+    let u = NonPurgeableBox::unlock(l);
+    assert_eq!(u.label(), Some("test_label"));
+
+    let l = NonPurgeableBox::new(&1i32);
+    assert_eq!(NonPurgeableBox::label(&l), None);
+}
+
+#[cfg(all(target_os = "linux", not(purgeable_sim)))]
+#[test]
+fn test_label_in_proc_maps() {
+    let _l = NonPurgeableBox::new_filled_slice_with_label(0u8, 4096, Some("test label [maps]"));
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    // Anonymous VMA names require Linux 5.17+ built with `CONFIG_ANON_VMA_NAME`
+    if maps.contains("[anon:purgeable") {
+        assert!(maps.contains("purgeable:test label _maps_"));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_resident() {
+    let page_size = page_size::get();
+    let l = NonPurgeableBox::new_filled_slice(1u8, page_size * 2 + 1);
+    assert_eq!(NonPurgeableBox::resident_pages(&l).unwrap(), 3);
+    assert_eq!(
+        NonPurgeableBox::resident_bytes(&l).unwrap(),
+        page_size * 2 + 1
+    );
+    let u = NonPurgeableBox::unlock(l);
+    assert!(u.resident_bytes().unwrap() <= page_size * 2 + 1);
+    assert!(u.resident_pages().unwrap() <= 3);
+
+    let l = NonPurgeableBox::new(&());
+    assert_eq!(NonPurgeableBox::resident_pages(&l).unwrap(), 0);
+}
+
+#[test]
+fn test_integrity_verified() {
+    let mut l = NonPurgeableBox::new_filled_slice(7u8, 10000);
+    NonPurgeableBox::set_integrity_verified(&mut l, true);
+    let l = NonPurgeableBox::unlock(l).lock().unwrap();
+    assert!(NonPurgeableBox::is_integrity_verified(&l));
+    assert!(l.iter().all(|&it| it == 7));
+
+    let mut l = NonPurgeableBox::new_str("verified");
+    NonPurgeableBox::set_integrity_verified(&mut l, true);
+    assert_eq!(&*NonPurgeableBox::unlock(l).lock().unwrap(), "verified");
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_integrity_verified_corruption() {
+    use crate::testing::{available_backends, corrupt, with_backend};
+
+    const TAG: u64 = 0xc0de;
+    for backend in available_backends() {
+        with_backend(backend, || {
+            let mut l = NonPurgeableBox::new_filled_slice(7u8, 10000);
+            NonPurgeableBox::set_integrity_verified(&mut l, true);
+            NonPurgeableBox::set_tag(&mut l, TAG);
+            let mut u = NonPurgeableBox::unlock(l);
+            assert!(corrupt(&mut u));
+            let events = crate::purge_events();
+            assert!(u.lock().is_err());
+            assert!(events
+                .try_iter()
+                .any(|it| it.tag == TAG && it.detection == crate::PurgeDetection::Checksum));
+
+            // Without the verification the corruption is not detected
+            let mut u = NonPurgeableBox::unlock(NonPurgeableBox::new_filled_slice(7u8, 10000));
+            assert!(corrupt(&mut u));
+            assert_eq!(u.lock().unwrap()[9999], !7);
+        });
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_metrics() {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    crate::set_exported_labels(["metrics_exported"]);
+    let _boxes = metrics::with_local_recorder(&recorder, || {
+        let l = NonPurgeableBox::new_filled_slice_with_label(0u8, 100, Some("metrics_exported"));
+        let l = NonPurgeableBox::unlock(l).lock().unwrap();
+        let other =
+            NonPurgeableBox::new_filled_slice_with_label(0u8, 10, Some("metrics_file_1234"));
+        (l, other)
+    });
+
+    let metrics: Vec<_> = snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .map(|(key, _, _, value)| {
+            let (_, key) = key.into_parts();
+            let label = key.labels().find(|it| it.key() == "label").unwrap();
+            let value = match value {
+                DebugValue::Counter(it) => it as f64,
+                DebugValue::Gauge(it) => it.into_inner(),
+                DebugValue::Histogram(_) => unreachable!(),
+            };
+            (key.name().to_owned(), label.value().to_owned(), value)
+        })
+        .collect();
+    let value = |name: &str, label: &str| {
+        metrics
+            .iter()
+            .find(|it| it.0 == name && it.1 == label)
+            .map(|it| it.2)
+    };
+    assert_eq!(
+        value("purgeable_locked_bytes", "metrics_exported"),
+        Some(100.0)
+    );
+    assert_eq!(
+        value("purgeable_unlocked_bytes", "metrics_exported"),
+        Some(0.0)
+    );
+    assert_eq!(
+        value("purgeable_lock_successes_total", "metrics_exported"),
+        Some(1.0)
+    );
+    // Labels that are not exported don't create time series
+    assert_eq!(value("purgeable_locked_bytes", "other"), Some(10.0));
+    assert!(metrics.iter().all(|it| it.1 != "metrics_file_1234"));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing() {
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Collects the span names and the event messages
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<String>>>);
+
+    struct Message(String);
+
+    impl Visit for Message {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{value:?}");
+            }
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == "purgeable"
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            self.0
+                .lock()
+                .unwrap()
+                .push(span.metadata().name().to_owned());
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            self.0.lock().unwrap().push(message.0);
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    let collector = Collector::default();
+    tracing::subscriber::with_default(collector.clone(), || {
+        let l = NonPurgeableBox::new_filled_slice_with_label(0u8, 100, Some("traced"));
+        drop(NonPurgeableBox::unlock(l).lock());
+    });
+    assert_eq!(
+        *collector.0.lock().unwrap(),
+        [
+            "purgeable memory allocated",
+            "purgeable_unlock",
+            "purgeable_lock",
+            "purgeable memory released"
+        ]
+    );
+}
+
+#[cfg(feature = "debug-registry")]
+#[test]
+fn test_debug_registry() {
+    use crate::debug::{live_allocations, BoxState};
+
+    let l = NonPurgeableBox::new_filled_slice_with_label(0u8, 100, Some("test_debug_registry"));
+    let find = || {
+        live_allocations()
+            .into_iter()
+            .find(|it| it.label.as_deref() == Some("test_debug_registry"))
+    };
+    assert_eq!(find().unwrap().state, BoxState::Locked);
+    assert_eq!(find().unwrap().size, 100);
+
+    let u = NonPurgeableBox::unlock(l);
+    assert_eq!(find().unwrap().state, BoxState::Unlocked);
+    drop(u);
+    assert!(find().is_none());
+}
+
+#[cfg(all(purgeable_sim, not(loom)))]
+#[test]
+fn test_sim_purge_order() {
+    const TAG: u64 = 0x5101;
+    let page = page_size::get();
+    let unlock = |priority: PurgePriority| {
+        let mut l = NonPurgeableBox::new_filled_slice(0u8, page);
+        NonPurgeableBox::set_tag(&mut l, TAG);
+        NonPurgeableBox::unlock_with_priority(l, priority)
+    };
+    let high = unlock(PurgePriority::new(3));
+    let fifo_1 = unlock(PurgePriority::new(1));
+    let fifo_2 = unlock(PurgePriority::new(1));
+    let lifo_1 = unlock(PurgePriority::new(1).lifo());
+    let lifo_2 = unlock(PurgePriority::new(1).lifo());
+
+    assert_eq!(crate::sim::purge_tagged(TAG, page), page);
+    assert!(fifo_1.is_purged());
+    assert!(!fifo_2.is_purged());
+    assert_eq!(crate::sim::purge_tagged(TAG, 2 * page), 2 * page);
+    assert!(fifo_2.is_purged() && lifo_2.is_purged());
+    assert!(!lifo_1.is_purged() && !high.is_purged());
+
+    assert!(fifo_1.lock().is_err());
+    assert_eq!(*lifo_1.lock().unwrap(), *vec![0u8; page]);
+    assert!(high.lock().is_ok());
+}
+
+#[cfg(all(purgeable_sim, not(loom)))]
+#[test]
+fn test_sim_reclaim_events() {
+    const TAG: u64 = 0x5102;
+    let events = crate::purge_events();
+    let mut l = NonPurgeableBox::new(&1u64);
+    NonPurgeableBox::set_tag(&mut l, TAG);
+    let u = NonPurgeableBox::unlock(l);
+    assert!(crate::sim::purge_tagged(TAG, usize::MAX) > 0);
+
+    let event = events.try_iter().find(|it| it.tag == TAG).unwrap();
+    assert_eq!(event.detection, crate::PurgeDetection::Reclaim);
+    // The purge is reported once
+    assert!(u.is_purged());
+    assert!(u.lock().is_err());
+    assert!(events.try_iter().all(|it| it.tag != TAG));
+}
+
+#[cfg(all(purgeable_sim, loom))]
+#[test]
+fn loom_lock_races_with_purge() {
+    loom::model(|| {
+        let u = NonPurgeableBox::unlock(NonPurgeableBox::new(&42u64));
+        let purger = loom::thread::spawn(|| {
+            crate::sim::purge_all();
+        });
+        if let Ok(l) = u.lock() {
+            assert_eq!(*l, 42);
+            let u = NonPurgeableBox::unlock(l);
+            drop(u);
+        }
+        purger.join().unwrap();
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_conformance() {
+    use crate::testing::{available_backends, conformance};
+
+    let backends = available_backends();
+    assert!(!backends.is_empty());
+    for backend in backends {
+        for seed in 0..8 {
+            conformance::check(backend, &conformance::Config::with_seed(seed));
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_force_purge() {
+    for backend in crate::testing::available_backends() {
+        crate::testing::with_backend(backend, || {
+            let mut u = NonPurgeableBox::unlock(NonPurgeableBox::new_filled_slice(1u8, 10000));
+            assert_eq!(u.backend(), backend);
+            let empty = NonPurgeableBox::new_filled_slice(1u8, 0);
+            assert_eq!(NonPurgeableBox::backend(&empty), backend);
+            if crate::testing::force_purge(&mut u) {
+                assert!(u.lock().is_err());
+            }
+        });
+    }
+}
+
+#[test]
+#[cfg_attr(miri, ignore = "Miri isolation forbids files")]
+fn test_recorder() {
+    use crate::recorder::{self, TraceEventKind, TraceReader};
+    use std::fs::File;
+    use std::io::{Read, Write};
+
+    /// Copies every write into a purgeable box, whose events are recorded too
+    struct Boxing(File);
+
+    impl Write for Boxing {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(&NonPurgeableBox::new_slice(buf))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    /// Interrupts every other read
+    struct Interrupting<'a>(&'a [u8], bool);
+
+    impl Read for Interrupting<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.1 = !self.1;
+            if self.1 {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            self.0.read(buf)
+        }
+    }
+
+    let path = std::env::temp_dir().join(format!("purgeable-{}.trace", std::process::id()));
+    let recording = recorder::record(Boxing(File::create(&path).unwrap())).unwrap();
+    assert!(recorder::record(std::io::sink()).is_err());
+
+    const SIZE: usize = 12345;
+    let u = NonPurgeableBox::unlock_with_priority(
+        NonPurgeableBox::new_filled_slice(0u8, SIZE),
+        PurgePriority::new(2).lifo(),
+    );
+    let locked = u.lock().is_ok();
+    recording.finish().unwrap();
+
+    let trace = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let events: Vec<_> = TraceReader::new(Interrupting(&trace, false))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    let box_id = events
+        .iter()
+        .find(|it| matches!(it.kind, TraceEventKind::Allocated { size: SIZE, .. }))
+        .unwrap()
+        .box_id;
+    let kinds: Vec<_> = events
+        .iter()
+        .filter(|it| it.box_id == box_id)
+        .map(|it| it.kind)
+        .collect();
+    assert!(matches!(
+        kinds.as_slice(),
+        [
+            TraceEventKind::Allocated { .. },
+            TraceEventKind::Unlocked { priority },
+            TraceEventKind::Locked { success },
+            TraceEventKind::Dropped,
+        ] if *priority == PurgePriority::new(2).lifo() && *success == locked
+    ));
+    assert!(events.windows(2).all(|it| it[0].time <= it[1].time));
+
+    // The iteration ends after the first error
+    for corrupted in [
+        &trace[..trace.len() - 1],
+        &[&trace[..], &[0xff, 0, 0, 0xff, 0, 0]].concat(),
+    ] {
+        let results: Vec<_> = TraceReader::new(corrupted).unwrap().collect();
+        assert!(results.last().unwrap().is_err());
+        assert_eq!(results.iter().filter(|it| it.is_err()).count(), 1);
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    struct Cache {
+        name: NonPurgeableBox<str>,
+        value: PurgeableBox<u32>,
+        text: PurgeableBox<str>,
+        #[serde(with = "crate::serde_bytes")]
+        bytes: PurgeableBox<[u8]>,
+    }
+
+    let cache = Cache {
+        name: NonPurgeableBox::new_str("cache"),
+        value: NonPurgeableBox::unlock(NonPurgeableBox::new(&7)),
+        text: NonPurgeableBox::unlock(NonPurgeableBox::new_str("text")),
+        bytes: NonPurgeableBox::unlock_with_priority(
+            NonPurgeableBox::new_slice(&[1, 2]),
+            PurgePriority::new(1),
+        ),
+    };
+    let json = serde_json::to_string(&cache).unwrap();
+    assert_eq!(
+        json,
+        r#"{"name":"cache","value":7,"text":"text","bytes":[1,2]}"#
+    );
+    let cache: Cache = serde_json::from_str(&json).unwrap();
+    assert_eq!(&*cache.name, "cache");
+    assert_eq!(*cache.value.lock().unwrap(), 7);
+    assert_eq!(&*cache.text.lock().unwrap(), "text");
+    assert_eq!(*cache.bytes.lock().unwrap(), [1, 2]);
+
+    // Purged boxes round-trip as `None`
+    #[cfg(feature = "testing")]
+    if let Some(backend) = force_purgeable_backend() {
+        fn purged<T: ?Sized>(npb: NonPurgeableBox<T>) -> PurgeableBox<T> {
+            let mut u = NonPurgeableBox::unlock(npb);
+            assert!(crate::testing::force_purge(&mut u));
+            u
+        }
+        let cache = crate::testing::with_backend(backend, || Cache {
+            name: NonPurgeableBox::new_str("cache"),
+            value: purged(NonPurgeableBox::new(&7)),
+            text: purged(NonPurgeableBox::new_str("text")),
+            bytes: purged(NonPurgeableBox::new_slice(&[1, 2])),
+        });
+        let json = serde_json::to_string(&cache).unwrap();
+        assert_eq!(
+            json,
+            r#"{"name":"cache","value":null,"text":null,"bytes":null}"#
+        );
+        let cache: Cache = serde_json::from_str(&json).unwrap();
+        assert!(cache.value.lock().is_err());
+        assert!(cache.text.lock().is_err());
+        assert!(cache.bytes.lock().is_err());
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_none() {
+    use std::num::NonZeroU64;
+
+    fn purged<T: serde::de::DeserializeOwned + serde::Serialize + Copy>() {
+        let u: PurgeableBox<T> = serde_json::from_str("null").unwrap();
+        // Purged boxes own no memory, whatever `T` is
+        assert_eq!(u.size(), 0);
+        assert_eq!(serde_json::to_string(&u).unwrap(), "null");
+        assert!(u.lock().is_err());
+    }
+
+    // Types with invalid bit patterns must never be materialized
+    purged::<bool>();
+    purged::<char>();
+    purged::<NonZeroU64>();
+    purged::<[u64; 32]>();
+
+    let u: PurgeableBox<[u16]> = serde_json::from_str("null").unwrap();
+    assert_eq!(u.size(), 0);
+    assert!(u.lock().is_err());
+    let u: PurgeableBox<str> = serde_json::from_str("null").unwrap();
+    assert_eq!(u.size(), 0);
+    assert!(u.lock().is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_reentrant() {
+    use serde::{Serialize, Serializer};
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    /// Reaches the box it is stored in while it is serialized
+    #[derive(Clone, Copy)]
+    struct Reentrant(u8);
+
+    thread_local! {
+        static BOX: RefCell<Option<Rc<PurgeableBox<[Reentrant]>>>> = const { RefCell::new(None) };
+        static SIZE: Cell<usize> = const { Cell::new(0) };
+    }
+
+    impl Serialize for Reentrant {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let u = BOX.with(|it| it.borrow().clone()).unwrap();
+            SIZE.set(u.size());
+            u.serialize(serializer)
+        }
+    }
+
+    let u = Rc::new(NonPurgeableBox::unlock(NonPurgeableBox::new_slice(&[
+        Reentrant(1),
+        Reentrant(2),
+    ])));
+    BOX.with(|it| *it.borrow_mut() = Some(u.clone()));
+    let e = serde_json::to_string(&*u).unwrap_err();
+    assert!(e.to_string().contains("within its own serialization"));
+    assert_eq!(SIZE.get(), 2);
+
+    // The box is unlocked again after the failure
+    BOX.with(|it| it.borrow_mut().take());
+    let u = Rc::into_inner(u).unwrap();
+    assert_eq!(u.lock().unwrap()[1].0, 2);
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn test_bytes() {
+    use crate::PurgeableBytesMut;
+    use bytes::{BufMut, Bytes};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let bytes = Bytes::from(NonPurgeableBox::new_slice(&[1u8, 2, 3]));
+    assert_eq!(&bytes.slice(1..)[..], [2, 3]);
+
+    let released = Arc::new(AtomicBool::new(false));
+    let flag = released.clone();
+    let bytes =
+        NonPurgeableBox::into_bytes_with(NonPurgeableBox::new_slice(&[4u8; 8]), move |npb| {
+            assert_eq!(*npb, [4; 8]);
+            flag.store(true, Ordering::Relaxed);
+        });
+    let clone = bytes.clone();
+    drop(bytes);
+    assert!(!released.load(Ordering::Relaxed));
+    drop(clone);
+    assert!(released.load(Ordering::Relaxed));
+
+    let page_size = page_size::get();
+    let mut buf = PurgeableBytesMut::with_capacity_and_label(1, Some("bytes"));
+    assert_eq!(buf.capacity(), page_size);
+    for i in 0..page_size + 1 {
+        buf.put_u8(i as u8);
+    }
+    assert_eq!(buf.capacity(), 2 * page_size);
+    assert_eq!(buf.len(), page_size + 1);
+    let npb = buf.into_box();
+    assert_eq!(NonPurgeableBox::label(&npb), Some("bytes"));
+    assert_eq!(npb[page_size], page_size as u8);
+
+    let mut buf = PurgeableBytesMut::new();
+    buf.extend_from_slice(b"abc");
+    buf[0] = b'x';
+    assert_eq!(&buf.freeze()[..], b"xbc");
+}
+
+#[test]
+fn test_io() {
+    use crate::io::{read_exact_to_box, read_to_box, Cursor};
+    use std::io::{BufRead, ErrorKind, Read, Seek, SeekFrom, Write};
+
+    let len = 3 * page_size::get() + 5;
+    let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+    // `chain` makes the reader return short reads
+    let npb = read_to_box((&data[..10]).chain(&data[10..])).unwrap();
+    assert_eq!(&*npb, &data[..]);
+    assert_eq!(&*read_to_box(std::io::empty()).unwrap(), b"");
+    assert_eq!(&*read_exact_to_box(&data[..], 100).unwrap(), &data[..100]);
+    let e = read_exact_to_box(&data[..], len + 1).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+    let mut cursor = Cursor::new(NonPurgeableBox::new_filled_slice(0u8, 4));
+    assert_eq!(cursor.write(b"abcdef").unwrap(), 4);
+    assert_eq!(cursor.write(b"x").unwrap(), 0);
+    assert_eq!(cursor.seek(SeekFrom::End(-3)).unwrap(), 1);
+    assert_eq!(cursor.fill_buf().unwrap(), b"bcd");
+    let mut rest = Vec::new();
+    cursor.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"bcd");
+    assert!(cursor.seek(SeekFrom::Current(-5)).is_err());
+    assert_eq!(cursor.position(), 4);
+}
+
+#[cfg(any(feature = "bytemuck", feature = "zerocopy"))]
+#[test]
+fn test_cast_slice_box() {
+    #[cfg(feature = "bytemuck")]
+    {
+        use crate::bytemuck::try_cast_slice_box;
+
+        let (_, npb) =
+            try_cast_slice_box::<u8, u32>(NonPurgeableBox::new_slice(&[1; 5])).unwrap_err();
+        assert_eq!(npb.len(), 5);
+        let empty = try_cast_slice_box::<u8, u64>(NonPurgeableBox::new_slice(&[])).unwrap();
+        assert!(empty.is_empty());
+        let mut words = try_cast_slice_box::<u8, u16>(NonPurgeableBox::new_slice(&[0; 6])).unwrap();
+        words[2] = u16::MAX;
+        let bytes = try_cast_slice_box::<u16, u8>(words).unwrap();
+        assert_eq!(*bytes, [0, 0, 0, 0, 0xff, 0xff]);
+
+        // The verification is kept and covers every byte of the cast box
+        let mut bytes = NonPurgeableBox::new_slice(&[0u8; 16]);
+        NonPurgeableBox::set_integrity_verified(&mut bytes, true);
+        let mut words = try_cast_slice_box::<u8, u64>(bytes).unwrap();
+        assert!(NonPurgeableBox::is_integrity_verified(&words));
+        words[1] = u64::MAX;
+        let words = NonPurgeableBox::unlock(words).lock().unwrap();
+        assert_eq!(*words, [0, u64::MAX]);
+    }
+    #[cfg(feature = "zerocopy")]
+    {
+        use crate::zerocopy::{try_cast_slice_box, CastError};
+
+        let (e, _) =
+            try_cast_slice_box::<u8, u32>(NonPurgeableBox::new_slice(&[1; 5])).unwrap_err();
+        assert_eq!(e, CastError::Size);
+        let words = try_cast_slice_box::<u8, u32>(NonPurgeableBox::new_slice(&[0xff; 8])).unwrap();
+        assert_eq!(*words, [u32::MAX; 2]);
+
+        let mut bytes = NonPurgeableBox::new_slice(&[0u8; 16]);
+        NonPurgeableBox::set_integrity_verified(&mut bytes, true);
+        let mut words = try_cast_slice_box::<u8, u32>(bytes).unwrap();
+        words[3] = u32::MAX;
+        let words = NonPurgeableBox::unlock(words).lock().unwrap();
+        assert_eq!(*words, [0, 0, 0, u32::MAX]);
+    }
+}
+
+#[cfg(all(feature = "async", not(loom)))]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_async_cache() {
+    use crate::AsyncCache;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let cache = Arc::new(AsyncCache::<u32, u64>::new());
+    let regenerations = Arc::new(AtomicUsize::new(0));
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            let cache = cache.clone();
+            let regenerations = regenerations.clone();
+            tokio::spawn(async move {
+                let value = cache
+                    .get_or_regenerate(1, async {
+                        regenerations.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        NonPurgeableBox::new(&42)
+                    })
+                    .await;
+                *value
+            })
+        })
+        .collect();
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), 42);
+    }
+    assert_eq!(regenerations.load(Ordering::Relaxed), 1);
+
+    // Readers of a resident value don't wait for each other
+    let first = cache.get_or_regenerate(1, async { unreachable!() }).await;
+    let second = tokio::time::timeout(
+        Duration::from_secs(10),
+        cache.get_or_regenerate(1, async { unreachable!() }),
+    )
+    .await
+    .expect("the value is shared");
+    assert_eq!((*first, *second), (42, 42));
+    drop((first, second));
+
+    let failed = cache
+        .get_or_try_regenerate(2, async { Err::<NonPurgeableBox<u64>, _>("unavailable") })
+        .await;
+    assert_eq!(failed.err(), Some("unavailable"));
+
+    // `regen` is not polled for a resident box
+    let pb = NonPurgeableBox::unlock(NonPurgeableBox::new(&1u8));
+    let mut polled = false;
+    let npb = pb
+        .lock_or_else_async(async {
+            polled = true;
+            NonPurgeableBox::new(&2)
+        })
+        .await;
+    assert!(!polled);
+    assert_eq!(*npb, 1);
+}
+
+/// A backend that [crate::testing::force_purge] always purges, if one is available
+#[cfg(feature = "testing")]
+fn force_purgeable_backend() -> Option<crate::Backend> {
+    use crate::Backend;
+
+    crate::testing::available_backends()
+        .into_iter()
+        .find(|it| matches!(it, Backend::Mach | Backend::MadvFree | Backend::Sim))
+}
+
+#[cfg(all(feature = "async", feature = "testing", not(loom)))]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_async_cache_regenerates_purged() {
+    use crate::AsyncCache;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let Some(backend) = force_purgeable_backend() else {
+        return;
+    };
+    let new_value =
+        move |value: u64| crate::testing::with_backend(backend, || NonPurgeableBox::new(&value));
+
+    let cache = Arc::new(AsyncCache::<u32, u64>::new());
+    drop(cache.get_or_regenerate(1, async { new_value(1) }).await);
+    assert!(cache.force_purge(&1));
+    let regenerations = Arc::new(AtomicUsize::new(0));
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            let cache = cache.clone();
+            let regenerations = regenerations.clone();
+            tokio::spawn(async move {
+                let value = cache
+                    .get_or_regenerate(1, async {
+                        regenerations.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        new_value(2)
+                    })
+                    .await;
+                *value
+            })
+        })
+        .collect();
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), 2);
+    }
+    assert_eq!(regenerations.load(Ordering::Relaxed), 1);
+
+    // `regen` is polled for a purged box
+    let mut pb = NonPurgeableBox::unlock(new_value(1));
+    assert!(crate::testing::force_purge(&mut pb));
+    let mut polled = false;
+    let npb = pb
+        .lock_or_else_async(async {
+            polled = true;
+            new_value(2)
+        })
+        .await;
+    assert!(polled);
+    assert_eq!(*npb, 2);
+}
+
+#[cfg(all(feature = "async", not(loom)))]
+#[tokio::test]
+async fn test_async_cache_removes_empty_slots() {
+    use crate::AsyncCache;
+    use std::time::Duration;
+
+    let cache = AsyncCache::<u32, u64>::new();
+    let failed = cache
+        .get_or_try_regenerate(1, async { Err::<NonPurgeableBox<u64>, _>(()) })
+        .await;
+    assert!(failed.is_err());
+    assert!(cache.is_empty());
+
+    let cancelled = tokio::time::timeout(
+        Duration::from_millis(10),
+        cache.get_or_regenerate(1, std::future::pending()),
+    )
+    .await;
+    assert!(cancelled.is_err());
+    assert!(cache.is_empty());
+
+    // A waiting task keeps the slot and regenerates the value
+    let (started, regenerating) = tokio::sync::oneshot::channel();
+    let failing = cache.get_or_try_regenerate(1, async {
+        started.send(()).unwrap();
+        tokio::task::yield_now().await;
+        Err::<NonPurgeableBox<u64>, _>(())
+    });
+    let waiting = async {
+        regenerating.await.unwrap();
+        cache
+            .get_or_regenerate(1, async { NonPurgeableBox::new(&7) })
+            .await
+    };
+    let (failed, value) = tokio::join!(failing, waiting);
+    assert!(failed.is_err());
+    assert_eq!(*value, 7);
+    drop(value);
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn test_shared_purgeable_box() {
+    use crate::SharedPurgeableBox;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    let shared = SharedPurgeableBox::<[u32]>::new();
+    let regenerations = AtomicUsize::new(0);
+    let barrier = Barrier::new(4);
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                barrier.wait();
+                let value = shared.get_or_regenerate(|| {
+                    regenerations.fetch_add(1, Ordering::Relaxed);
+                    std::thread::sleep(Duration::from_millis(20));
+                    NonPurgeableBox::new_filled_slice(7, 100)
+                });
+                assert_eq!(value[99], 7);
+            });
+        }
+    });
+    assert_eq!(regenerations.load(Ordering::Relaxed), 1);
+
+    // The purged value is regenerated once too
+    #[cfg(feature = "testing")]
+    if let Some(backend) = force_purgeable_backend() {
+        let shared = SharedPurgeableBox::<u32>::new();
+        let new_value =
+            |value: u32| crate::testing::with_backend(backend, || NonPurgeableBox::new(&value));
+        drop(shared.get_or_regenerate(|| new_value(1)));
+        assert!(shared.force_purge());
+        regenerations.store(0, Ordering::Relaxed);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    barrier.wait();
+                    let value = shared.get_or_regenerate(|| {
+                        regenerations.fetch_add(1, Ordering::Relaxed);
+                        std::thread::sleep(Duration::from_millis(20));
+                        new_value(2)
+                    });
+                    assert_eq!(*value, 2);
+                });
+            }
+        });
+        assert_eq!(regenerations.load(Ordering::Relaxed), 1);
+    }
+
+    let shared = SharedPurgeableBox::<u32>::new();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        shared.get_or_regenerate(|| panic!("regeneration failed"));
+    }));
+    assert!(result.is_err());
+    // A reentrant read panics instead of waiting for itself
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        shared.get_or_regenerate(|| {
+            NonPurgeableBox::new(&*shared.get_or_regenerate(|| unreachable!()))
+        });
+    }));
+    let message = result.unwrap_err().downcast::<&str>().unwrap();
+    assert_eq!(
+        *message,
+        "the SharedPurgeableBox is read from its own regeneration"
+    );
+    assert!(shared.get_or_try_regenerate(|| Err("unavailable")).is_err());
+    let first = shared.get_or_regenerate(|| NonPurgeableBox::new(&1));
+    let second = shared.get_or_regenerate(|| NonPurgeableBox::new(&2));
+    assert_eq!((*first, *second), (1, 1));
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_rayon() {
+    let len = 100 * page_size::get() + 3;
+    let mut npb = NonPurgeableBox::par_new_from_fn(len, |i| i as u32);
+    assert!(npb.iter().enumerate().all(|(i, &x)| x == i as u32));
+    NonPurgeableBox::par_fill(&mut npb, 5);
+    assert!(npb.iter().all(|&x| x == 5));
+    let src: Vec<u32> = (0..len as u32).rev().collect();
+    NonPurgeableBox::par_copy_from_slice(&mut npb, &src);
+    assert_eq!(*npb, *src);
+    assert!(NonPurgeableBox::<[u8]>::par_new_from_fn(0, |_| 1).is_empty());
+}
+
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+#[test]
+fn test_shared_handle() {
+    use crate::SharedHandle;
+    use std::io::ErrorKind;
+    use std::os::unix::net::UnixStream;
+
+    let (producer, consumer) = UnixStream::pair().unwrap();
+
+    // Any fd can be passed, but only `ashmem` and sealed `memfd` regions can be mapped
+    let file = std::fs::File::open("/dev/null").unwrap();
+    // SAFETY: the file is not a region, so it is never mapped
+    unsafe { SharedHandle::new(file.into(), 3) }
+        .send(&producer)
+        .unwrap();
+    let handle = SharedHandle::recv(&consumer).unwrap();
+    assert_eq!(handle.len(), 3);
+    // SAFETY: see above
+    let err = unsafe { NonPurgeableBox::from_shared_handle(handle) }.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let unsealed = unsafe { libc::memfd_create(c"unsealed".as_ptr(), libc::MFD_CLOEXEC) };
+    assert!(unsealed >= 0);
+    // SAFETY: `memfd_create` returned a new file descriptor; the region is never mapped
+    let handle = unsafe { SharedHandle::new(std::os::fd::FromRawFd::from_raw_fd(unsealed), 3) };
+    let err = unsafe { NonPurgeableBox::from_shared_handle(handle) }.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    // Private `MADV_FREE` boxes are copied into a shareable region
+    let npb = NonPurgeableBox::new_slice_with_label(b"shared", Some("shared"));
+    let private = NonPurgeableBox::backend(&npb) == crate::Backend::MadvFree;
+    if private {
+        let err = NonPurgeableBox::export_fd(&npb).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+    let handle = NonPurgeableBox::into_shared_handle(npb).unwrap();
+    handle.send(&producer).unwrap();
+    drop(handle);
+    // SAFETY: the producer box has been converted into the handle, so this box is the only
+    //  mapping of the region
+    let npb =
+        unsafe { NonPurgeableBox::from_shared_handle(SharedHandle::recv(&consumer).unwrap()) }
+            .unwrap();
+    assert_eq!(&*npb, b"shared");
+    assert_eq!(NonPurgeableBox::label(&npb), Some("shared"));
+    assert!(matches!(
+        NonPurgeableBox::backend(&npb),
+        crate::Backend::Ashmem | crate::Backend::Memfd
+    ));
+    assert_eq!(NonPurgeableBox::fork_policy(&npb), crate::fork_policy());
+
+    // Shared regions are exported as is and the box stays usable
+    let fd = NonPurgeableBox::export_fd(&npb).unwrap();
+    // SAFETY: the boxes of both mappings are only read, and only the last one is unlocked
+    let other = unsafe { NonPurgeableBox::from_shared_handle(SharedHandle::new(fd, 6)) }.unwrap();
+    assert_eq!(&*other, b"shared");
+    drop(npb);
+    assert!(NonPurgeableBox::unlock(other).lock().is_ok());
+    let empty = NonPurgeableBox::new_slice(b"");
+    let err = NonPurgeableBox::into_shared_handle(empty).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+#[test]
+fn test_fork_policy() {
+    use crate::ForkPolicy;
+
+    /// Forks a child that exits with the first byte of the box it sees
+    fn first_byte_in_child(npb: &NonPurgeableBox<[u8]>) -> i32 {
+        unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                libc::_exit(npb[0] as i32);
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            libc::WEXITSTATUS(status)
+        }
+    }
+
+    let mut npb = NonPurgeableBox::new_filled_slice(7u8, page_size::get());
+    assert_eq!(NonPurgeableBox::fork_policy(&npb), ForkPolicy::Inherit);
+    if NonPurgeableBox::backend(&npb) == crate::Backend::Ashmem {
+        let err = NonPurgeableBox::set_fork_policy(&mut npb, ForkPolicy::WipeOnFork).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    } else {
+        NonPurgeableBox::set_fork_policy(&mut npb, ForkPolicy::WipeOnFork).unwrap();
+        assert_eq!(first_byte_in_child(&npb), 0);
+        NonPurgeableBox::set_fork_policy(&mut npb, ForkPolicy::CopyOnFork).unwrap();
+        assert_eq!(first_byte_in_child(&npb), 7);
+    }
+    NonPurgeableBox::set_fork_policy(&mut npb, ForkPolicy::DontFork).unwrap();
+    NonPurgeableBox::set_fork_policy(&mut npb, ForkPolicy::Inherit).unwrap();
+    assert_eq!(first_byte_in_child(&npb), 7);
+    // The global policy of new boxes is tested in `tests/fork_policy.rs`
+}
+
+#[cfg(feature = "testing")]
+#[test]
+#[cfg_attr(miri, ignore = "Miri isolation forbids files")]
+fn test_spill() {
+    use crate::spill::SpillDir;
+    use std::fs;
+
+    const TAG: u64 = 0x5b11;
+    let Some(backend) = force_purgeable_backend() else {
+        return;
+    };
+    let page = page_size::get();
+    let path = std::env::temp_dir().join(format!("purgeable-spill-{}", std::process::id()));
+    let dir = SpillDir::new(&path, 1 << 20).unwrap();
+    let spill_purged = |content: &[u8]| {
+        let mut npb = crate::testing::with_backend(backend, || {
+            NonPurgeableBox::new_slice_with_label(content, Some("spilled"))
+        });
+        NonPurgeableBox::set_tag(&mut npb, TAG);
+        NonPurgeableBox::set_integrity_verified(&mut npb, true);
+        let spilled = dir.unlock(npb);
+        dir.flush();
+        assert!(spilled.force_purge());
+        spilled
+    };
+
+    let spilled = spill_purged(&vec![3u8; page]);
+    assert!(spilled.is_spilled());
+    assert!(dir.used_bytes() > page as u64);
+    let npb = spilled.lock().unwrap();
+    assert_eq!(*npb, *vec![3u8; page]);
+    assert_eq!(NonPurgeableBox::label(&npb), Some("spilled"));
+    assert_eq!(NonPurgeableBox::tag(&npb), TAG);
+    assert!(NonPurgeableBox::is_integrity_verified(&npb));
+    assert_eq!(dir.used_bytes(), 0);
+
+    // Corrupted files are not reloaded
+    let spilled = spill_purged(&npb);
+    let file = fs::read_dir(&path)
+        .unwrap()
+        .map(|it| it.unwrap().path())
+        .find(|it| it.extension() == Some("spill".as_ref()))
+        .unwrap();
+    let mut content = fs::read(&file).unwrap();
+    *content.last_mut().unwrap() ^= 1;
+    fs::write(&file, content).unwrap();
+    assert!(spilled.lock().is_err());
+
+    // Boxes over the limit are not spilled
+    let small = SpillDir::new(&path, page as u64).unwrap();
+    let spilled = small.unlock(npb);
+    small.flush();
+    assert!(!spilled.is_spilled());
+    assert!(spilled.lock().is_ok());
+
+    // The lock file of the process is removed with the last `SpillDir`
+    drop((dir, small));
+    assert_eq!(fs::read_dir(&path).unwrap().count(), 0);
+    fs::remove_dir(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+#[cfg_attr(miri, ignore = "Miri isolation forbids files")]
+fn test_spill_dir() {
+    use crate::spill::SpillDir;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let page = page_size::get();
+    let path = std::env::temp_dir().join(format!("purgeable-spill-dir-{}", std::process::id()));
+    let names = |dir: &std::path::Path| {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|it| it.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    let mode = |path: &std::path::Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    // Files of owners whose lock file is missing or unlocked are removed
+    let stale = path.join("stale");
+    fs::create_dir_all(&stale).unwrap();
+    for name in [
+        "1234-0.spill",
+        "exited-0.spill",
+        "exited.lock",
+        "alive-0.spill",
+        "alive.lock",
+        "notes.spill",
+    ] {
+        fs::write(stale.join(name), b"").unwrap();
+    }
+    let alive = fs::File::open(stale.join("alive.lock")).unwrap();
+    let fd = std::os::fd::AsRawFd::as_raw_fd(&alive);
+    assert_eq!(unsafe { libc::flock(fd, libc::LOCK_EX) }, 0);
+    let first = SpillDir::new(&stale, 0).unwrap();
+    let mut left = names(&stale);
+    let own_lock = left.remove(
+        left.iter()
+            .position(|it| it.ends_with(".lock") && it != "alive.lock")
+            .unwrap(),
+    );
+    assert_eq!(left, ["alive-0.spill", "alive.lock", "notes.spill"]);
+    // ...but not the files of the process while it uses the directory
+    let own = own_lock.replace(".lock", "-0.spill");
+    fs::write(stale.join(&own), b"").unwrap();
+    let second = SpillDir::new(&stale, 0).unwrap();
+    assert!(stale.join(&own).exists());
+    drop((first, second));
+    assert!(!stale.join(&own_lock).exists());
+    drop(alive);
+    let third = SpillDir::new(&stale, 0).unwrap();
+    assert_eq!(names(&stale).len(), 2);
+    assert!(names(&stale).contains(&"notes.spill".to_owned()));
+    drop(third);
+
+    let nested = path.join("a").join("b");
+    let dir = SpillDir::new(&nested, 2 * page as u64).unwrap();
+    assert_eq!(mode(&path.join("a")), 0o700);
+    assert_eq!(mode(&nested), 0o700);
+    let spilled = dir.unlock(NonPurgeableBox::new_slice(&vec![5u8; page]));
+    dir.flush();
+    assert!(spilled.is_spilled());
+    let files = names(&nested);
+    assert_eq!(files.len(), 2);
+    assert!(files.iter().any(|it| it.ends_with(".lock")));
+    for file in files {
+        assert_eq!(mode(&nested.join(file)), 0o600);
+    }
+
+    // The limit applies to all the `SpillDir`s of the directory
+    let other = SpillDir::new(nested.join("..").join("b"), dir.max_bytes()).unwrap();
+    assert_eq!(other.used_bytes(), dir.used_bytes());
+    let not_spilled = other.unlock(NonPurgeableBox::new_slice(&vec![6u8; page]));
+    other.flush();
+    assert!(!not_spilled.is_spilled());
+
+    assert_eq!(*spilled.lock().unwrap(), *vec![5u8; page]);
+    assert_eq!(dir.used_bytes(), 0);
+    assert_eq!(names(&nested).len(), 1);
+    drop(not_spilled);
+    fs::remove_dir_all(&path).unwrap();
+}
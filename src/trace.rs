@@ -0,0 +1,90 @@
+//! `tracing` instrumentation; every function is a no-op if the `tracing` feature is disabled
+
+use crate::events::PurgeDetection;
+use crate::Backend;
+
+#[cfg(feature = "tracing")]
+pub(crate) type SpanGuard = tracing::span::EnteredSpan;
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct SpanGuard;
+
+#[cfg(feature = "tracing")]
+macro_rules! enter_span {
+    ($name:literal, $size:expr, $backend:expr, $label:expr) => {
+        tracing::debug_span!(
+            target: "purgeable",
+            $name,
+            size = $size,
+            backend = $backend.name(),
+            label = $label
+        )
+        .entered()
+    };
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn lock_span(size: usize, backend: Backend, label: Option<&str>) -> SpanGuard {
+    enter_span!("purgeable_lock", size, backend, label)
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn lock_span(_size: usize, _backend: Backend, _label: Option<&str>) -> SpanGuard {
+    SpanGuard
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn unlock_span(size: usize, backend: Backend, label: Option<&str>) -> SpanGuard {
+    enter_span!("purgeable_unlock", size, backend, label)
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn unlock_span(_size: usize, _backend: Backend, _label: Option<&str>) -> SpanGuard {
+    SpanGuard
+}
+
+#[inline(always)]
+#[allow(unused_variables)]
+pub(crate) fn allocated(size: usize, backend: Backend, label: Option<&str>) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        target: "purgeable",
+        size,
+        backend = backend.name(),
+        label,
+        "purgeable memory allocated"
+    );
+}
+
+#[inline(always)]
+#[allow(unused_variables)]
+pub(crate) fn purge_detected(
+    size: usize,
+    backend: Backend,
+    label: Option<&str>,
+    detection: PurgeDetection,
+) {
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        target: "purgeable",
+        size,
+        backend = backend.name(),
+        label,
+        ?detection,
+        "purgeable memory has been purged"
+    );
+}
+
+#[inline(always)]
+#[allow(unused_variables)]
+pub(crate) fn dropped(size: usize, backend: Backend, label: Option<&str>) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        target: "purgeable",
+        size,
+        backend = backend.name(),
+        label,
+        "purgeable memory released"
+    );
+}
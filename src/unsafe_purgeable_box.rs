@@ -1,23 +1,118 @@
+use crate::checksum::{checksum, ByteContent};
 use crate::error::PurgeableAllocError;
+use crate::events::{self, PurgeDetection};
 use crate::os;
+use crate::stats::BoxStats;
+use crate::{trace, Backend, PurgePriority};
 use std::fmt;
-use std::mem::MaybeUninit;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// States: `LOCKED`, `UNLOCKED`, `PURGED`.
 pub(crate) struct UnsafePurgeableBox<T: ?Sized> {
+    /// `Some` while the box is unlocked and checked by pollers. Declared first, so it is
+    /// dropped before the memory is released
+    watch: Option<events::Watch>,
     inner: os::SystemPurgeableBox<T>,
+    stats: BoxStats,
+    tag: u64,
+    label: Option<Box<str>>,
+    /// `Some` if integrity verification is enabled; holds the checksum recorded on unlock
+    checksum: Option<u64>,
+    /// The priority of the last unlock
+    priority: PurgePriority,
+    /// Set once the purge has been found, e.g. by `with_transient_lock` or `is_purged`, so
+    /// following locks fail without asking the backend and the purge is reported once.
+    /// Atomic because `is_purged` takes `&self`
+    purged: AtomicBool,
 }
 
 impl<T: Copy> UnsafePurgeableBox<T> {
     /// Returns the box in the `LOCKED` state
     pub(crate) fn try_new_locked_uninit(
+        label: Option<&str>,
     ) -> Result<UnsafePurgeableBox<MaybeUninit<T>>, PurgeableAllocError> {
-        let inner = os::SystemPurgeableBox::new_uninit()?;
-        Ok(UnsafePurgeableBox { inner })
+        let inner = os::SystemPurgeableBox::new_uninit(label)?;
+        Ok(UnsafePurgeableBox::from_locked_inner(inner, label))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T> UnsafePurgeableBox<T> {
+    /// Returns a box in the `UNLOCKED` state that has already been purged: it owns no memory,
+    /// and all locks fail
+    pub(crate) fn new_purged() -> UnsafePurgeableBox<T> {
+        let mut upb =
+            UnsafePurgeableBox::from_locked_inner(os::SystemPurgeableBox::new_empty(), None);
+        upb.set_purged();
+        upb
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T> UnsafePurgeableBox<[T]> {
+    /// See [UnsafePurgeableBox::new_purged]; the slice is empty
+    pub(crate) fn new_purged() -> UnsafePurgeableBox<[T]> {
+        UnsafePurgeableBox::<T>::new_purged().map_inner(|inner| {
+            // SAFETY: an empty slice at the dangling pointer is aligned and never dereferenced
+            unsafe { inner.map_ptr(|ptr| ptr::NonNull::slice_from_raw_parts(ptr, 0)) }
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl UnsafePurgeableBox<str> {
+    /// See [UnsafePurgeableBox::new_purged]; the string is empty
+    pub(crate) fn new_purged() -> UnsafePurgeableBox<str> {
+        // SAFETY: an empty slice is valid UTF-8
+        unsafe { UnsafePurgeableBox::<[u8]>::new_purged().assume_utf8() }
+    }
+}
+
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+impl UnsafePurgeableBox<[u8]> {
+    /// Returns the box in the `LOCKED` state, or `Ok(None)` if the region has been purged.
+    /// The label is recovered from the name of the region
+    pub(crate) fn try_new_locked_from_shared_fd(
+        fd: std::os::fd::OwnedFd,
+        size: usize,
+    ) -> std::io::Result<Option<UnsafePurgeableBox<[u8]>>> {
+        let inner = os::SystemPurgeableBox::from_shared_fd(fd, size)?;
+        Ok(inner.map(|inner| {
+            let label = inner.shared_label();
+            UnsafePurgeableBox::from_locked_inner(inner, label.as_deref())
+        }))
+    }
+
+    /// See [os::SystemPurgeableBox::new_shared_fd]
+    pub(crate) fn new_shared_fd(
+        content: &[u8],
+        label: Option<&str>,
+    ) -> std::io::Result<std::os::fd::OwnedFd> {
+        os::SystemPurgeableBox::new_shared_fd(content, label)
     }
 }
 
 impl<T: ?Sized> UnsafePurgeableBox<T> {
+    fn from_locked_inner(
+        inner: os::SystemPurgeableBox<T>,
+        label: Option<&str>,
+    ) -> UnsafePurgeableBox<T> {
+        let stats = BoxStats::new_locked(inner.size(), inner.backend(), label);
+        trace::allocated(inner.size(), inner.backend(), label);
+        UnsafePurgeableBox {
+            watch: None,
+            inner,
+            stats,
+            tag: 0,
+            label: label.map(Box::from),
+            checksum: None,
+            priority: PurgePriority::DEFAULT,
+            purged: AtomicBool::new(false),
+        }
+    }
+
     /// # Safety
     ///
     /// The caller must guarantee that `self` is in the `UNLOCKED` state.
@@ -28,19 +123,186 @@ impl<T: ?Sized> UnsafePurgeableBox<T> {
     /// [UB]: https://doc.rust-lang.org/reference/behavior-considered-undefined.html
     #[must_use]
     pub(crate) unsafe fn lock(&mut self) -> bool {
-        self.inner.lock()
+        let _span = trace::lock_span(self.size(), self.inner.backend(), self.label());
+        // Pollers must not check the box while it is being locked
+        let polled = self.watch.take().is_some_and(events::Watch::unwatch);
+        if *self.purged.get_mut() {
+            // The purge has already been found and reported
+            self.stats.on_lock(self.inner.backend(), false);
+            return false;
+        }
+        let mut success = self.inner.lock();
+        let mut detection = PurgeDetection::Lock;
+        if success
+            && self
+                .checksum
+                .is_some_and(|it| it != self.content_checksum())
+        {
+            success = false;
+            detection = PurgeDetection::Checksum;
+        }
+        self.stats.on_lock(self.inner.backend(), success);
+        *self.purged.get_mut() = !success;
+        if !success && !polled && !self.is_reclaim_reported() {
+            self.emit_purge_event(detection);
+        }
+        success
     }
 
     /// # Safety
     ///
     /// The caller must guarantee that `self` is in the `LOCKED` state.
-    pub(crate) unsafe fn unlock(&mut self) {
-        self.inner.unlock()
+    pub(crate) unsafe fn unlock(&mut self, priority: PurgePriority) {
+        let _span = trace::unlock_span(self.size(), self.inner.backend(), self.label());
+        if self.checksum.is_some() {
+            self.checksum = Some(self.content_checksum());
+        }
+        #[cfg(purgeable_sim)]
+        self.inner.set_tag(self.tag);
+        self.inner.unlock(priority);
+        self.priority = priority;
+        self.stats.on_unlock(priority);
+        self.watch = events::Watch::new(
+            self.inner.purge_probe(),
+            self.tag,
+            self.size(),
+            self.inner.backend(),
+            self.label(),
+        );
     }
 
-    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    /// Locks the box for the duration of `f` and unlocks it with the same priority again,
+    /// even if `f` panics. Returns `None` if the box has been purged; all following locks
+    /// fail then.
+    ///
+    /// Takes a pointer, so `f` can read the box through shared references, e.g. its size:
+    /// no mutable reference to the box is alive while `f` runs.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `this` is valid and in the `UNLOCKED` state, and that
+    /// the box is neither locked, unlocked nor mutated otherwise until the call returns.
+    #[cfg(feature = "serde")]
+    pub(crate) unsafe fn with_transient_lock<R>(
+        this: *mut UnsafePurgeableBox<T>,
+        f: impl FnOnce(&T) -> R,
+    ) -> Option<R> {
+        struct Unlock<T: ?Sized>(*mut UnsafePurgeableBox<T>);
+
+        impl<T: ?Sized> Drop for Unlock<T> {
+            fn drop(&mut self) {
+                // SAFETY: the box has been locked by `with_transient_lock`
+                unsafe {
+                    let upb = &mut *self.0;
+                    upb.unlock(upb.priority)
+                }
+            }
+        }
+
+        if !(*this).lock() {
+            return None;
+        }
+        let _unlock = Unlock(this);
+        Some(f(&*(*this).ptr()))
+    }
+
+    /// Marks the box as purged, so all following locks fail
+    #[cfg(feature = "serde")]
+    pub(crate) fn set_purged(&mut self) {
+        *self.purged.get_mut() = true;
+        self.stats.on_purged();
+    }
+
+    /// # Safety
+    ///
+    /// The caller must guarantee that `self` is in the `LOCKED` state. The content must not
+    /// have uninitialized bytes, which [set_integrity_verified] guarantees
+    ///
+    /// [set_integrity_verified]: UnsafePurgeableBox::set_integrity_verified
+    unsafe fn content_checksum(&self) -> u64 {
+        // SAFETY: the mapping is page-aligned and consists of `size` bytes
+        checksum(self.ptr() as *const u8, self.size())
+    }
+
+    pub(crate) fn is_integrity_verified(&self) -> bool {
+        self.checksum.is_some()
+    }
+
+    pub(crate) fn set_integrity_verified(&mut self, enabled: bool)
+    where
+        T: ByteContent,
+    {
+        // The actual checksum is recorded on unlock
+        self.checksum = if enabled { Some(0) } else { None };
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios", purgeable_sim))]
     pub(crate) fn is_purged(&self) -> bool {
-        self.inner.is_purged()
+        if self.purged.load(Ordering::Relaxed) {
+            return true;
+        }
+        let purged = self.inner.is_purged();
+        if purged && !self.purged.swap(true, Ordering::Relaxed) {
+            self.stats.on_purge_detected();
+            let reported = self.watch.as_ref().is_some_and(events::Watch::is_reported)
+                || self.is_reclaim_reported();
+            if !reported {
+                self.emit_purge_event(PurgeDetection::StatusQuery);
+            }
+        }
+        purged
+    }
+
+    /// Whether the simulator has reported the purge when it reclaimed the box
+    fn is_reclaim_reported(&self) -> bool {
+        #[cfg(purgeable_sim)]
+        return self.inner.is_purge_reported();
+        #[cfg(not(purgeable_sim))]
+        false
+    }
+
+    fn emit_purge_event(&self, detection: PurgeDetection) {
+        trace::purge_detected(self.size(), self.inner.backend(), self.label(), detection);
+        events::emit(self.tag, self.size(), self.inner.backend(), detection);
+    }
+
+    pub(crate) fn tag(&self) -> u64 {
+        self.tag
+    }
+
+    pub(crate) fn set_tag(&mut self, tag: u64) {
+        self.tag = tag;
+    }
+
+    pub(crate) fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub(crate) fn backend(&self) -> Backend {
+        self.inner.backend()
+    }
+
+    /// Returns `true` if the next `lock` is guaranteed to fail.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `self` is in the `UNLOCKED` state.
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn force_purge(&mut self) -> bool {
+        self.inner.force_purge()
+    }
+
+    /// Changes the content without the backend noticing. Returns `false` if it can't.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `self` is in the `UNLOCKED` state.
+    #[cfg(feature = "testing")]
+    pub(crate) unsafe fn corrupt(&mut self) -> bool
+    where
+        T: ByteContent,
+    {
+        self.inner.corrupt()
     }
 
     /// # Safety
@@ -71,14 +333,79 @@ impl<T: ?Sized> UnsafePurgeableBox<T> {
     pub(crate) fn size(&self) -> usize {
         self.inner.size()
     }
+
+    #[inline(always)]
+    fn map_inner<R: ?Sized>(
+        self,
+        f: impl FnOnce(os::SystemPurgeableBox<T>) -> os::SystemPurgeableBox<R>,
+    ) -> UnsafePurgeableBox<R> {
+        let this = ManuallyDrop::new(self);
+        // SAFETY: every field is moved out exactly once and `self` is not dropped
+        unsafe {
+            UnsafePurgeableBox {
+                watch: ptr::read(&this.watch),
+                inner: f(ptr::read(&this.inner)),
+                stats: ptr::read(&this.stats),
+                tag: this.tag,
+                label: ptr::read(&this.label),
+                checksum: this.checksum,
+                priority: this.priority,
+                purged: AtomicBool::new(this.purged.load(Ordering::Relaxed)),
+            }
+        }
+    }
+
+    #[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+    pub(crate) fn shared_fd(&self) -> Option<std::os::fd::BorrowedFd<'_>> {
+        self.inner.shared_fd()
+    }
+
+    #[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+    pub(crate) fn fork_policy(&self) -> crate::ForkPolicy {
+        self.inner.fork_policy()
+    }
+
+    #[cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+    pub(crate) fn set_fork_policy(&mut self, policy: crate::ForkPolicy) -> std::io::Result<()> {
+        self.inner.set_fork_policy(policy)
+    }
+
+    /// Calling it is safe in any state since it doesn't access the content
+    #[cfg(unix)]
+    pub(crate) fn resident_pages(&self) -> std::io::Result<usize> {
+        self.inner.resident_pages()
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn resident_bytes(&self) -> std::io::Result<usize> {
+        self.inner.resident_bytes()
+    }
 }
 
 impl<T: Copy> UnsafePurgeableBox<[T]> {
     pub(crate) fn try_new_locked_uninit_slice(
         len: usize,
+        label: Option<&str>,
     ) -> Result<UnsafePurgeableBox<[MaybeUninit<T>]>, PurgeableAllocError> {
-        let inner = os::SystemPurgeableBox::<[T]>::new_uninit_slice(len)?;
-        Ok(UnsafePurgeableBox { inner })
+        let inner = os::SystemPurgeableBox::<[T]>::new_uninit_slice(len, label)?;
+        Ok(UnsafePurgeableBox::from_locked_inner(inner, label))
+    }
+}
+
+#[cfg(any(feature = "bytemuck", feature = "zerocopy"))]
+impl<A> UnsafePurgeableBox<[A]> {
+    /// See [os::SystemPurgeableBox::cast_slice]
+    #[inline(always)]
+    pub(crate) unsafe fn cast_slice<B>(self) -> UnsafePurgeableBox<[B]> {
+        self.map_inner(|inner| inner.cast_slice())
+    }
+}
+
+impl UnsafePurgeableBox<[u8]> {
+    /// See docs for [std::str::from_utf8_unchecked]
+    #[inline(always)]
+    pub(crate) unsafe fn assume_utf8(self) -> UnsafePurgeableBox<str> {
+        self.map_inner(|inner| inner.assume_utf8())
     }
 }
 
@@ -86,9 +413,7 @@ impl<T: Copy> UnsafePurgeableBox<[MaybeUninit<T>]> {
     /// See docs for [MaybeUninit::assume_init]
     #[inline(always)]
     pub(crate) unsafe fn assume_init(self) -> UnsafePurgeableBox<[T]> {
-        UnsafePurgeableBox {
-            inner: self.inner.assume_init(),
-        }
+        self.map_inner(|inner| inner.assume_init())
     }
 }
 
@@ -96,9 +421,13 @@ impl<T> UnsafePurgeableBox<MaybeUninit<T>> {
     /// See docs for [MaybeUninit::assume_init]
     #[inline(always)]
     pub(crate) unsafe fn assume_init(self) -> UnsafePurgeableBox<T> {
-        UnsafePurgeableBox {
-            inner: self.inner.assume_init(),
-        }
+        self.map_inner(|inner| inner.assume_init())
+    }
+}
+
+impl<T: ?Sized> Drop for UnsafePurgeableBox<T> {
+    fn drop(&mut self) {
+        trace::dropped(self.size(), self.inner.backend(), self.label());
     }
 }
 
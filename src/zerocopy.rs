@@ -0,0 +1,90 @@
+//! Casts between boxes of [zerocopy] types without copying; requires the `zerocopy` feature.
+//!
+//! A locked box derefs to a slice, so it can be viewed as another type in place with
+//! [FromBytes::ref_from_bytes] and [FromBytes::mut_from_bytes], which check the alignment
+//! and the length. Purgeable memory is page-aligned, so only views at offsets can be misaligned.
+//!
+//! # Examples
+//!
+//! ```
+//! use purgeable::NonPurgeableBox;
+//! use zerocopy::FromBytes;
+//!
+//! let bytes = NonPurgeableBox::new_slice(&[1u8, 0, 0, 0, 2, 0, 0, 0]);
+//! let view = <[u32]>::ref_from_bytes(&bytes).unwrap();
+//! assert_eq!(view.len(), 2);
+//!
+//! let words: NonPurgeableBox<[u32]> = purgeable::zerocopy::cast_slice_box(bytes);
+//! assert_eq!(words[1], u32::from_le(2));
+//! let bytes: NonPurgeableBox<[u8]> = purgeable::zerocopy::cast_slice_box(words);
+//! assert_eq!(bytes.len(), 8);
+//! ```
+//!
+//! The target type must be [IntoBytes] too: the box can be unlocked again after a cast, and an
+//! [integrity verified](NonPurgeableBox::set_integrity_verified) box reads all of its bytes
+//! then, so they must not become padding or uninitialized
+//!
+//! ```compile_fail
+//! use purgeable::NonPurgeableBox;
+//! use std::mem::MaybeUninit;
+//!
+//! let bytes = NonPurgeableBox::new_slice(&[0u8; 8]);
+//! let _: NonPurgeableBox<[MaybeUninit<u8>]> = purgeable::zerocopy::cast_slice_box(bytes);
+//! ```
+
+use crate::NonPurgeableBox;
+use ::zerocopy::{FromBytes, IntoBytes};
+use std::{fmt, mem};
+
+/// Why [try_cast_slice_box] has failed
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CastError {
+    /// The box is not aligned for the target type
+    Alignment,
+    /// The size of the box is not a multiple of the size of the target type
+    Size,
+}
+
+impl fmt::Display for CastError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CastError::Alignment => f.write_str("the box is not aligned for the target type"),
+            CastError::Size => {
+                f.write_str("the box size is not a multiple of the target type size")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CastError {}
+
+/// Reinterprets a box of `A` as a box of `B` in place.
+///
+/// Fails if the box is not aligned for `B` or its size in bytes is not a multiple of the size
+/// of `B`; the box is returned back then. Casting to bytes never fails
+#[allow(clippy::result_large_err, clippy::type_complexity)]
+pub fn try_cast_slice_box<A: IntoBytes, B: FromBytes + IntoBytes>(
+    npb: NonPurgeableBox<[A]>,
+) -> Result<NonPurgeableBox<[B]>, (CastError, NonPurgeableBox<[A]>)> {
+    let size = mem::size_of_val::<[A]>(&npb);
+    if size != 0 && !(npb.as_ptr() as usize).is_multiple_of(mem::align_of::<B>()) {
+        return Err((CastError::Alignment, npb));
+    }
+    if !size.is_multiple_of(mem::size_of::<B>()) {
+        return Err((CastError::Size, npb));
+    }
+    // SAFETY: the alignment and the size have been checked; `A` and `B` have no
+    //  uninitialized bytes and any bytes are a valid `B`
+    Ok(unsafe { npb.cast_slice() })
+}
+
+/// Like [try_cast_slice_box], but panics if the cast fails
+pub fn cast_slice_box<A: IntoBytes, B: FromBytes + IntoBytes>(
+    npb: NonPurgeableBox<[A]>,
+) -> NonPurgeableBox<[B]> {
+    match try_cast_slice_box(npb) {
+        Ok(npb) => npb,
+        Err((e, _)) => panic!("cast_slice_box failed: {}", e),
+    }
+}
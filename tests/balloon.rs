@@ -0,0 +1,56 @@
+//! The balloon reclaims the memory of every box in the process, so it's tested in its own
+//! test binary instead of `src/tests.rs`, where it would purge the boxes of concurrent tests.
+//!
+//! `test_balloon_reclaim` fills the memory of the machine up to the safety limit, so it may
+//! swap or wake the OOM killer depending on the host and cgroup limits. It is ignored by
+//! default; run it with `cargo test --features testing --test balloon -- --ignored`
+
+#![cfg(all(feature = "testing", target_os = "linux", not(purgeable_sim)))]
+
+use purgeable::testing::{self, Balloon, BalloonLimits, StopReason};
+use purgeable::{Backend, NonPurgeableBox};
+
+#[test]
+fn test_balloon() {
+    let mut limits = BalloonLimits::default();
+    limits.chunk_size = 1 << 20;
+    let balloon = Balloon::inflate(8 << 20, &limits);
+    assert!(balloon.size() <= 8 << 20);
+    if balloon.stop_reason() == StopReason::Target {
+        assert_eq!(balloon.size(), 8 << 20);
+    }
+}
+
+#[test]
+#[ignore = "fills the memory of the machine, run explicitly with --ignored"]
+fn test_balloon_reclaim() {
+    // Only `MADV_FREE` regions are reclaimed like page cache; `ashmem` needs the shrinker
+    if !testing::available_backends().contains(&Backend::MadvFree) {
+        return;
+    }
+    // The kernel frees the lazily freed pages when free memory runs low, so there must be
+    // enough of them to still be there when it does
+    let boxes: Vec<_> = testing::with_backend(Backend::MadvFree, || {
+        (0..16)
+            .map(|_| NonPurgeableBox::unlock(NonPurgeableBox::new_filled_slice(1u8, 16 << 20)))
+            .collect()
+    });
+
+    // PSI stalls and cgroup events may be reported before anything is reclaimed, so only the
+    // safety limit stops the balloon
+    let mut limits = BalloonLimits::default();
+    limits.max_stall = None;
+    limits.stop_on_cgroup_events = false;
+    let balloon = Balloon::inflate(usize::MAX, &limits);
+    let purged = boxes
+        .into_iter()
+        .map(|u| u.lock())
+        .filter(Result::is_err)
+        .count();
+    assert!(
+        purged > 0,
+        "nothing has been reclaimed after inflating {} MiB: {:?}",
+        balloon.size() >> 20,
+        balloon.stop_reason()
+    );
+}
@@ -0,0 +1,70 @@
+//! [purgeable::set_fork_policy] changes the policy of every box allocated afterwards, so it's
+//! tested in its own test binary instead of `src/tests.rs`, where it would change the boxes
+//! of concurrent tests
+
+#![cfg(all(any(target_os = "linux", target_os = "android"), not(purgeable_sim)))]
+
+use purgeable::{Backend, ForkPolicy, NonPurgeableBox};
+
+#[test]
+fn test_set_fork_policy() {
+    assert_eq!(purgeable::fork_policy(), ForkPolicy::Inherit);
+    let inherited = NonPurgeableBox::new_filled_slice(7u8, page_size::get());
+
+    purgeable::set_fork_policy(ForkPolicy::CopyOnFork);
+    assert_eq!(purgeable::fork_policy(), ForkPolicy::CopyOnFork);
+    let copied = NonPurgeableBox::new_filled_slice(7u8, page_size::get());
+    assert_eq!(
+        NonPurgeableBox::fork_policy(&copied),
+        ForkPolicy::CopyOnFork
+    );
+    // `ashmem` regions are always shared with the child
+    assert_eq!(NonPurgeableBox::backend(&copied), Backend::MadvFree);
+    // Empty boxes report the backend a non-empty box gets
+    let empty = NonPurgeableBox::new_slice(&[0u8; 0]);
+    assert_eq!(NonPurgeableBox::backend(&empty), Backend::MadvFree);
+    assert_eq!(NonPurgeableBox::fork_policy(&empty), ForkPolicy::CopyOnFork);
+    // Existing boxes keep their policy
+    assert_eq!(
+        NonPurgeableBox::fork_policy(&inherited),
+        ForkPolicy::Inherit
+    );
+
+    // A forced `ashmem` region can't be private, and the allocation reports why
+    #[cfg(feature = "testing")]
+    if purgeable::testing::available_backends().contains(&Backend::Ashmem) {
+        use std::error::Error;
+
+        purgeable::set_fork_policy(ForkPolicy::WipeOnFork);
+        let err = purgeable::testing::with_backend(Backend::Ashmem, || {
+            NonPurgeableBox::<[u8]>::try_new_uninit_slice(page_size::get()).unwrap_err()
+        });
+        let cause = err
+            .source()
+            .unwrap()
+            .downcast_ref::<std::io::Error>()
+            .unwrap();
+        assert_eq!(cause.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    // Mapped shared regions get the global policy too, unless it requires a private mapping
+    let share = |content: &[u8]| {
+        let handle = NonPurgeableBox::into_shared_handle(NonPurgeableBox::new_slice(content));
+        // SAFETY: the producer box has been converted into the handle, so this box is the only
+        //  mapping of the region
+        unsafe { NonPurgeableBox::from_shared_handle(handle.unwrap()) }
+    };
+    purgeable::set_fork_policy(ForkPolicy::DontFork);
+    let imported = share(b"shared").unwrap();
+    assert_eq!(
+        NonPurgeableBox::fork_policy(&imported),
+        ForkPolicy::DontFork
+    );
+    purgeable::set_fork_policy(ForkPolicy::WipeOnFork);
+    let err = share(b"shared").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+
+    purgeable::set_fork_policy(ForkPolicy::Inherit);
+    let npb = NonPurgeableBox::new_filled_slice(7u8, page_size::get());
+    assert_eq!(NonPurgeableBox::fork_policy(&npb), ForkPolicy::Inherit);
+}